@@ -0,0 +1,93 @@
+use crate::c63;
+
+/// variance of the 16x16 luma pixels of the macroblock at `x`, `y`
+fn activity(luma: &[u8], w: i32, x: i32, y: i32) -> f64 {
+    let mut sum = 0u32;
+    let mut squares = 0u32;
+    for v in y..y + 16 {
+        for u in x..x + 16 {
+            let p = luma[(v * w + u) as usize] as u32;
+            sum += p;
+            squares += p * p;
+        }
+    }
+    let mean = sum as f64 / 256.0;
+    return squares as f64 / 256.0 - mean * mean;
+}
+
+/// set the delta qp of each 16x16 macroblock of `frame` from the activity of
+/// its luma, `w` by `h` pixels
+///
+/// Errors are easy to see in flat areas, like banding in a sky, and masked in
+/// texture. Macroblocks flatter than the average of the frame are quantized
+/// finer and busier ones coarser, `strength` steps of delta qp per doubling of
+/// the variance. The delta qps average to about 0, so the size of the frame
+/// stays close to what its qp gives.
+pub fn set_delta_qps(frame: &mut c63::Frame, w: i32, h: i32, strength: f64) {
+    let mut log_activity = Vec::with_capacity(frame.delta_qps.len());
+    for y in (0..h).step_by(16) {
+        for x in (0..w).step_by(16) {
+            log_activity.push((activity(&frame.orig.y, w, x, y) + 1.0).log2());
+        }
+    }
+    let mean = log_activity.iter().sum::<f64>() / log_activity.len() as f64;
+
+    let max = c63::MAX_DELTA_QP as f64;
+    for (delta_qp, a) in frame.delta_qps.iter_mut().zip(&log_activity) {
+        *delta_qp = (strength * (mean - a)).round().clamp(-max, max) as i8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_context;
+    use crate::testing::*;
+
+    #[test]
+    fn flat_macroblocks_are_finer() {
+        /* A flat left half next to a textured right half */
+        let (w, h) = (64, 32);
+        let (padw, padh) = c63::compute_padding(w, h);
+        let mut image = c63::YUV::new(
+            (padw[0] * padh[0]) as usize,
+            (padw[1] * padh[1]) as usize,
+            (padw[2] * padh[2]) as usize,
+        );
+        for y in 0..h {
+            for x in 0..w {
+                let texture = if (x / 2 + y / 3) % 2 == 0 { 60 } else { 180 };
+                image.y[(y * w + x) as usize] = if x < w / 2 { 120 } else { texture };
+            }
+        }
+        let mut frame = c63::Frame::new(image, &padw, &padh, w / 8, h / 8, true);
+
+        set_delta_qps(&mut frame, w, h, 1.0);
+        let cols = (w / 16) as usize;
+        for (i, &delta_qp) in frame.delta_qps.iter().enumerate() {
+            if i % cols < cols / 2 {
+                assert!(delta_qp > 0);
+            } else {
+                assert!(delta_qp < 0);
+            }
+        }
+
+        /* Without strength every macroblock has the qp of the frame */
+        set_delta_qps(&mut frame, w, h, 0.0);
+        assert!(frame.delta_qps.iter().all(|&d| d == 0));
+    }
+
+    #[test]
+    fn encoder_sets_delta_qps() {
+        /* The flat half is quantized finer than the textured one */
+        let tools = c63::CodingTools {
+            adaptive_quantization: true,
+            ..c63::CodingTools::new()
+        };
+        let mut ctx = context(SMALL, tools);
+        let image = half_flat_image(&ctx, 0);
+        encode_context::encode_image(&mut ctx, image);
+        let delta_qps = &ctx.current_frame.as_ref().unwrap().delta_qps;
+        assert!(delta_qps.iter().any(|&d| d > 0) && delta_qps.iter().any(|&d| d < 0));
+    }
+}
@@ -0,0 +1,591 @@
+use crate::bitstream::{BitReader, BitWriter, SymbolWriter};
+use crate::entropy::{EntropyDecoder, EntropyEncoder, Flag};
+use crate::rdoq;
+
+use std::io::{self, Read};
+
+/* Binary range coder in the style of LZMA. Probabilities are 11 bit estimates
+of the chance of a 0, adapted after every coded bit. The output bytes go through
+the same 0xFF stuffing as huffman coded data. */
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+const MOVE_BITS: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+/* Number of unary coded magnitude bins before the escape to exp-golomb */
+const MAGNITUDE_BINS: usize = 14;
+
+/* Contexts of the flags, per flag the number of contexts the syntax passes */
+const FLAG_CONTEXTS: usize = 3;
+const FLAGS: usize = 9;
+
+/// number of contexts for motion vector components, 3 classes of neighbouring
+/// motion per axis
+pub const MV_CONTEXTS: usize = 6;
+
+struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn new() -> RangeEncoder {
+        return RangeEncoder {
+            low: 0,
+            range: 0xFFFFFFFF,
+            cache: 0,
+            cache_size: 1,
+            out: Vec::new(),
+        };
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF000000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut temp = self.cache;
+            loop {
+                self.out.push(temp.wrapping_add(carry));
+                temp = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FFFFFF) << 8;
+    }
+
+    fn encode_bit(&mut self, prob: &mut u16, bit: bool) {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        if !bit {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+        } else {
+            self.low += bound as u64;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    /// encode the `n` low bits of `value` with a fixed probability of 1/2
+    fn encode_direct(&mut self, value: u32, n: u8) {
+        for i in (0..n).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 != 0 {
+                self.low += self.range as u64;
+            }
+            while self.range < TOP {
+                self.range <<= 8;
+                self.shift_low();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+    }
+}
+
+struct RangeDecoder {
+    range: u32,
+    code: u32,
+}
+
+impl RangeDecoder {
+    fn new<R: Read>(reader: &mut BitReader<R>) -> Result<RangeDecoder, io::Error> {
+        let mut code: u32 = 0;
+        for _ in 0..5 {
+            code = (code << 8) | reader.get_bits(8)? as u32;
+        }
+        return Ok(RangeDecoder {
+            range: 0xFFFFFFFF,
+            code: code,
+        });
+    }
+
+    fn normalize<R: Read>(&mut self, reader: &mut BitReader<R>) -> Result<(), io::Error> {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | reader.get_bits(8)? as u32;
+        }
+        return Ok(());
+    }
+
+    fn decode_bit<R: Read>(
+        &mut self,
+        reader: &mut BitReader<R>,
+        prob: &mut u16,
+    ) -> Result<bool, io::Error> {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+            false
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+            true
+        };
+        self.normalize(reader)?;
+        return Ok(bit);
+    }
+
+    fn decode_direct<R: Read>(
+        &mut self,
+        reader: &mut BitReader<R>,
+        n: u8,
+    ) -> Result<u32, io::Error> {
+        let mut value: u32 = 0;
+        for _ in 0..n {
+            self.range >>= 1;
+            let bit = self.code >= self.range;
+            if bit {
+                self.code -= self.range;
+            }
+            value = (value << 1) | bit as u32;
+            self.normalize(reader)?;
+        }
+        return Ok(value);
+    }
+}
+
+/// Contexts of a signed value coded as zero flag, sign and unary magnitude
+#[derive(Clone, Copy)]
+struct ValueContexts {
+    zero: u16,
+    sign: u16,
+    magnitude: [u16; MAGNITUDE_BINS],
+}
+
+impl ValueContexts {
+    fn new() -> ValueContexts {
+        return ValueContexts {
+            zero: PROB_INIT,
+            sign: PROB_INIT,
+            magnitude: [PROB_INIT; MAGNITUDE_BINS],
+        };
+    }
+}
+
+/// Contexts of the coefficients of one color component and prediction type
+#[derive(Clone, Copy)]
+struct BlockContexts {
+    dc: ValueContexts,
+    coded: u16,
+    significant: [u16; 64],
+    last: [u16; 64],
+    /* Magnitude bins per frequency band */
+    level: [[u16; MAGNITUDE_BINS]; 4],
+}
+
+impl BlockContexts {
+    fn new() -> BlockContexts {
+        return BlockContexts {
+            dc: ValueContexts::new(),
+            coded: PROB_INIT,
+            significant: [PROB_INIT; 64],
+            last: [PROB_INIT; 64],
+            level: [[PROB_INIT; MAGNITUDE_BINS]; 4],
+        };
+    }
+}
+
+/// All adaptive probabilities of a frame, they start over at every frame
+struct Contexts {
+    flags: [[u16; FLAG_CONTEXTS]; FLAGS],
+    mv: [ValueContexts; MV_CONTEXTS],
+    /* Indexed by huffman table id (luma, chroma) and inter prediction */
+    blocks: [[BlockContexts; 2]; 2],
+}
+
+impl Contexts {
+    fn new() -> Contexts {
+        return Contexts {
+            flags: [[PROB_INIT; FLAG_CONTEXTS]; FLAGS],
+            mv: [ValueContexts::new(); MV_CONTEXTS],
+            blocks: [[BlockContexts::new(); 2]; 2],
+        };
+    }
+
+    fn flag(&mut self, flag: Flag, ctx: usize) -> &mut u16 {
+        let index = match flag {
+            Flag::UseMv => 0,
+            Flag::ReuseMv => 1,
+            Flag::Skip => 2,
+            Flag::Partition => 3,
+            Flag::RefIndex => 4,
+            Flag::Bidirectional => 5,
+            Flag::Backward => 6,
+            Flag::IntraMode => 7,
+            Flag::DeltaQp => 8,
+        };
+        return &mut self.flags[index][ctx.min(FLAG_CONTEXTS - 1)];
+    }
+}
+
+/// frequency band of a zig-zag position, used to share level contexts
+fn band(i: usize) -> usize {
+    return match i {
+        0..=2 => 0,
+        3..=9 => 1,
+        10..=27 => 2,
+        _ => 3,
+    };
+}
+
+fn bit_length(val: u32) -> u8 {
+    return (32 - val.leading_zeros()) as u8;
+}
+
+/// bins of the unary and exp-golomb code of `put_magnitude`
+fn magnitude_bins(val: u32) -> u32 {
+    if (val as usize) < MAGNITUDE_BINS {
+        return val + 1;
+    }
+    let rest = val - MAGNITUDE_BINS as u32 + 1;
+    return MAGNITUDE_BINS as u32 + 2 * bit_length(rest) as u32 - 1;
+}
+
+/// The bins `put_coefficients` codes for the AC coefficients of a block
+///
+/// The levels are chosen before the frame is coded, so the contexts have not
+/// adapted yet. Every bin is counted at the probability of 1/2 they start from,
+/// one bit like the bypass bins.
+pub struct AcBins;
+
+impl rdoq::AcBits for AcBins {
+    fn coefficient(&self, prev: usize, i: usize, level: i16) -> f64 {
+        /* A significance flag per zero, then the significance and last flags
+        of the coefficient, which are implied at the last position */
+        let zeros = (i - prev - 1) as u32;
+        let flags = if i < 63 { 2 } else { 0 };
+        let sign = 1;
+        let magnitude = magnitude_bins(level.unsigned_abs() as u32 - 1);
+        return (zeros + flags + magnitude + sign) as f64;
+    }
+
+    /* The coded flag, the last flag of the last coefficient replaces one
+    already counted */
+    fn end(&self, _last: usize) -> f64 {
+        return 1.0;
+    }
+}
+
+/// Context-adaptive binary arithmetic coding of the frame data
+pub struct ArithEncoder<'a> {
+    writer: &'a mut BitWriter,
+    rc: RangeEncoder,
+    contexts: Contexts,
+}
+
+impl<'a> ArithEncoder<'a> {
+    pub fn new(writer: &'a mut BitWriter) -> ArithEncoder<'a> {
+        return ArithEncoder {
+            writer: writer,
+            rc: RangeEncoder::new(),
+            contexts: Contexts::new(),
+        };
+    }
+
+    /// unary code min(`val`, MAGNITUDE_BINS) followed by exp-golomb bypass
+    /// bits for the rest
+    fn put_magnitude(rc: &mut RangeEncoder, bins: &mut [u16; MAGNITUDE_BINS], val: u32) {
+        let n = val as usize;
+        for bin in bins.iter_mut().take(n) {
+            rc.encode_bit(bin, true);
+        }
+        if n < MAGNITUDE_BINS {
+            rc.encode_bit(&mut bins[n], false);
+            return;
+        }
+
+        /* Exp-golomb, order 0 */
+        let rest = val - MAGNITUDE_BINS as u32 + 1;
+        let len = bit_length(rest);
+        rc.encode_direct(0, len - 1);
+        rc.encode_direct(rest, len);
+    }
+
+    fn put_value(rc: &mut RangeEncoder, contexts: &mut ValueContexts, val: i32) {
+        rc.encode_bit(&mut contexts.zero, val != 0);
+        if val == 0 {
+            return;
+        }
+        rc.encode_bit(&mut contexts.sign, val < 0);
+        Self::put_magnitude(rc, &mut contexts.magnitude, val.unsigned_abs() - 1);
+    }
+}
+
+impl<'a> EntropyEncoder for ArithEncoder<'a> {
+    fn put_flag(&mut self, flag: Flag, ctx: usize, bit: bool) {
+        self.rc.encode_bit(self.contexts.flag(flag, ctx), bit);
+    }
+
+    fn put_mv_component(&mut self, ctx: usize, val: i16) {
+        let contexts = &mut self.contexts.mv[ctx.min(MV_CONTEXTS - 1)];
+        Self::put_value(&mut self.rc, contexts, val as i32);
+    }
+
+    fn put_coefficients(&mut self, cc: usize, inter: bool, block: &[i16], prev_dc: &mut i16) {
+        let rc = &mut self.rc;
+        let contexts = &mut self.contexts.blocks[cc][inter as usize];
+
+        /* DC */
+        let dc = block[0] as i32 - *prev_dc as i32;
+        *prev_dc = block[0];
+        Self::put_value(rc, &mut contexts.dc, dc);
+
+        /* Significance map of the AC coefficients, ended by a last flag */
+        let last = match (1..64).rev().find(|&i| block[i] != 0) {
+            Some(i) => i,
+            None => 0,
+        };
+        rc.encode_bit(&mut contexts.coded, last != 0);
+        if last == 0 {
+            return;
+        }
+
+        for i in 1..63 {
+            let significant = block[i] != 0;
+            rc.encode_bit(&mut contexts.significant[i], significant);
+            if significant {
+                rc.encode_bit(&mut contexts.last[i], i == last);
+                if i == last {
+                    break;
+                }
+            }
+        }
+
+        /* Levels */
+        for i in 1..=last {
+            if block[i] == 0 {
+                continue;
+            }
+            let level = block[i] as i32;
+            Self::put_magnitude(rc, &mut contexts.level[band(i)], level.unsigned_abs() - 1);
+            rc.encode_direct((level < 0) as u32, 1);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.rc.flush();
+        for &byte in self.rc.out.iter() {
+            self.writer.put_bits(byte as u16, 8);
+        }
+        self.rc.out.clear();
+    }
+}
+
+pub struct ArithDecoder<'a, 'b, R: Read> {
+    reader: &'a mut BitReader<'b, R>,
+    rc: RangeDecoder,
+    contexts: Contexts,
+}
+
+impl<'a, 'b, R: Read> ArithDecoder<'a, 'b, R> {
+    pub fn new(reader: &'a mut BitReader<'b, R>) -> Result<ArithDecoder<'a, 'b, R>, io::Error> {
+        let rc = RangeDecoder::new(reader)?;
+        return Ok(ArithDecoder {
+            reader: reader,
+            rc: rc,
+            contexts: Contexts::new(),
+        });
+    }
+
+    fn get_magnitude(
+        rc: &mut RangeDecoder,
+        reader: &mut BitReader<R>,
+        bins: &mut [u16; MAGNITUDE_BINS],
+    ) -> Result<u32, io::Error> {
+        for n in 0..MAGNITUDE_BINS {
+            if !rc.decode_bit(reader, &mut bins[n])? {
+                return Ok(n as u32);
+            }
+        }
+
+        let mut len: u8 = 1;
+        while rc.decode_direct(reader, 1)? == 0 {
+            len += 1;
+            if len > 24 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid exp-golomb code",
+                ));
+            }
+        }
+        let rest = (1 << (len - 1)) | rc.decode_direct(reader, len - 1)?;
+        return Ok(rest + MAGNITUDE_BINS as u32 - 1);
+    }
+
+    fn get_value(
+        rc: &mut RangeDecoder,
+        reader: &mut BitReader<R>,
+        contexts: &mut ValueContexts,
+    ) -> Result<i32, io::Error> {
+        if !rc.decode_bit(reader, &mut contexts.zero)? {
+            return Ok(0);
+        }
+        let negative = rc.decode_bit(reader, &mut contexts.sign)?;
+        let magnitude = Self::get_magnitude(rc, reader, &mut contexts.magnitude)? as i32 + 1;
+        return Ok(if negative { -magnitude } else { magnitude });
+    }
+}
+
+impl<'a, 'b, R: Read> EntropyDecoder for ArithDecoder<'a, 'b, R> {
+    fn get_flag(&mut self, flag: Flag, ctx: usize) -> Result<bool, io::Error> {
+        return self
+            .rc
+            .decode_bit(self.reader, self.contexts.flag(flag, ctx));
+    }
+
+    fn get_mv_component(&mut self, ctx: usize) -> Result<i16, io::Error> {
+        let contexts = &mut self.contexts.mv[ctx.min(MV_CONTEXTS - 1)];
+        return Ok(Self::get_value(&mut self.rc, self.reader, contexts)? as i16);
+    }
+
+    fn get_coefficients(
+        &mut self,
+        cc: usize,
+        inter: bool,
+        block: &mut [i16],
+        prev_dc: &mut i16,
+    ) -> Result<(), io::Error> {
+        let rc = &mut self.rc;
+        let reader = &mut *self.reader;
+        let contexts = &mut self.contexts.blocks[cc][inter as usize];
+
+        block[0..64].fill(0);
+
+        /* DC */
+        let dc = Self::get_value(rc, reader, &mut contexts.dc)?;
+        *prev_dc = (*prev_dc as i32 + dc) as i16;
+        block[0] = *prev_dc;
+
+        if !rc.decode_bit(reader, &mut contexts.coded)? {
+            return Ok(());
+        }
+
+        /* Significance map, a block that reaches the end has its last
+        coefficient implied */
+        let mut last = 63;
+        for i in 1..63 {
+            if rc.decode_bit(reader, &mut contexts.significant[i])? {
+                block[i] = 1;
+                if rc.decode_bit(reader, &mut contexts.last[i])? {
+                    last = i;
+                    break;
+                }
+            }
+        }
+        block[last] = 1;
+
+        /* Levels */
+        for i in 1..=last {
+            if block[i] == 0 {
+                continue;
+            }
+            let level = Self::get_magnitude(rc, reader, &mut contexts.level[band(i)])? as i32 + 1;
+            let negative = rc.decode_direct(reader, 1)? != 0;
+            block[i] = if negative { -level } else { level } as i16;
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::c63;
+    use crate::testing::*;
+
+    #[test]
+    fn syntax_elements_round_trip() {
+        let mut block = [0i16; 64];
+        block[0] = -300;
+        block[1] = 5;
+        block[2] = -1;
+        block[9] = 2000;
+        block[63] = 1;
+        let empty = [0i16; 64];
+        let mvs: [i16; 6] = [0, 1, -1, 16, -16, 100];
+
+        let mut writer = BitWriter::new();
+        {
+            let mut encoder = ArithEncoder::new(&mut writer);
+            let mut prev_dc = 0;
+            for round in 0..50 {
+                encoder.put_flag(Flag::UseMv, round % 3, round % 2 == 0);
+                encoder.put_mv_component(round % MV_CONTEXTS, mvs[round % mvs.len()]);
+                encoder.put_coefficients(round % 2, round % 3 == 0, &block, &mut prev_dc);
+                encoder.put_coefficients(1, false, &empty, &mut prev_dc);
+            }
+            encoder.finish();
+        }
+        writer.put_byte(0xFF);
+        writer.put_byte(0xD9);
+
+        let mut input = writer.data();
+        let mut reader = BitReader::new(&mut input);
+        let mut decoder = ArithDecoder::new(&mut reader).unwrap();
+        let mut prev_dc = 0;
+        for round in 0..50 {
+            assert_eq!(
+                decoder.get_flag(Flag::UseMv, round % 3).unwrap(),
+                round % 2 == 0
+            );
+            assert_eq!(
+                decoder.get_mv_component(round % MV_CONTEXTS).unwrap(),
+                mvs[round % mvs.len()]
+            );
+            let mut decoded = [0i16; 64];
+            decoder
+                .get_coefficients(round % 2, round % 3 == 0, &mut decoded, &mut prev_dc)
+                .unwrap();
+            assert_eq!(decoded, block);
+            decoder
+                .get_coefficients(1, false, &mut decoded, &mut prev_dc)
+                .unwrap();
+            assert_eq!(decoded, empty);
+        }
+
+        /* All coded bytes are consumed, the marker follows */
+        assert_eq!(reader.get_byte().unwrap(), 0xFF);
+        assert_eq!(reader.get_byte().unwrap(), 0xD9);
+    }
+
+    #[test]
+    fn smaller_than_huffman() {
+        /* At least 5% smaller on camera-like content. With every frame a
+        keyframe both coders code the same levels, only the size differs */
+        let tools = c63::CodingTools::new();
+        let [huffman, arithmetic] = size_and_psnr_of_each_coder(
+            QCIF,
+            tools,
+            |ctx| ctx.keyframe_interval = 1,
+            noisy_image,
+            4,
+        );
+        assert!(arithmetic.0 * 100 <= huffman.0 * 95);
+        assert_eq!(arithmetic.1, huffman.1);
+
+        /* With inter frames the C compatible Huffman stream has the vectors
+        of the lowest SAD, the arithmetic stream weighs their bits */
+        let [huffman, arithmetic] =
+            size_and_psnr_of_each_coder(QCIF, tools, |_| {}, noisy_image, 4);
+        assert!(arithmetic.0 * 100 <= huffman.0 * 95);
+        assert!((arithmetic.1 - huffman.1).abs() < 0.05);
+    }
+}
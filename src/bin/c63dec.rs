@@ -1,5 +1,7 @@
 use clap::{arg, command, value_parser};
 
+use c63_rust::{decode, yuv};
+
 #[derive(Debug)]
 struct DecoderOptions {
     input_file: std::path::PathBuf,
@@ -20,7 +22,7 @@ fn main() {
         )
         .get_matches();
 
-    let encoder_options = DecoderOptions {
+    let decoder_options = DecoderOptions {
         input_file: matches
             .get_one::<std::path::PathBuf>("input_file")
             .expect("required")
@@ -31,5 +33,32 @@ fn main() {
             .clone(),
     };
 
-    println!("options: {:#?}", encoder_options);
+    println!("options: {:#?}", decoder_options);
+
+    let input_file = std::fs::File::open(decoder_options.input_file).unwrap();
+    let mut output_file = std::fs::File::create(decoder_options.output_file).unwrap();
+    let mut input = std::io::BufReader::new(input_file);
+
+    let mut ctx = decode::DecodeContext::new();
+
+    loop {
+        // decode frame, stop at end of file
+        let more = match decode::decode_frame(&mut ctx, &mut input) {
+            Err(e) => panic!("{}", e),
+            Ok(more) => more,
+        };
+
+        if more {
+            println!("{}", ctx.framenum);
+        }
+
+        // frames come out in display order, the rest at the end of file
+        while let Some(image) = decode::next_display_image(&mut ctx, !more) {
+            yuv::dump_image(&image, ctx.width, ctx.height, &mut output_file).unwrap();
+        }
+
+        if !more {
+            break;
+        }
+    }
 }
@@ -1,12 +1,39 @@
 use clap::{arg, command, value_parser};
 
-use c63_rust::{c63, encode_context, yuv};
+use c63_rust::{c63, c63_write, encode_context, motion_search, quant, ratecontrol, yuv};
+
+use std::io::Write;
 
 #[derive(Debug)]
 struct EncoderOptions {
     image_height: u32,
     image_width: u32,
     frames: Option<u64>, // number of frames to encode
+    optimize_huffman: bool,
+    arithmetic: bool,
+    skip_blocks: bool,
+    mv_precision: u8,
+    mv_prediction: bool,
+    chroma_mv_from_luma: bool,
+    macroblock_partitions: bool,
+    reference_frames: u8,
+    b_frames: u8,
+    intra_prediction: bool,
+    deblocking: bool,
+    adaptive_quantization: bool,
+    aq_strength: f64,
+    me_method: motion_search::SearchMethod,
+    rdoq: bool,
+    rounding: quant::Rounding,
+    qmatrix_file: Option<std::path::PathBuf>,
+    qp: Option<u8>,
+    bitrate: Option<f64>, // kbit/s
+    rate_control: String,
+    vbv_size: Option<f64>, // kbit
+    fps: f64,
+    crf: Option<f64>,
+    pass: Option<u8>,
+    stats_file: Option<std::path::PathBuf>,
     input_file: std::path::PathBuf,
     output_file: std::path::PathBuf,
 }
@@ -38,12 +65,205 @@ fn main() {
                 .required(false)
                 .value_parser(value_parser!(u64)),
         )
+        .arg(
+            arg!(--"optimize-huffman" "Use huffman tables optimized for each GOP, written when the GOP is complete")
+                .required(false),
+        )
+        .arg(
+            arg!(--arithmetic "Use the adaptive arithmetic coder instead of huffman coding")
+                .required(false)
+                .conflicts_with("optimize-huffman"),
+        )
+        .arg(
+            arg!(--"skip-blocks" "Code blocks with the predicted motion vector and no residual with just a skip flag")
+                .required(false),
+        )
+        .arg(
+            arg!(--"mv-precision" <value> "Precision of motion vectors")
+                .required(false)
+                .value_parser(["integer", "half", "quarter"])
+                .default_value("integer"),
+        )
+        .arg(
+            arg!(--"mv-prediction" "Code motion vectors as the difference to the median of their neighbours")
+                .required(false),
+        )
+        .arg(
+            arg!(--"chroma-mv-from-luma" "Derive chroma motion vectors from luma instead of searching and coding them")
+                .required(false),
+        )
+        .arg(
+            arg!(--partitions "Choose between one motion vector per 16x16 macroblock and one per 8x8 block")
+                .required(false),
+        )
+        .arg(
+            arg!(--refs <value> "Number of previous frames inter frames can be predicted from")
+                .required(false)
+                .value_parser(value_parser!(u8).range(1..=c63::MAX_REFERENCE_FRAMES as i64))
+                .default_value("1"),
+        )
+        .arg(
+            arg!(--"b-frames" <value> "Number of B-frames, predicted from the frames on both sides, between two other frames")
+                .required(false)
+                .value_parser(value_parser!(u8).range(0..=c63::MAX_B_FRAMES as i64))
+                .default_value("0"),
+        )
+        .arg(
+            arg!(--"intra-prediction" "Predict intra blocks from the reconstructed blocks above and to the left")
+                .required(false),
+        )
+        .arg(
+            arg!(--deblock "Filter the edges between blocks of reconstructed frames, the coarser the quantization the stronger, fine qps leave them alone")
+                .required(false),
+        )
+        .arg(
+            arg!(--aq "Quantize flat macroblocks finer and textured ones coarser, coding a delta qp per macroblock")
+                .required(false),
+        )
+        .arg(
+            arg!(--"aq-strength" <value> "Delta qp per doubling of the activity of a macroblock with --aq; unlike the linear --qp, each delta qp scales the quantizer step by 2^(-1/6), so 6 halve it")
+                .required(false)
+                .value_parser(value_parser!(f64))
+                .default_value("1.0"),
+        )
+        .arg(
+            arg!(--"me-method" <value> "Motion search, the fast methods check far fewer vectors than the full search")
+                .required(false)
+                .value_parser(["full", "tss", "dia", "hex", "epzs"])
+                .default_value("full"),
+        )
+        .arg(
+            arg!(--rdoq "Drop or lower coefficients where the bits saved are worth more than the added distortion")
+                .required(false),
+        )
+        .arg(
+            arg!(--"dead-zone" "Round levels down more, from 1/3 in intra and 1/6 in inter blocks, to drop small noisy coefficients")
+                .required(false),
+        )
+        .arg(
+            arg!(--"intra-rounding" <value> "Fraction from which levels of intra blocks are rounded up, 0.5 is rounding to nearest")
+                .required(false)
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--"inter-rounding" <value> "Fraction from which levels of inter blocks are rounded up, 0.5 is rounding to nearest")
+                .required(false)
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--qmatrix <file> "Quantization matrices, 64 luma and 64 chroma values in raster order or after the word zigzag, scaled by the qp like the default ones")
+                .required(false)
+                .value_parser(value_parser!(std::path::PathBuf)),
+        )
+        .arg(
+            arg!(--qp <value> "Quality parameter from 1 to 100, higher is better quality and larger frames. The quantization matrices are scaled by 10 / qp")
+                .required(false)
+                .value_parser(value_parser!(u8).range(1..=c63::MAX_QP as i64)),
+        )
+        .arg(
+            arg!(--bitrate <value> "Target bitrate in kbit/s, the qp is then chosen per frame")
+                .required(false)
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--"rate-control" <mode> "Rate control mode for --bitrate")
+                .required(false)
+                .value_parser(["abr", "cbr"])
+                .default_value("abr"),
+        )
+        .arg(
+            arg!(--"vbv-size" <value> "Size of the rate control buffer in kbit, defaults to 1 second")
+                .required(false)
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--fps <value> "Frame rate of the input, used by rate control")
+                .required(false)
+                .value_parser(value_parser!(f64))
+                .default_value("25"),
+        )
+        .arg(
+            arg!(--crf <value> "Constant quality, qp follows the complexity of the frames around this level")
+                .required(false)
+                .value_parser(value_parser!(f64))
+                .conflicts_with("bitrate"),
+        )
+        .arg(
+            arg!(--pass <value> "Pass of a two-pass encode, 1 writes statistics to --stats and 2 reads them to hit --bitrate")
+                .required(false)
+                .value_parser(value_parser!(u8).range(1..=2))
+                .requires("stats")
+                .requires_if("2", "bitrate"),
+        )
+        .arg(
+            arg!(--stats <file> "First pass statistics file")
+                .required(false)
+                .value_parser(value_parser!(std::path::PathBuf)),
+        )
         .get_matches();
 
     let encoder_options = EncoderOptions {
         image_height: *matches.get_one::<u32>("height").expect("required"),
         image_width: *matches.get_one::<u32>("width").expect("required"),
         frames: matches.get_one::<u64>("frames").cloned(),
+        optimize_huffman: matches.get_flag("optimize-huffman"),
+        arithmetic: matches.get_flag("arithmetic"),
+        skip_blocks: matches.get_flag("skip-blocks"),
+        mv_precision: match matches
+            .get_one::<String>("mv-precision")
+            .expect("default")
+            .as_str()
+        {
+            "half" => 1,
+            "quarter" => 2,
+            _ => 0,
+        },
+        mv_prediction: matches.get_flag("mv-prediction"),
+        chroma_mv_from_luma: matches.get_flag("chroma-mv-from-luma"),
+        macroblock_partitions: matches.get_flag("partitions"),
+        reference_frames: *matches.get_one::<u8>("refs").expect("default"),
+        b_frames: *matches.get_one::<u8>("b-frames").expect("default"),
+        intra_prediction: matches.get_flag("intra-prediction"),
+        deblocking: matches.get_flag("deblock"),
+        adaptive_quantization: matches.get_flag("aq"),
+        aq_strength: *matches.get_one::<f64>("aq-strength").expect("default"),
+        me_method: match matches
+            .get_one::<String>("me-method")
+            .expect("default")
+            .as_str()
+        {
+            "tss" => motion_search::SearchMethod::ThreeStep,
+            "dia" => motion_search::SearchMethod::Diamond,
+            "hex" => motion_search::SearchMethod::Hexagon,
+            "epzs" => motion_search::SearchMethod::Epzs,
+            _ => motion_search::SearchMethod::Full,
+        },
+        rdoq: matches.get_flag("rdoq"),
+        rounding: {
+            let mut rounding = match matches.get_flag("dead-zone") {
+                true => quant::Rounding::DEAD_ZONE,
+                false => quant::Rounding::NEAREST,
+            };
+            if let Some(&intra) = matches.get_one::<f64>("intra-rounding") {
+                rounding.intra = intra;
+            }
+            if let Some(&inter) = matches.get_one::<f64>("inter-rounding") {
+                rounding.inter = inter;
+            }
+            rounding
+        },
+        qmatrix_file: matches.get_one::<std::path::PathBuf>("qmatrix").cloned(),
+        qp: matches.get_one::<u8>("qp").cloned(),
+        bitrate: matches.get_one::<f64>("bitrate").cloned(),
+        rate_control: matches
+            .get_one::<String>("rate-control")
+            .expect("default")
+            .clone(),
+        vbv_size: matches.get_one::<f64>("vbv-size").cloned(),
+        fps: *matches.get_one::<f64>("fps").expect("default"),
+        crf: matches.get_one::<f64>("crf").cloned(),
+        pass: matches.get_one::<u8>("pass").cloned(),
+        stats_file: matches.get_one::<std::path::PathBuf>("stats").cloned(),
         input_file: matches
             .get_one::<std::path::PathBuf>("input_file")
             .expect("required")
@@ -56,17 +276,103 @@ fn main() {
 
     println!("options: {:#?}", encoder_options);
 
+    for rounding in [
+        encoder_options.rounding.intra,
+        encoder_options.rounding.inter,
+    ] {
+        if !(0.0..=0.5).contains(&rounding) {
+            panic!("Rounding offsets must be between 0 and 0.5");
+        }
+    }
+
     let mut ctx = encode_context::EncodeContext::new(
         encoder_options.image_width as i32,
         encoder_options.image_height as i32,
     )
     .unwrap();
 
+    if encoder_options.arithmetic {
+        ctx.coding_tools.entropy_coder = c63::EntropyCoder::Arithmetic;
+    }
+    ctx.coding_tools.skip_blocks = encoder_options.skip_blocks;
+    ctx.coding_tools.mv_precision = encoder_options.mv_precision;
+    ctx.coding_tools.mv_prediction = encoder_options.mv_prediction;
+    ctx.coding_tools.chroma_mv_from_luma = encoder_options.chroma_mv_from_luma;
+    ctx.coding_tools.macroblock_partitions = encoder_options.macroblock_partitions;
+    ctx.coding_tools.reference_frames = encoder_options.reference_frames;
+    ctx.coding_tools.b_frames = encoder_options.b_frames;
+    ctx.coding_tools.intra_prediction = encoder_options.intra_prediction;
+    ctx.coding_tools.deblocking = encoder_options.deblocking;
+    ctx.coding_tools.adaptive_quantization = encoder_options.adaptive_quantization;
+    ctx.aq_strength = encoder_options.aq_strength;
+    ctx.me_method = encoder_options.me_method;
+    ctx.rdoq = encoder_options.rdoq;
+    ctx.rounding = encoder_options.rounding;
+
+    if let Some(qmatrix_file) = encoder_options.qmatrix_file.as_ref() {
+        let file = std::fs::File::open(qmatrix_file).unwrap();
+        match quant::read_qmatrix(std::io::BufReader::new(file)) {
+            Ok(matrices) => ctx.set_quant_matrices(matrices),
+            Err(e) => panic!("Invalid --qmatrix file: {}", e),
+        }
+    }
+
+    if let Some(qp) = encoder_options.qp {
+        ctx.set_qp(qp);
+    }
+
+    if let Some(crf) = encoder_options.crf {
+        ctx.rate_control = ratecontrol::RateControl::with_quality(crf);
+    }
+
+    if let Some(bitrate) = encoder_options.bitrate {
+        let mode = match encoder_options.rate_control.as_str() {
+            "cbr" => ratecontrol::RateControlMode::Cbr,
+            _ => ratecontrol::RateControlMode::Abr,
+        };
+        ctx.rate_control = ratecontrol::RateControl::with_bitrate(
+            mode,
+            bitrate,
+            encoder_options.fps,
+            encoder_options.vbv_size,
+        );
+    }
+
+    if encoder_options.pass == Some(2) {
+        let bitrate = encoder_options.bitrate.expect("required by --pass 2");
+        let stats_file = encoder_options
+            .stats_file
+            .as_ref()
+            .expect("required by --pass");
+        let stats_file = std::fs::File::open(stats_file).unwrap();
+        let stats = ratecontrol::read_stats(std::io::BufReader::new(stats_file)).unwrap();
+        ctx.rate_control =
+            ratecontrol::RateControl::second_pass(&stats, bitrate, encoder_options.fps).unwrap();
+    }
+
     let mut output_file = std::fs::File::create(encoder_options.output_file).unwrap();
     let mut input_file = std::fs::File::open(encoder_options.input_file).unwrap();
 
+    let mut gop_writer = c63_write::GopWriter::new();
+
     let mut num_frames = 0;
 
+    // write an encoded frame, rate control needs its size
+    let mut write_frame = |ctx: &mut encode_context::EncodeContext| {
+        let mut frame_data = Vec::new();
+        if encoder_options.optimize_huffman {
+            gop_writer.write_frame(ctx, &mut output_file).unwrap();
+            // the size with the default tables is close enough
+            if encoder_options.bitrate.is_some() || encoder_options.pass.is_some() {
+                c63_write::write_frame(ctx, &mut frame_data).unwrap();
+            }
+        } else {
+            c63_write::write_frame(ctx, &mut frame_data).unwrap();
+            output_file.write_all(&frame_data).unwrap();
+        }
+        ctx.rate_control.update(frame_data.len() * 8);
+    };
+
     loop {
         println!("{}", num_frames);
 
@@ -78,16 +384,12 @@ fn main() {
             Ok(i) => i,
         };
 
-        // encode image
-        encode_context::encode_image(&mut ctx, image);
-
-        yuv::dump_image(
-            &ctx.current_frame.as_ref().unwrap().predicted,
-            ctx.width,
-            ctx.height,
-            &mut output_file,
-        )
-        .unwrap();
+        // encode the frames that can be coded now, B-frames wait for the
+        // next frame they are predicted from
+        encode_context::push_image(&mut ctx, image);
+        while encode_context::encode_next_frame(&mut ctx) {
+            write_frame(&mut ctx);
+        }
 
         num_frames += 1;
         // if frame limit is set and num_frames is over limit then break
@@ -95,4 +397,22 @@ fn main() {
             break;
         }
     }
+
+    // encode the frames still waiting
+    encode_context::finish_input(&mut ctx);
+    while encode_context::encode_next_frame(&mut ctx) {
+        write_frame(&mut ctx);
+    }
+
+    // write the last GOP
+    gop_writer.flush(&mut output_file).unwrap();
+
+    if encoder_options.pass == Some(1) {
+        let stats_file = encoder_options
+            .stats_file
+            .as_ref()
+            .expect("required by --pass");
+        let mut stats_file = std::fs::File::create(stats_file).unwrap();
+        ratecontrol::write_stats(&mut stats_file, ctx.rate_control.stats()).unwrap();
+    }
 }
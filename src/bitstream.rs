@@ -0,0 +1,1052 @@
+use crate::c63;
+use crate::tables;
+
+use std::io::{self, Read};
+
+/// Bit level output, with JPEG byte stuffing of the entropy coded data
+pub struct BitWriter {
+    data: Vec<u8>,
+    bit_buffer: u32,
+    bit_buffer_width: u8,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        return BitWriter {
+            data: Vec::new(),
+            bit_buffer: 0,
+            bit_buffer_width: 0,
+        };
+    }
+
+    /// Write a byte directly, used for markers and segment headers
+    pub fn put_byte(&mut self, byte: u8) {
+        self.data.push(byte);
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Pad the last byte with 1-bits
+    pub fn flush_bits(&mut self) {
+        if self.bit_buffer_width > 0 {
+            self.put_bits(0xFF, 8 - self.bit_buffer_width);
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        return &self.data;
+    }
+}
+
+/// Output of the entropy coded data, either written as bits or recorded
+pub trait SymbolWriter {
+    /// Write the `n` least significant bits of `bits`, msb first
+    fn put_bits(&mut self, bits: u16, n: u8);
+
+    /// Write the huffman code of `symbol`
+    fn put_symbol(&mut self, table: &HuffmanTable, symbol: u8);
+}
+
+impl SymbolWriter for BitWriter {
+    fn put_bits(&mut self, bits: u16, n: u8) {
+        if n == 0 {
+            return;
+        }
+
+        self.bit_buffer <<= n;
+        self.bit_buffer |= bits as u32 & ((1u32 << n) - 1);
+        self.bit_buffer_width += n;
+
+        while self.bit_buffer_width >= 8 {
+            let byte = (self.bit_buffer >> (self.bit_buffer_width - 8)) as u8;
+            self.put_byte(byte);
+
+            /* A 0xFF in the entropy coded data must be followed by a zero byte,
+            otherwise it would be read as a marker. */
+            if byte == 0xFF {
+                self.put_byte(0x00);
+            }
+
+            self.bit_buffer_width -= 8;
+        }
+    }
+
+    fn put_symbol(&mut self, table: &HuffmanTable, symbol: u8) {
+        self.put_bits(table.code[symbol as usize], table.size[symbol as usize]);
+    }
+}
+
+pub enum Token {
+    Bits(u16, u8),
+    Symbol(u8, u8),
+}
+
+/// Records the entropy coded data as tokens, so it can be written later with
+/// other huffman tables than the ones it was recorded with
+pub struct SymbolRecorder {
+    pub tokens: Vec<Token>,
+}
+
+impl SymbolRecorder {
+    pub fn new() -> SymbolRecorder {
+        return SymbolRecorder { tokens: Vec::new() };
+    }
+
+    /// Add the number of times each symbol of table `id` is used to `frequencies`
+    pub fn count_symbols(&self, id: u8, frequencies: &mut [u32; 256]) {
+        for token in &self.tokens {
+            if let Token::Symbol(table_id, symbol) = *token {
+                if table_id == id {
+                    frequencies[symbol as usize] += 1;
+                }
+            }
+        }
+    }
+
+    /// Write the recorded data using the table in `huffman_tables` with the same id
+    pub fn replay<W: SymbolWriter>(&self, writer: &mut W, huffman_tables: &HuffmanTables) {
+        for token in &self.tokens {
+            match *token {
+                Token::Bits(bits, n) => writer.put_bits(bits, n),
+                Token::Symbol(id, symbol) => writer.put_symbol(huffman_tables.get(id), symbol),
+            }
+        }
+    }
+}
+
+impl SymbolWriter for SymbolRecorder {
+    fn put_bits(&mut self, bits: u16, n: u8) {
+        if n > 0 {
+            self.tokens.push(Token::Bits(bits, n));
+        }
+    }
+
+    fn put_symbol(&mut self, table: &HuffmanTable, symbol: u8) {
+        self.tokens.push(Token::Symbol(table.id, symbol));
+    }
+}
+
+/// Bit level input, removes the JPEG byte stuffing of the entropy coded data
+pub struct BitReader<'a, R: Read> {
+    input: &'a mut R,
+    bit_buffer: u32,
+    bit_buffer_width: u8,
+}
+
+impl<'a, R: Read> BitReader<'a, R> {
+    pub fn new(input: &'a mut R) -> BitReader<'a, R> {
+        return BitReader {
+            input: input,
+            bit_buffer: 0,
+            bit_buffer_width: 0,
+        };
+    }
+
+    /// Read a byte directly, used for markers and segment headers
+    pub fn get_byte(&mut self) -> Result<u8, io::Error> {
+        let mut byte = [0u8; 1];
+        self.input.read_exact(&mut byte)?;
+        return Ok(byte[0]);
+    }
+
+    pub fn get_bytes(&mut self, bytes: &mut [u8]) -> Result<(), io::Error> {
+        return self.input.read_exact(bytes);
+    }
+
+    /// Read a big endian 16 bit value, like segment sizes
+    pub fn get_size(&mut self) -> Result<u16, io::Error> {
+        let high = self.get_byte()? as u16;
+        let low = self.get_byte()? as u16;
+        return Ok((high << 8) | low);
+    }
+
+    pub fn skip_bytes(&mut self, n: usize) -> Result<(), io::Error> {
+        for _ in 0..n {
+            self.get_byte()?;
+        }
+        return Ok(());
+    }
+
+    /// Read `n` bits, msb first
+    pub fn get_bits(&mut self, n: u8) -> Result<u16, io::Error> {
+        if n == 0 {
+            return Ok(0);
+        }
+
+        while self.bit_buffer_width < n {
+            let byte = self.get_byte()?;
+
+            /* 0xFF is always followed by a stuffed zero byte in the entropy
+            coded data */
+            if byte == 0xFF {
+                let stuffing = self.get_byte()?;
+                if stuffing != 0x00 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected marker in entropy coded data",
+                    ));
+                }
+            }
+
+            self.bit_buffer = (self.bit_buffer << 8) | byte as u32;
+            self.bit_buffer_width += 8;
+        }
+
+        self.bit_buffer_width -= n;
+        let bits = (self.bit_buffer >> self.bit_buffer_width) & ((1u32 << n) - 1);
+
+        return Ok(bits as u16);
+    }
+
+    /// Read a huffman code and return its symbol
+    pub fn get_symbol(&mut self, table: &HuffmanTable) -> Result<u8, io::Error> {
+        let mut code: i32 = 0;
+
+        for length in 1..=16 {
+            code = (code << 1) | self.get_bits(1)? as i32;
+
+            if code <= table.maxcode[length] {
+                let index = table.valptr[length] + (code - table.mincode[length]) as usize;
+                return Ok(table.data[index]);
+            }
+        }
+
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid huffman code",
+        ));
+    }
+
+    /// Throw away the padding bits at the end of the entropy coded data
+    pub fn flush_bits(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_buffer_width = 0;
+    }
+}
+
+/// Id of the motion vector table, which is not part of the DHT segment
+pub const MV_TABLE_ID: u8 = 0x20;
+
+/// Canonical huffman table, as specified in a DHT segment
+pub struct HuffmanTable {
+    /// Table class (0 is DC, 1 is AC) in the high nibble, table number in the low
+    pub id: u8,
+    /// Number of codes of each length 1..=16
+    pub num_by_length: [u8; 16],
+    /// Symbols ordered by code
+    pub data: Vec<u8>,
+
+    code: [u16; 256],
+    size: [u8; 256],
+
+    /* Decoding tables, indexed by code length */
+    mincode: [i32; 17],
+    maxcode: [i32; 17],
+    valptr: [usize; 17],
+}
+
+impl HuffmanTable {
+    /// Build the canonical codes for a table specification
+    pub fn new(
+        id: u8,
+        num_by_length: &[u8; 16],
+        data: &[u8],
+    ) -> Result<HuffmanTable, &'static str> {
+        let n: usize = num_by_length.iter().map(|&x| x as usize).sum();
+        if n > data.len() || n > 256 {
+            return Err("Huffman table has more codes than symbols");
+        }
+
+        let mut table = HuffmanTable {
+            id: id,
+            num_by_length: *num_by_length,
+            data: data[..n].to_vec(),
+            code: [0; 256],
+            size: [0; 256],
+            mincode: [0; 17],
+            maxcode: [-1; 17],
+            valptr: [0; 17],
+        };
+
+        let mut code: u32 = 0;
+        let mut k: usize = 0;
+        for length in 1..=16 {
+            let count = num_by_length[length - 1] as usize;
+
+            if count > 0 {
+                table.valptr[length] = k;
+                table.mincode[length] = code as i32;
+
+                for _ in 0..count {
+                    let symbol = table.data[k] as usize;
+                    table.code[symbol] = code as u16;
+                    table.size[symbol] = length as u8;
+                    code += 1;
+                    k += 1;
+                }
+
+                table.maxcode[length] = code as i32 - 1;
+            }
+
+            if code > (1 << length) {
+                return Err("Huffman table has too many codes of a length");
+            }
+            code <<= 1;
+        }
+
+        return Ok(table);
+    }
+
+    /// Build a table from explicit codes, `entries` are (symbol, code, size)
+    ///
+    /// Codes are masked to their size, like the bit writer does. Decoding
+    /// follows the canonical order, a code given to two symbols decodes as the
+    /// first of them.
+    fn from_codes(id: u8, entries: &[(u8, u16, u8)]) -> HuffmanTable {
+        let mut sorted: Vec<(u8, u16, u8)> = entries
+            .iter()
+            .filter(|e| e.2 > 0)
+            .map(|&(symbol, code, size)| (symbol, (code as u32 & ((1 << size) - 1)) as u16, size))
+            .collect();
+        sorted.sort_by_key(|&(_, code, size)| (size, code));
+
+        let mut num_by_length = [0u8; 16];
+        for &(_, _, size) in &sorted {
+            num_by_length[size as usize - 1] += 1;
+        }
+        let data: Vec<u8> = sorted.iter().map(|&(symbol, _, _)| symbol).collect();
+
+        let mut table = HuffmanTable::new(id, &num_by_length, &data).unwrap();
+        for &(symbol, code, size) in &sorted {
+            table.code[symbol as usize] = code;
+            table.size[symbol as usize] = size;
+        }
+
+        return table;
+    }
+
+    /// Default DC table from tables::DCVLC, the symbol is the size of the value
+    pub fn dc_default(table: usize) -> HuffmanTable {
+        let entries: Vec<(u8, u16, u8)> = (0..tables::DCVLC[table].len())
+            .map(|size| {
+                (
+                    size as u8,
+                    tables::DCVLC[table][size],
+                    tables::DCVLC_SIZE[table][size],
+                )
+            })
+            .collect();
+
+        return HuffmanTable::from_codes(table as u8, &entries);
+    }
+
+    /// Default AC table from tables::ACVLC, the symbol is run << 4 | size
+    pub fn ac_default(table: usize) -> HuffmanTable {
+        let mut entries: Vec<(u8, u16, u8)> = Vec::new();
+        for run in 0..c63::HUFF_AC_ZERO as usize {
+            for size in 0..c63::HUFF_AC_SIZE as usize {
+                entries.push((
+                    ((run << 4) | size) as u8,
+                    tables::ACVLC[table][run][size],
+                    tables::ACVLC_SIZE[table][run][size],
+                ));
+            }
+        }
+
+        return HuffmanTable::from_codes(0x10 | table as u8, &entries);
+    }
+
+    /// AC table with the canonical codes of tables::ACVLC_NUM_BY_LENGTH and
+    /// tables::ACVLC_DATA, which differs from ac_default in the luma code of
+    /// run 0 size 10
+    pub fn ac_canonical(table: usize) -> HuffmanTable {
+        return HuffmanTable::new(
+            0x10 | table as u8,
+            &tables::ACVLC_NUM_BY_LENGTH[table],
+            &tables::ACVLC_DATA[table],
+        )
+        .unwrap();
+    }
+
+    /// Motion vector table from tables::MVVLC, the symbol is the size of the value
+    pub fn mv_default() -> HuffmanTable {
+        let entries: Vec<(u8, u16, u8)> = (0..tables::MVVLC.len())
+            .map(|size| (size as u8, tables::MVVLC[size], tables::MVVLC_SIZE[size]))
+            .collect();
+
+        return HuffmanTable::from_codes(MV_TABLE_ID, &entries);
+    }
+
+    /// Optimal table for the symbol `frequencies`, with codes of at most 16 bits
+    ///
+    /// This is the procedure from section K.2 of the JPEG standard. One extra
+    /// symbol is reserved so no code consists of only 1-bits.
+    pub fn optimal(id: u8, frequencies: &[u32; 256]) -> HuffmanTable {
+        const MAX_CLEN: usize = 32;
+
+        let mut freq = [0u64; 257];
+        for i in 0..256 {
+            freq[i] = frequencies[i] as u64;
+        }
+        freq[256] = 1;
+
+        let mut codesize = [0usize; 257];
+        let mut others = [-1i32; 257];
+
+        /* Huffman's algorithm, merge the two least frequent trees until one is left */
+        loop {
+            let mut c1: i32 = -1;
+            let mut v = u64::MAX;
+            for i in 0..257 {
+                if freq[i] > 0 && freq[i] <= v {
+                    v = freq[i];
+                    c1 = i as i32;
+                }
+            }
+
+            let mut c2: i32 = -1;
+            v = u64::MAX;
+            for i in 0..257 {
+                if freq[i] > 0 && freq[i] <= v && i as i32 != c1 {
+                    v = freq[i];
+                    c2 = i as i32;
+                }
+            }
+
+            if c2 < 0 {
+                break;
+            }
+
+            let (mut c1, mut c2) = (c1 as usize, c2 as usize);
+
+            freq[c1] += freq[c2];
+            freq[c2] = 0;
+
+            codesize[c1] += 1;
+            while others[c1] >= 0 {
+                c1 = others[c1] as usize;
+                codesize[c1] += 1;
+            }
+            others[c1] = c2 as i32;
+
+            codesize[c2] += 1;
+            while others[c2] >= 0 {
+                c2 = others[c2] as usize;
+                codesize[c2] += 1;
+            }
+        }
+
+        let mut bits = [0usize; MAX_CLEN + 1];
+        for i in 0..257 {
+            if codesize[i] > 0 {
+                bits[codesize[i]] += 1;
+            }
+        }
+
+        /* Limit the code lengths to 16 bits. A code of the longest length is
+        removed together with its prefix sibling, the sibling moves up one level,
+        and a shorter code is split in two. */
+        for i in (17..=MAX_CLEN).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+
+        /* Remove the reserved symbol from the longest code length */
+        let mut i = 16;
+        while i > 0 && bits[i] == 0 {
+            i -= 1;
+        }
+        if i > 0 {
+            bits[i] -= 1;
+        }
+
+        let mut num_by_length = [0u8; 16];
+        for length in 1..=16 {
+            num_by_length[length - 1] = bits[length] as u8;
+        }
+
+        /* Symbols ordered by code size, the limiting above keeps the order */
+        let mut data: Vec<u8> = Vec::new();
+        for length in 1..=MAX_CLEN {
+            for symbol in 0..256 {
+                if codesize[symbol] == length {
+                    data.push(symbol as u8);
+                }
+            }
+        }
+
+        return HuffmanTable::new(id, &num_by_length, &data).unwrap();
+    }
+
+    /// Length of the code for `symbol`, 0 if it has no code
+    pub fn code_size(&self, symbol: u8) -> u8 {
+        return self.size[symbol as usize];
+    }
+}
+
+/// The tables used to code a frame, DC and AC are indexed by table id
+pub struct HuffmanTables {
+    pub dc: [HuffmanTable; 2],
+    pub ac: [HuffmanTable; 2],
+    pub mv: HuffmanTable,
+}
+
+impl HuffmanTables {
+    /// The default tables from tables.rs, with the codes the C encoder writes
+    pub fn new() -> HuffmanTables {
+        return HuffmanTables {
+            dc: [HuffmanTable::dc_default(0), HuffmanTable::dc_default(1)],
+            ac: [HuffmanTable::ac_default(0), HuffmanTable::ac_default(1)],
+            mv: HuffmanTable::mv_default(),
+        };
+    }
+
+    /// The default tables with the canonical codes of their DHT specification,
+    /// every symbol can be decoded
+    pub fn canonical() -> HuffmanTables {
+        return HuffmanTables {
+            ac: [HuffmanTable::ac_canonical(0), HuffmanTable::ac_canonical(1)],
+            ..HuffmanTables::new()
+        };
+    }
+
+    /// Optimal DC and AC tables for the data in `recorders`
+    pub fn optimal(recorders: &[&SymbolRecorder]) -> HuffmanTables {
+        let build = |id: u8| {
+            let mut frequencies = [0u32; 256];
+            for recorder in recorders {
+                recorder.count_symbols(id, &mut frequencies);
+            }
+            return HuffmanTable::optimal(id, &frequencies);
+        };
+
+        return HuffmanTables {
+            dc: [build(0x00), build(0x01)],
+            ac: [build(0x10), build(0x11)],
+            mv: HuffmanTable::mv_default(),
+        };
+    }
+
+    pub fn get(&self, id: u8) -> &HuffmanTable {
+        return match id {
+            0x00 | 0x01 => &self.dc[(id & 0x0F) as usize],
+            0x10 | 0x11 => &self.ac[(id & 0x0F) as usize],
+            _ => &self.mv,
+        };
+    }
+}
+
+/// Number of bits needed to represent the magnitude of `i`
+pub fn bit_width(i: i16) -> u8 {
+    return (32 - (i as i32).abs().leading_zeros()) as u8;
+}
+
+/// Write a signed value as `size` bits, negative values in one's complement
+pub fn put_signed<W: SymbolWriter>(writer: &mut W, val: i16, size: u8) {
+    let mut val = val;
+    if val < 0 {
+        val -= 1;
+    }
+    writer.put_bits(val as u16, size);
+}
+
+/// Sign extend a value written with `put_signed`
+pub fn extend_sign(val: u16, size: u8) -> i16 {
+    if size == 0 {
+        return 0;
+    }
+    if (val as i32) < (1 << (size - 1)) {
+        return (val as i32 - (1 << size) + 1) as i16;
+    }
+    return val as i16;
+}
+
+/// Write a value as the code of its size followed by the value bits
+pub fn write_value<W: SymbolWriter>(writer: &mut W, table: &HuffmanTable, val: i16) {
+    let size = bit_width(val);
+    writer.put_symbol(table, size);
+    put_signed(writer, val, size);
+}
+
+pub fn read_value<R: Read>(
+    reader: &mut BitReader<R>,
+    table: &HuffmanTable,
+) -> Result<i16, io::Error> {
+    let size = reader.get_symbol(table)?;
+    if size > 15 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Value size out of range",
+        ));
+    }
+    let val = reader.get_bits(size)?;
+    return Ok(extend_sign(val, size));
+}
+
+/// Write the 64 zig-zagged coefficients of a block, the DC as a difference to
+/// `prev_dc` and the AC as run-length coded (run, size) symbols
+pub fn write_coefficients<W: SymbolWriter>(
+    writer: &mut W,
+    dc_table: &HuffmanTable,
+    ac_table: &HuffmanTable,
+    block: &[i16],
+    prev_dc: &mut i16,
+) {
+    /* Calculate DC component, and write to stream */
+    let dc = block[0] - *prev_dc;
+    *prev_dc = block[0];
+    write_value(writer, dc_table, dc);
+
+    /* Find the last nonzero entry of the ac-coefficients */
+    let mut last = 64;
+    while last > 1 && block[last - 1] == 0 {
+        last -= 1;
+    }
+
+    /* Put the nonzero ac-coefficients */
+    let mut num_ac: u8 = 0;
+    for &ac in &block[1..last] {
+        if ac == 0 {
+            num_ac += 1;
+            if num_ac == 16 {
+                writer.put_symbol(ac_table, 0xF0);
+                num_ac = 0;
+            }
+        } else {
+            let size = bit_width(ac);
+            writer.put_symbol(ac_table, (num_ac << 4) | size);
+            put_signed(writer, ac, size);
+            num_ac = 0;
+        }
+    }
+
+    /* Put end of block marker */
+    if last < 64 {
+        writer.put_symbol(ac_table, 0x00);
+    }
+}
+
+/// Read the coefficients of a block written with `write_coefficients`
+pub fn read_coefficients<R: Read>(
+    reader: &mut BitReader<R>,
+    dc_table: &HuffmanTable,
+    ac_table: &HuffmanTable,
+    block: &mut [i16],
+    prev_dc: &mut i16,
+) -> Result<(), io::Error> {
+    block[..64].fill(0);
+
+    /* Decode DC */
+    let dc = read_value(reader, dc_table)?;
+    block[0] = dc.checked_add(*prev_dc).ok_or_else(|| {
+        return io::Error::new(io::ErrorKind::InvalidData, "DC out of range");
+    })?;
+    *prev_dc = block[0];
+
+    /* Decode AC RLE */
+    let mut i = 1;
+    while i < 64 {
+        let symbol = reader.get_symbol(ac_table)?;
+        let run = (symbol >> 4) as usize;
+        let size = symbol & 0x0F;
+
+        if symbol == 0x00 {
+            /* End of block */
+            break;
+        }
+
+        i += run;
+        if size == 0 {
+            /* 16 zeros */
+            i += 1;
+            continue;
+        }
+        if i >= 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "AC run-length outside of block",
+            ));
+        }
+
+        block[i] = extend_sign(reader.get_bits(size)?, size);
+        i += 1;
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_round_trip() {
+        let mut writer = BitWriter::new();
+        writer.put_bits(0b101, 3);
+        writer.put_bits(0x1234, 16);
+        writer.put_bits(0, 0);
+        writer.put_bits(0b1, 1);
+        writer.flush_bits();
+
+        let data = writer.data().to_vec();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(reader.get_bits(3).unwrap(), 0b101);
+        assert_eq!(reader.get_bits(16).unwrap(), 0x1234);
+        assert_eq!(reader.get_bits(0).unwrap(), 0);
+        assert_eq!(reader.get_bits(1).unwrap(), 0b1);
+    }
+
+    #[test]
+    fn byte_stuffing() {
+        let mut writer = BitWriter::new();
+        writer.put_bits(0xFF, 8);
+        writer.put_bits(0xAB, 8);
+        writer.put_bits(0b1111, 4);
+        writer.flush_bits();
+        assert_eq!(writer.data(), [0xFF, 0x00, 0xAB, 0xFF, 0x00]);
+
+        let data = writer.data().to_vec();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(reader.get_bits(8).unwrap(), 0xFF);
+        assert_eq!(reader.get_bits(8).unwrap(), 0xAB);
+        assert_eq!(reader.get_bits(8).unwrap(), 0xFF);
+    }
+
+    #[test]
+    fn marker_in_entropy_data_is_an_error() {
+        let data = [0xFF, 0xD9];
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        assert!(reader.get_bits(8).is_err());
+    }
+
+    #[test]
+    fn signed_values() {
+        for val in -1024i16..=1024 {
+            let size = bit_width(val);
+            let mut writer = BitWriter::new();
+            put_signed(&mut writer, val, size);
+            writer.flush_bits();
+
+            let data = writer.data().to_vec();
+            let mut input = &data[..];
+            let mut reader = BitReader::new(&mut input);
+            assert_eq!(extend_sign(reader.get_bits(size).unwrap(), size), val);
+        }
+    }
+
+    #[test]
+    fn default_tables_are_canonical() {
+        let defaults = [
+            HuffmanTable::dc_default(0),
+            HuffmanTable::dc_default(1),
+            HuffmanTable::ac_default(0),
+            HuffmanTable::ac_default(1),
+            HuffmanTable::mv_default(),
+        ];
+
+        for table in defaults.iter() {
+            let mut canonical =
+                HuffmanTable::new(table.id, &table.num_by_length, &table.data).unwrap();
+            /* Except for the duplicate code of the C luma table */
+            if table.id == 0x10 {
+                assert_eq!(canonical.code[0x0A], 65411);
+                canonical.code[0x0A] = 65410;
+            }
+            assert_eq!(table.code, canonical.code);
+            assert_eq!(table.size, canonical.size);
+        }
+
+        /* The specifications in tables.rs describe the same codes */
+        for t in 0..2 {
+            assert_eq!(
+                HuffmanTable::dc_default(t).num_by_length,
+                tables::DCVLC_NUM_BY_LENGTH[t]
+            );
+            assert_eq!(
+                HuffmanTable::ac_default(t).num_by_length,
+                tables::ACVLC_NUM_BY_LENGTH[t]
+            );
+            let spec = HuffmanTable::new(
+                0x10 | t as u8,
+                &tables::ACVLC_NUM_BY_LENGTH[t],
+                &tables::ACVLC_DATA[t],
+            );
+            assert_eq!(HuffmanTable::ac_canonical(t).code, spec.unwrap().code);
+        }
+    }
+
+    #[test]
+    fn c_luma_table_duplicates_a_code() {
+        let c_table = HuffmanTable::ac_default(0);
+        let canonical = HuffmanTable::ac_canonical(0);
+
+        /* Run 0 size 10 is written with the code of size 9 and read as it */
+        assert_eq!(c_table.code[0x0A], c_table.code[0x09]);
+        let mut writer = BitWriter::new();
+        writer.put_symbol(&c_table, 0x0A);
+        writer.put_symbol(&canonical, 0x0A);
+        writer.flush_bits();
+
+        let data = writer.data().to_vec();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(reader.get_symbol(&c_table).unwrap(), 0x09);
+        assert_eq!(reader.get_symbol(&canonical).unwrap(), 0x0A);
+
+        /* The only difference of the tables */
+        for symbol in 0..=255u8 {
+            if symbol != 0x0A {
+                assert_eq!(
+                    c_table.code[symbol as usize],
+                    canonical.code[symbol as usize]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn symbols_round_trip() {
+        let table = HuffmanTable::ac_canonical(0);
+
+        let mut writer = BitWriter::new();
+        for &symbol in &table.data {
+            writer.put_symbol(&table, symbol);
+        }
+        writer.flush_bits();
+
+        let data = writer.data().to_vec();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        for &symbol in &table.data {
+            assert_eq!(reader.get_symbol(&table).unwrap(), symbol);
+        }
+    }
+
+    #[test]
+    fn coefficients_round_trip() {
+        let dc_table = HuffmanTable::dc_default(0);
+        let ac_table = HuffmanTable::ac_default(0);
+
+        let mut blocks = [[0i16; 64]; 4];
+        /* Only DC */
+        blocks[0][0] = 50;
+        /* Long zero runs, last coefficient set so no end of block */
+        blocks[1][0] = -20;
+        blocks[1][1] = 3;
+        blocks[1][40] = -700;
+        blocks[1][63] = 1;
+        /* Negative DC difference and large values */
+        blocks[2][0] = -1000;
+        blocks[2][2] = 1023;
+        blocks[2][20] = -1;
+        /* All zero */
+
+        let mut writer = BitWriter::new();
+        let mut prev_dc = 0;
+        for block in blocks.iter() {
+            write_coefficients(&mut writer, &dc_table, &ac_table, block, &mut prev_dc);
+        }
+        writer.flush_bits();
+
+        let data = writer.data().to_vec();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        let mut prev_dc = 0;
+        for block in blocks.iter() {
+            let mut decoded = [0x55i16; 64];
+            read_coefficients(
+                &mut reader,
+                &dc_table,
+                &ac_table,
+                &mut decoded,
+                &mut prev_dc,
+            )
+            .unwrap();
+            assert_eq!(&decoded, block);
+        }
+    }
+
+    #[test]
+    fn dc_out_of_range_is_an_error() {
+        /* A malformed stream can predict a DC past what a coefficient holds */
+        let dc_table = HuffmanTable::dc_default(0);
+        let ac_table = HuffmanTable::ac_default(0);
+
+        let mut writer = BitWriter::new();
+        write_value(&mut writer, &dc_table, 2047);
+        writer.flush_bits();
+
+        let data = writer.data().to_vec();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        let mut block = [0i16; 64];
+        let mut prev_dc = i16::MAX - 1000;
+        let error = read_coefficients(&mut reader, &dc_table, &ac_table, &mut block, &mut prev_dc)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn zero_run_of_sixteen() {
+        let dc_table = HuffmanTable::dc_default(1);
+        let ac_table = HuffmanTable::ac_default(1);
+
+        let mut block = [0i16; 64];
+        block[17] = 5;
+        block[50] = -2;
+
+        let mut writer = BitWriter::new();
+        let mut prev_dc = 0;
+        write_coefficients(&mut writer, &dc_table, &ac_table, &block, &mut prev_dc);
+
+        /* DC size 0, ZRL, (0, 3) + 3 bits, ZRL, ZRL, (0, 2) + 2 bits, EOB */
+        let expected_bits = 2 + 10 + (4 + 3) + 10 + 10 + (3 + 2) + 2;
+        let expected_bytes = (expected_bits + 7) / 8;
+        writer.flush_bits();
+        assert_eq!(writer.data().len(), expected_bytes);
+
+        let data = writer.data().to_vec();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        let mut decoded = [0i16; 64];
+        let mut prev_dc = 0;
+        read_coefficients(
+            &mut reader,
+            &dc_table,
+            &ac_table,
+            &mut decoded,
+            &mut prev_dc,
+        )
+        .unwrap();
+        assert_eq!(decoded, block);
+    }
+
+    #[test]
+    fn optimal_table_limits_code_length() {
+        /* Fibonacci frequencies give a code length of n - 1 without limiting */
+        let mut frequencies = [0u32; 256];
+        let (mut a, mut b) = (1u32, 1u32);
+        for i in 0..30 {
+            frequencies[i] = a;
+            let next = a + b;
+            a = b;
+            b = next;
+        }
+
+        let table = HuffmanTable::optimal(0x10, &frequencies);
+        assert_eq!(table.data.len(), 30);
+        for i in 0..30 {
+            assert!(table.code_size(i as u8) > 0);
+            assert!(table.code_size(i as u8) <= 16);
+        }
+
+        /* No code of only 1-bits */
+        for i in 0..30 {
+            let size = table.code_size(i as u8);
+            assert_ne!(table.code[i], ((1u32 << size) - 1) as u16);
+        }
+    }
+
+    #[test]
+    fn optimal_table_is_not_worse_than_default() {
+        let default = HuffmanTable::ac_default(0);
+
+        let mut frequencies = [0u32; 256];
+        frequencies[0x00] = 500;
+        frequencies[0x01] = 300;
+        frequencies[0x11] = 200;
+        frequencies[0x02] = 20;
+        frequencies[0xF0] = 10;
+        frequencies[0x3A] = 1;
+
+        let optimal = HuffmanTable::optimal(0x10, &frequencies);
+
+        let cost = |table: &HuffmanTable| -> u32 {
+            let mut bits = 0;
+            for symbol in 0..256 {
+                bits += frequencies[symbol] * table.code_size(symbol as u8) as u32;
+            }
+            return bits;
+        };
+        assert!(cost(&optimal) < cost(&default));
+
+        /* Only symbols in use get a code */
+        assert_eq!(optimal.data.len(), 6);
+        assert_eq!(optimal.code_size(0x03), 0);
+    }
+
+    #[test]
+    fn optimal_table_without_symbols() {
+        let table = HuffmanTable::optimal(0x01, &[0u32; 256]);
+        assert_eq!(table.num_by_length, [0; 16]);
+        assert!(table.data.is_empty());
+
+        /* A single symbol still gets a code */
+        let mut frequencies = [0u32; 256];
+        frequencies[5] = 10;
+        let table = HuffmanTable::optimal(0x01, &frequencies);
+        assert_eq!(table.code_size(5), 1);
+    }
+
+    #[test]
+    fn recorded_data_replays_with_optimal_tables() {
+        let defaults = HuffmanTables::new();
+
+        let mut block = [0i16; 64];
+        block[0] = 30;
+        block[1] = -4;
+        block[5] = 2;
+
+        let mut recorder = SymbolRecorder::new();
+        let mut prev_dc = 0;
+        recorder.put_bits(0b1, 1);
+        for _ in 0..3 {
+            write_coefficients(
+                &mut recorder,
+                &defaults.dc[1],
+                &defaults.ac[1],
+                &block,
+                &mut prev_dc,
+            );
+        }
+        write_value(&mut recorder, &defaults.mv, -7);
+
+        let optimal = HuffmanTables::optimal(&[&recorder]);
+        let mut writer = BitWriter::new();
+        recorder.replay(&mut writer, &optimal);
+        writer.flush_bits();
+
+        let data = writer.data().to_vec();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        let mut prev_dc = 0;
+        assert_eq!(reader.get_bits(1).unwrap(), 1);
+        for _ in 0..3 {
+            let mut decoded = [0i16; 64];
+            read_coefficients(
+                &mut reader,
+                &optimal.dc[1],
+                &optimal.ac[1],
+                &mut decoded,
+                &mut prev_dc,
+            )
+            .unwrap();
+            assert_eq!(decoded, block);
+        }
+        assert_eq!(read_value(&mut reader, &optimal.mv).unwrap(), -7);
+    }
+}
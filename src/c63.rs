@@ -31,11 +31,87 @@ pub enum JPEGMarker {
     DHT = 0xC4,
     SOS = 0xDA,
     EOI = 0xD9,
+    APP0 = 0xE0,
+}
+
+/* Identifier of the APP0 segment with the coding tools of this implementation,
+other APP0 segments are skipped by the decoder */
+pub const CODING_TOOLS_ID: [u8; 4] = *b"C63X";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntropyCoder {
+    Huffman = 0,
+    Arithmetic = 1,
+}
+
+/// Most reference frames an inter frame can be predicted from
+pub const MAX_REFERENCE_FRAMES: u8 = 8;
+
+/// Most B-frames between two other frames
+pub const MAX_B_FRAMES: u8 = 7;
+
+/// Highest quality parameter, qp is from 1 to this
+pub const MAX_QP: u8 = 100;
+
+/// Largest delta qp of a macroblock with adaptive quantization, in steps of a
+/// sixth of an octave of the quantizer
+pub const MAX_DELTA_QP: i8 = 12;
+
+/// Coding tools that extend the C reference format
+///
+/// Frames that use anything but the defaults carry them in an APP0 segment
+/// after SOI, so default streams stay readable by the C decoder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CodingTools {
+    pub entropy_coder: EntropyCoder,
+    pub skip_blocks: bool,           // inter frames have a skip flag per block
+    pub mv_precision: u8, // motion vectors are in units of 1/2^mv_precision pixels, at most 2
+    pub mv_prediction: bool, // motion vectors are coded as the difference to the median of their neighbours
+    pub chroma_mv_from_luma: bool, // chroma blocks have no vectors of their own, they follow luma
+    pub macroblock_partitions: bool, // inter frames code a partition mode per 16x16 macroblock
+    pub reference_frames: u8, // frames inter frames are predicted from, 1 to MAX_REFERENCE_FRAMES
+    pub b_frames: u8,        // B-frames between two other frames, at most MAX_B_FRAMES
+    pub intra_prediction: bool, // intra blocks are predicted from their reconstructed neighbours
+    pub deblocking: bool, // block edges of reconstructed frames are filtered, with thresholds from the quantizer steps
+    pub adaptive_quantization: bool, // each 16x16 macroblock codes a delta qp
+}
+
+impl CodingTools {
+    pub fn new() -> CodingTools {
+        return CodingTools {
+            entropy_coder: EntropyCoder::Huffman,
+            skip_blocks: false,
+            mv_precision: 0,
+            mv_prediction: false,
+            chroma_mv_from_luma: false,
+            macroblock_partitions: false,
+            reference_frames: 1,
+            b_frames: 0,
+            intra_prediction: false,
+            deblocking: false,
+            adaptive_quantization: false,
+        };
+    }
+
+    /// whether frames can be read by the C decoder, without an APP0 segment
+    pub fn c_compatible(&self) -> bool {
+        return *self == CodingTools::new();
+    }
+
+    /// frames kept in the decoded picture buffer, B-frames need the two frames
+    /// around them
+    pub fn dpb_size(&self) -> u8 {
+        if self.b_frames > 0 {
+            return self.reference_frames.max(2);
+        }
+        return self.reference_frames;
+    }
 }
 
 pub const HUFF_AC_ZERO: u8 = 16;
 pub const HUFF_AC_SIZE: u8 = 11;
 
+#[derive(Clone)]
 pub struct YUV {
     pub y: Vec<u8>,
     pub u: Vec<u8>,
@@ -68,15 +144,86 @@ impl DCT {
     }
 }
 
-#[derive(Default, Clone)]
+/// Which reference frames a block of a B-frame is predicted from
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Direction {
+    #[default]
+    Forward, // the earlier frame, the only direction of other frames
+    Backward,      // the later frame
+    Bidirectional, // the average of both
+}
+
+/// How an intra block is predicted from the reconstructed pixels above and to
+/// the left of it, in the order of their codes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IntraMode {
+    #[default]
+    Dc, // the mean of the neighbours
+    Vertical,   // the row above, repeated down
+    Horizontal, // the column to the left, repeated right
+    Planar,     // a gradient between the row above and the column to the left
+}
+
+pub const INTRA_MODES: [IntraMode; 4] = [
+    IntraMode::Dc,
+    IntraMode::Vertical,
+    IntraMode::Horizontal,
+    IntraMode::Planar,
+];
+
+#[derive(Default, Clone, PartialEq)]
 pub struct MacroBlock {
     pub use_mv: bool,
-    pub mv_x: i8,
+    pub mv_x: i8, // forward vector
     pub mv_y: i8,
+    pub ref_index: u8, // frame of the decoded picture buffer the vector points into
+    pub direction: Direction,
+    pub backward_mv_x: i8,
+    pub backward_mv_y: i8,
+    pub skip: bool, // the predicted vector and no residual, coded with just a flag
+    pub intra_mode: IntraMode, // of blocks without a vector, with intra prediction
+}
+
+impl MacroBlock {
+    /// the backward or forward vector if the block is predicted from that
+    /// direction
+    pub fn vector(&self, backward: bool) -> Option<(i32, i32)> {
+        if !self.use_mv {
+            return None;
+        }
+
+        return match (self.direction, backward) {
+            (Direction::Forward, true) | (Direction::Backward, false) => None,
+            (_, false) => Some((self.mv_x as i32, self.mv_y as i32)),
+            (_, true) => Some((self.backward_mv_x as i32, self.backward_mv_y as i32)),
+        };
+    }
 }
 
 pub type MacroBlockContainer = [Vec<MacroBlock>; COLOR_COMPONENTS];
 
+/// How the luma of a 16x16 macroblock is predicted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Partition {
+    #[default]
+    Split8x8, // a vector per 8x8 block, as in the C reference
+    Single16x16, // one vector for all 4 luma blocks
+}
+
+/// A 16x16 macroblock, the 4 luma blocks and the chroma block of each chroma
+/// plane that cover the same area of a 4:2:0 frame
+///
+/// Motion compensation and vector prediction work on 8x8 blocks, so the motion
+/// is stored per block in `Frame::mbs` and this is a view of it. With
+/// `Partition::Single16x16` the 4 luma blocks hold the vector of the
+/// macroblock, see `Frame::set_single_vector`.
+pub struct MacroBlock16x16<'a> {
+    pub partition: Partition,
+    pub y: [&'a MacroBlock; 4], // in raster order
+    pub u: &'a MacroBlock,
+    pub v: &'a MacroBlock,
+}
+
 pub struct Frame {
     pub orig: YUV,      // Original input image
     pub recons: YUV,    // Reconstructed image
@@ -84,12 +231,101 @@ pub struct Frame {
 
     pub residuals: DCT, // Difference between original image and predicted frame
 
-    pub mbs: MacroBlockContainer, // macroblocks
+    pub mbs: MacroBlockContainer,   // macroblocks
+    pub mb_cols: i32,               // 8x8 luma blocks per row
+    pub partitions: Vec<Partition>, // per 16x16 macroblock
+    pub delta_qps: Vec<i8>,         // per 16x16 macroblock, finer quantization if positive
+    pub keyframe: bool,
+    pub bidirectional: bool, // a B-frame, predicted from an earlier and a later frame
+
+    pub decode_number: u32, // position in the stream
+    pub display_number: u32,
+}
+
+/// The per frame fields written with SOF0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameHeader {
     pub keyframe: bool,
+    pub bidirectional: bool,
+    pub decode_number: u32,
+    pub display_number: u32,
+}
+
+/// The frames a frame is predicted from
+///
+/// Forward references are earlier frames in display order, the most recent
+/// first, a block chooses one by its reference index. B-frames have a single
+/// forward reference and the backward one.
+pub struct ReferenceFrames<'a> {
+    pub forward: Vec<&'a Frame>,
+    pub backward: Option<&'a Frame>,
+}
+
+impl<'a> ReferenceFrames<'a> {
+    /// the references of `frame` in the decoded picture buffer
+    /// `reference_frames`, None if it does not have enough frames
+    pub fn new(
+        reference_frames: &'a [Box<Frame>],
+        frame: &Frame,
+        coding_tools: &CodingTools,
+    ) -> Option<ReferenceFrames<'a>> {
+        if frame.keyframe {
+            return Some(ReferenceFrames {
+                forward: Vec::new(),
+                backward: None,
+            });
+        }
+
+        /* A B-frame is coded after the frames on both sides */
+        if frame.bidirectional {
+            if reference_frames.len() < 2 {
+                return None;
+            }
+            return Some(ReferenceFrames {
+                forward: vec![&reference_frames[1]],
+                backward: Some(&reference_frames[0]),
+            });
+        }
+
+        if reference_frames.is_empty() {
+            return None;
+        }
+        let n = reference_frames
+            .len()
+            .min(coding_tools.reference_frames as usize);
+        return Some(ReferenceFrames {
+            forward: reference_frames[..n].iter().map(|frame| &**frame).collect(),
+            backward: None,
+        });
+    }
 }
 
 pub type PaddingContainer = [i32; COLOR_COMPONENTS];
 
+/// compute the padded width and height of each color component
+pub fn compute_padding(width: i32, height: i32) -> (PaddingContainer, PaddingContainer) {
+    let ypw: i32 = (width as f32 / 16.0f32).ceil() as i32 * 16;
+    let yph: i32 = (height as f32 / 16.0f32).ceil() as i32 * 16;
+    let upw: i32 = (((width * COMPONENT_SIZE_UX as i32) as f32)
+        / (COMPONENT_SIZE_YX as f32 * 8.0f32))
+        .ceil() as i32
+        * 8;
+    let uph: i32 = (((height * COMPONENT_SIZE_UY as i32) as f32)
+        / (COMPONENT_SIZE_YY as f32 * 8.0f32))
+        .ceil() as i32
+        * 8;
+    let vpw: i32 = (((width * COMPONENT_SIZE_VX as i32) as f32)
+        / (COMPONENT_SIZE_YX as f32 * 8.0f32))
+        .ceil() as i32
+        * 8;
+    let vph: i32 = (((height * COMPONENT_SIZE_VY as i32) as f32)
+        / (COMPONENT_SIZE_YY as f32 * 8.0f32))
+        .ceil() as i32
+        * 8;
+
+    return ([ypw, upw, vpw], [yph, uph, vph]);
+}
+
 impl Frame {
     pub fn new(
         orig: YUV,
@@ -121,7 +357,71 @@ impl Frame {
                 vec![MacroBlock::default(); (mb_rows * mb_cols) as usize],
                 vec![MacroBlock::default(); (mb_rows * mb_cols) as usize],
             ],
+            mb_cols: mb_cols,
+            partitions: vec![Partition::default(); (mb_rows / 2 * mb_cols / 2) as usize],
+            delta_qps: vec![0; (mb_rows / 2 * mb_cols / 2) as usize],
             keyframe: keyframe,
+            bidirectional: false,
+            decode_number: 0,
+            display_number: 0,
+        };
+    }
+
+    pub fn header(&self) -> FrameHeader {
+        return FrameHeader {
+            keyframe: self.keyframe,
+            bidirectional: self.bidirectional,
+            decode_number: self.decode_number,
+            display_number: self.display_number,
         };
     }
+
+    /// the 16x16 macroblock at `mb_x`, `mb_y`
+    pub fn macroblock(&self, mb_x: i32, mb_y: i32) -> MacroBlock16x16<'_> {
+        let luma = |x: i32, y: i32| &self.mbs[COLOR_COMPONENT_Y][(y * self.mb_cols + x) as usize];
+        let chroma_index = (mb_y * (self.mb_cols / 2) + mb_x) as usize;
+
+        return MacroBlock16x16 {
+            partition: self.partitions[chroma_index],
+            y: [
+                luma(mb_x * 2, mb_y * 2),
+                luma(mb_x * 2 + 1, mb_y * 2),
+                luma(mb_x * 2, mb_y * 2 + 1),
+                luma(mb_x * 2 + 1, mb_y * 2 + 1),
+            ],
+            u: &self.mbs[COLOR_COMPONENT_U][chroma_index],
+            v: &self.mbs[COLOR_COMPONENT_V][chroma_index],
+        };
+    }
+
+    /// predict the luma of the 16x16 macroblock at `mb_x`, `mb_y` with the
+    /// single motion of `mb`
+    pub fn set_single_vector(&mut self, mb_x: i32, mb_y: i32, mb: MacroBlock) {
+        self.partitions[(mb_y * (self.mb_cols / 2) + mb_x) as usize] = Partition::Single16x16;
+        for y in mb_y * 2..mb_y * 2 + 2 {
+            for x in mb_x * 2..mb_x * 2 + 2 {
+                self.mbs[COLOR_COMPONENT_Y][(y * self.mb_cols + x) as usize] = mb.clone();
+            }
+        }
+    }
+}
+
+/// add the reconstructed `frame` to the decoded picture buffer
+/// `reference_frames`, most recent first, keeping at most `capacity` frames
+///
+/// A keyframe replaces all frames, later frames can not reference anything
+/// before it. B-frames are not used as references.
+pub fn push_reference_frame(
+    reference_frames: &mut Vec<Box<Frame>>,
+    frame: Box<Frame>,
+    capacity: u8,
+) {
+    if frame.bidirectional {
+        return;
+    }
+    if frame.keyframe {
+        reference_frames.clear();
+    }
+    reference_frames.insert(0, frame);
+    reference_frames.truncate(capacity as usize);
 }
@@ -0,0 +1,727 @@
+use crate::arith::ArithEncoder;
+use crate::bitstream::{BitWriter, HuffmanTable, HuffmanTables, SymbolRecorder};
+use crate::c63;
+use crate::encode_context;
+use crate::entropy::{self, EntropyEncoder, Flag, HuffmanEncoder};
+use crate::me;
+
+use std::io::{self, Write};
+
+fn put_marker(writer: &mut BitWriter, marker: c63::JPEGMarker) {
+    writer.put_byte(c63::JPEGMarker::DEF as u8);
+    writer.put_byte(marker as u8);
+}
+
+fn put_size(writer: &mut BitWriter, size: u16) {
+    writer.put_byte((size >> 8) as u8);
+    writer.put_byte((size & 0xFF) as u8);
+}
+
+fn write_soi(writer: &mut BitWriter) {
+    put_marker(writer, c63::JPEGMarker::SOI);
+}
+
+fn write_dqt(writer: &mut BitWriter, quanttbl: &[[u8; 64]; c63::COLOR_COMPONENTS]) {
+    let size: u16 = 2 + (c63::COLOR_COMPONENTS as u16 * 65);
+
+    put_marker(writer, c63::JPEGMarker::DQT);
+    put_size(writer, size);
+
+    for component in 0..c63::COLOR_COMPONENTS {
+        writer.put_byte(component as u8);
+        writer.put_bytes(&quanttbl[component]);
+    }
+}
+
+fn write_sof0(
+    writer: &mut BitWriter,
+    width: i32,
+    height: i32,
+    header: &c63::FrameHeader,
+    coding_tools: &c63::CodingTools,
+) {
+    let size: u16 = 8 + 3 * c63::COLOR_COMPONENTS as u16;
+
+    put_marker(writer, c63::JPEGMarker::SOF);
+    put_size(writer, size);
+
+    /* Precision */
+    writer.put_byte(8);
+
+    /* Width and height */
+    put_size(writer, height as u16);
+    put_size(writer, width as u16);
+
+    writer.put_byte(c63::COLOR_COMPONENTS as u8);
+
+    /* Component id, hsamp(4) | vsamp(4), quant table */
+    writer.put_byte(1);
+    writer.put_byte((c63::COMPONENT_SIZE_YX << 4) | c63::COMPONENT_SIZE_YY);
+    writer.put_byte(0);
+
+    writer.put_byte(2);
+    writer.put_byte((c63::COMPONENT_SIZE_UX << 4) | c63::COMPONENT_SIZE_UY);
+    writer.put_byte(1);
+
+    writer.put_byte(3);
+    writer.put_byte((c63::COMPONENT_SIZE_VX << 4) | c63::COMPONENT_SIZE_VY);
+    writer.put_byte(2);
+
+    /* Is this a keyframe or not? B-frames are 2 */
+    let frame_type = if header.bidirectional {
+        2
+    } else {
+        header.keyframe as u8
+    };
+    writer.put_byte(frame_type);
+
+    /* With B-frames the frames are not in display order */
+    if coding_tools.b_frames > 0 {
+        put_size(writer, header.decode_number as u16);
+        put_size(writer, header.display_number as u16);
+    }
+}
+
+fn write_dht_hts(writer: &mut BitWriter, id: u8, table: &HuffmanTable) {
+    writer.put_byte(id);
+    writer.put_bytes(&table.num_by_length);
+    writer.put_bytes(&table.data);
+}
+
+fn write_dht(writer: &mut BitWriter, huffman_tables: &HuffmanTables) {
+    /* 2 + n*(17+mi) */
+    let mut size: u16 = 2;
+    for table in huffman_tables.dc.iter().chain(huffman_tables.ac.iter()) {
+        size += 17 + table.data.len() as u16;
+    }
+
+    put_marker(writer, c63::JPEGMarker::DHT);
+    put_size(writer, size);
+
+    /* Write the 4 Huffman table specifications */
+    write_dht_hts(writer, 0x00, &huffman_tables.dc[0]); /* DC table 0 */
+    write_dht_hts(writer, 0x01, &huffman_tables.dc[1]); /* DC table 1 */
+    write_dht_hts(writer, 0x10, &huffman_tables.ac[0]); /* AC table 0 */
+    write_dht_hts(writer, 0x11, &huffman_tables.ac[1]); /* AC table 1 */
+}
+
+fn write_sos(writer: &mut BitWriter) {
+    let size: u16 = 6 + 2 * c63::COLOR_COMPONENTS as u16;
+
+    put_marker(writer, c63::JPEGMarker::SOS);
+    put_size(writer, size);
+
+    writer.put_byte(c63::COLOR_COMPONENTS as u8);
+
+    /* Component id, DC | AC huffman table */
+    writer.put_byte(1);
+    writer.put_byte(0x00);
+    writer.put_byte(2);
+    writer.put_byte(0x11);
+    writer.put_byte(3);
+    writer.put_byte(0x11);
+
+    writer.put_byte(0); /* ss, first AC */
+    writer.put_byte(63); /* se, last AC */
+    writer.put_byte(0); /* ah | al */
+}
+
+fn write_app0(writer: &mut BitWriter, coding_tools: &c63::CodingTools) {
+    let size: u16 = 2 + c63::CODING_TOOLS_ID.len() as u16 + 11;
+
+    put_marker(writer, c63::JPEGMarker::APP0);
+    put_size(writer, size);
+
+    writer.put_bytes(&c63::CODING_TOOLS_ID);
+    writer.put_byte(coding_tools.entropy_coder as u8);
+    writer.put_byte(coding_tools.skip_blocks as u8);
+    writer.put_byte(coding_tools.mv_precision);
+    writer.put_byte(coding_tools.mv_prediction as u8);
+    writer.put_byte(coding_tools.chroma_mv_from_luma as u8);
+    writer.put_byte(coding_tools.macroblock_partitions as u8);
+    writer.put_byte(coding_tools.reference_frames);
+    writer.put_byte(coding_tools.b_frames);
+    writer.put_byte(coding_tools.intra_prediction as u8);
+    writer.put_byte(coding_tools.deblocking as u8);
+    writer.put_byte(coding_tools.adaptive_quantization as u8);
+}
+
+fn write_eoi(writer: &mut BitWriter) {
+    put_marker(writer, c63::JPEGMarker::EOI);
+}
+
+fn write_block<E: EntropyEncoder>(
+    encoder: &mut E,
+    frame: &c63::Frame,
+    in_data: &[i16],
+    width: i32,
+    uoffset: i32,
+    voffset: i32,
+    prev_dc: &mut i16,
+    cc: usize,
+    channel: c63::ColorComponent,
+    skip_blocks: bool,
+    mcu_size: (i32, i32),
+    mv_prediction: bool,
+    derived_mv: bool,
+    reference_frames: usize,
+    intra_prediction: bool,
+) {
+    let mbs = &frame.mbs[channel];
+    let mb_index = (voffset / 8 * width / 8 + uoffset / 8) as usize;
+    let mb = &mbs[mb_index];
+    let (left, top) = entropy::neighbours(mbs, width, uoffset, voffset);
+
+    /* Skipped blocks are predicted with the vector they would inherit or the
+    one of me::skip_mv, the DC prediction is left as is */
+    if skip_blocks {
+        encoder.put_flag(Flag::Skip, entropy::skip_context(left, top), mb.skip);
+        if mb.skip {
+            return;
+        }
+    }
+
+    /* Chroma vectors derived from luma and the vectors of the other luma
+    blocks of a single vector macroblock are not coded */
+    if !derived_mv {
+        /* Use inter pred? */
+        encoder.put_flag(Flag::UseMv, entropy::use_mv_context(left, top), mb.use_mv);
+
+        if mb.use_mv {
+            /* Blocks of B-frames choose the direction they are predicted from */
+            if frame.bidirectional {
+                let bidirectional = mb.direction == c63::Direction::Bidirectional;
+                encoder.put_flag(Flag::Bidirectional, 0, bidirectional);
+                if !bidirectional {
+                    encoder.put_flag(Flag::Backward, 0, mb.direction == c63::Direction::Backward);
+                }
+            }
+
+            if mb.vector(false).is_some() {
+                entropy::put_ref_index(encoder, mb.ref_index, reference_frames);
+            }
+
+            for backward in [false, true] {
+                let (mv_x, mv_y) = match mb.vector(backward) {
+                    Some(mv) => mv,
+                    None => continue,
+                };
+
+                /* The vector is coded relative to the median of the neighbours,
+                or as in the C reference reused from the block to the left */
+                let predicted = if mv_prediction {
+                    Some(me::predict_mv(
+                        mbs,
+                        width / 8,
+                        uoffset / 8,
+                        voffset / 8,
+                        mcu_size.0,
+                        mcu_size.1,
+                        backward,
+                    ))
+                } else {
+                    left.and_then(|prev| prev.vector(backward))
+                };
+                let reuse_prev_mv = predicted == Some((mv_x, mv_y));
+
+                encoder.put_flag(Flag::ReuseMv, backward as usize, reuse_prev_mv);
+
+                if !reuse_prev_mv {
+                    let (pred_x, pred_y) = match predicted {
+                        Some(pred) if mv_prediction => pred,
+                        _ => (0, 0),
+                    };
+                    let (x_ctx, y_ctx) = entropy::mv_contexts(left, top);
+                    encoder.put_mv_component(x_ctx, (mv_x - pred_x) as i16);
+                    encoder.put_mv_component(y_ctx, (mv_y - pred_y) as i16);
+                }
+            }
+        }
+    }
+
+    /* Intra blocks are predicted from their neighbours */
+    if intra_prediction && !mb.use_mv {
+        entropy::put_intra_mode(encoder, mb.intra_mode);
+    }
+
+    /* Residuals are stored linear in memory */
+    let block_index = (uoffset * 8 + voffset * width) as usize;
+    encoder.put_coefficients(
+        cc,
+        mb.use_mv,
+        &in_data[block_index..block_index + 64],
+        prev_dc,
+    );
+}
+
+fn write_interleaved_data_mcu<E: EntropyEncoder>(
+    encoder: &mut E,
+    frame: &c63::Frame,
+    dct: &[i16],
+    ctx: &encode_context::EncodeContext,
+    h: u8,
+    v: u8,
+    x: i32,
+    y: i32,
+    prev_dc: &mut i16,
+    cc: usize,
+    channel: c63::ColorComponent,
+    skip_blocks: bool,
+    partition: c63::Partition,
+    reference_frames: usize,
+) {
+    let wi = ctx.padw[channel];
+    let he = ctx.padh[channel];
+    let h = h as i32;
+    let v = v as i32;
+
+    for j in (y * v * 8..(y + 1) * v * 8).step_by(8) {
+        let jj = std::cmp::min(j, he - 8);
+
+        for i in (x * h * 8..(x + 1) * h * 8).step_by(8) {
+            let ii = std::cmp::min(i, wi - 8);
+
+            let first = i == x * h * 8 && j == y * v * 8;
+            let derived_mv = (ctx.coding_tools.chroma_mv_from_luma
+                && channel != c63::COLOR_COMPONENT_Y)
+                || (partition == c63::Partition::Single16x16 && !first);
+
+            write_block(
+                encoder,
+                frame,
+                dct,
+                wi,
+                ii,
+                jj,
+                prev_dc,
+                cc,
+                channel,
+                skip_blocks,
+                (h, v),
+                ctx.coding_tools.mv_prediction,
+                derived_mv,
+                reference_frames,
+                ctx.coding_tools.intra_prediction,
+            );
+        }
+    }
+}
+
+fn write_interleaved_data<E: EntropyEncoder>(
+    encoder: &mut E,
+    ctx: &encode_context::EncodeContext,
+    frame: &c63::Frame,
+) {
+    let mut prev_dc: [i16; c63::COLOR_COMPONENTS] = [0; c63::COLOR_COMPONENTS];
+    let mut prev_delta_qp: i8 = 0;
+
+    /* Keyframes have no skipped blocks and no motion to partition */
+    let skip_blocks = ctx.coding_tools.skip_blocks && !frame.keyframe;
+    let partitions = ctx.coding_tools.macroblock_partitions && !frame.keyframe;
+
+    /* Vectors into other than the most recent frame have a reference index */
+    let reference_frames =
+        c63::ReferenceFrames::new(&ctx.reference_frames, frame, &ctx.coding_tools)
+            .map_or(0, |references| references.forward.len());
+
+    /* Set up which huffman tables we want to use */
+    let yhtbl = 0;
+    let uhtbl = 1;
+    let vhtbl = 1;
+
+    /* Find the number of MCU's for the intensity */
+    let ublocks = (ctx.ypw as f32 / (8.0f32 * c63::COMPONENT_SIZE_YX as f32)).ceil() as i32;
+    let vblocks = (ctx.yph as f32 / (8.0f32 * c63::COMPONENT_SIZE_YY as f32)).ceil() as i32;
+
+    /* Write the MCU's interleaved */
+    for v in 0..vblocks {
+        for u in 0..ublocks {
+            /* An MCU is a 16x16 macroblock, its partition comes first */
+            let partition = frame.partitions[(v * ublocks + u) as usize];
+            if partitions {
+                encoder.put_flag(
+                    Flag::Partition,
+                    entropy::partition_context(&frame.partitions, ublocks, u, v),
+                    partition == c63::Partition::Single16x16,
+                );
+            }
+
+            /* With adaptive quantization the change of its delta qp follows */
+            if ctx.coding_tools.adaptive_quantization {
+                let delta_qp = frame.delta_qps[(v * ublocks + u) as usize];
+                entropy::put_delta_qp_change(encoder, delta_qp - prev_delta_qp);
+                prev_delta_qp = delta_qp;
+            }
+
+            write_interleaved_data_mcu(
+                encoder,
+                frame,
+                &frame.residuals.y_dct,
+                ctx,
+                c63::COMPONENT_SIZE_YX,
+                c63::COMPONENT_SIZE_YY,
+                u,
+                v,
+                &mut prev_dc[c63::COLOR_COMPONENT_Y],
+                yhtbl,
+                c63::COLOR_COMPONENT_Y,
+                skip_blocks,
+                partition,
+                reference_frames,
+            );
+            write_interleaved_data_mcu(
+                encoder,
+                frame,
+                &frame.residuals.u_dct,
+                ctx,
+                c63::COMPONENT_SIZE_UX,
+                c63::COMPONENT_SIZE_UY,
+                u,
+                v,
+                &mut prev_dc[c63::COLOR_COMPONENT_U],
+                uhtbl,
+                c63::COLOR_COMPONENT_U,
+                skip_blocks,
+                partition,
+                reference_frames,
+            );
+            write_interleaved_data_mcu(
+                encoder,
+                frame,
+                &frame.residuals.v_dct,
+                ctx,
+                c63::COMPONENT_SIZE_VX,
+                c63::COMPONENT_SIZE_VY,
+                u,
+                v,
+                &mut prev_dc[c63::COLOR_COMPONENT_V],
+                vhtbl,
+                c63::COLOR_COMPONENT_V,
+                skip_blocks,
+                partition,
+                reference_frames,
+            );
+        }
+    }
+
+    encoder.finish();
+}
+
+/// Write all segments of a frame, `write_data` writes the entropy coded data
+fn write_segments<W: Write, F: FnOnce(&mut BitWriter)>(
+    output: &mut W,
+    width: i32,
+    height: i32,
+    header: &c63::FrameHeader,
+    quanttbl: &[[u8; 64]; c63::COLOR_COMPONENTS],
+    coding_tools: &c63::CodingTools,
+    huffman_tables: Option<&HuffmanTables>,
+    write_data: F,
+) -> Result<(), io::Error> {
+    let mut writer = BitWriter::new();
+
+    /* Start Of Image */
+    write_soi(&mut writer);
+    /* Coding tools, only if the frame is not C compatible */
+    if !coding_tools.c_compatible() {
+        write_app0(&mut writer, coding_tools);
+    }
+    /* Define Quantization Table(s) */
+    write_dqt(&mut writer, quanttbl);
+    /* Start Of Frame 0 (Baseline DCT) */
+    write_sof0(&mut writer, width, height, header, coding_tools);
+    /* Define Huffman Table(s), not needed by the arithmetic coder */
+    if let Some(huffman_tables) = huffman_tables {
+        write_dht(&mut writer, huffman_tables);
+    }
+    /* Start of Scan */
+    write_sos(&mut writer);
+
+    write_data(&mut writer);
+    writer.flush_bits();
+
+    /* End Of Image */
+    write_eoi(&mut writer);
+
+    output.write_all(writer.data())?;
+
+    return Ok(());
+}
+
+/// write the current frame of `ctx` to `output` as a c63 frame
+///
+/// The layout is the same as the C reference encoder: SOI, DQT with the three
+/// quantization tables in zig-zag order, SOF0 followed by one keyframe byte that
+/// is not counted in the segment length, DHT with the default tables, SOS, the
+/// interleaved entropy coded data and EOI. Each block starts with a use_mv bit,
+/// followed by a reuse-left-mv bit and the MVVLC coded vector if it is set.
+/// Like in the C encoder, luma AC coefficients of run 0 size 10 have the code of
+/// size 9 (see tables::ACVLC).
+///
+/// Any other coding tools in `ctx.coding_tools` are listed in an APP0 segment
+/// after SOI, and the AC coefficients have the codes of the DHT segment. With
+/// the arithmetic coder there is no DHT and the data is range coded instead. With
+/// motion vector prediction the reuse bit means the vector equals the median of
+/// its neighbours, otherwise only the difference to that median is coded. With
+/// macroblock partitions each MCU of an inter frame starts with a flag, if it is
+/// set only the first luma block of the MCU carries a vector. With more than one
+/// reference frame every vector is preceded by its truncated unary reference
+/// index. With adaptive quantization each MCU then codes the change of its
+/// delta qp from the previous MCU.
+pub fn write_frame<W: Write>(
+    ctx: &encode_context::EncodeContext,
+    output: &mut W,
+) -> Result<(), io::Error> {
+    let frame = match ctx.current_frame.as_ref() {
+        Some(f) => f,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No frame has been encoded",
+            ))
+        }
+    };
+
+    return match ctx.coding_tools.entropy_coder {
+        c63::EntropyCoder::Huffman => {
+            /* C compatible frames keep the code the C encoder gives luma AC run
+            0 size 10, other frames use the one of the DHT segment */
+            let huffman_tables = if ctx.coding_tools.c_compatible() {
+                HuffmanTables::new()
+            } else {
+                HuffmanTables::canonical()
+            };
+            write_segments(
+                output,
+                ctx.width,
+                ctx.height,
+                &frame.header(),
+                &ctx.quanttbl,
+                &ctx.coding_tools,
+                Some(&huffman_tables),
+                |writer| {
+                    let mut encoder = HuffmanEncoder::new(writer, &huffman_tables);
+                    write_interleaved_data(&mut encoder, ctx, frame);
+                },
+            )
+        }
+        c63::EntropyCoder::Arithmetic => write_segments(
+            output,
+            ctx.width,
+            ctx.height,
+            &frame.header(),
+            &ctx.quanttbl,
+            &ctx.coding_tools,
+            None,
+            |writer| {
+                let mut encoder = ArithEncoder::new(writer);
+                write_interleaved_data(&mut encoder, ctx, frame);
+            },
+        ),
+    };
+}
+
+struct RecordedFrame {
+    width: i32,
+    height: i32,
+    header: c63::FrameHeader,
+    quanttbl: [[u8; 64]; c63::COLOR_COMPONENTS],
+    coding_tools: c63::CodingTools,
+    data: SymbolRecorder,
+}
+
+/// Writes frames with huffman tables optimized for each GOP
+///
+/// The entropy coded data of the frames is recorded until the next keyframe.
+/// Then optimal tables are built from the symbol statistics of the whole GOP,
+/// and the recorded frames are written with them in their DHT segments.
+pub struct GopWriter {
+    frames: Vec<RecordedFrame>,
+}
+
+impl GopWriter {
+    pub fn new() -> GopWriter {
+        return GopWriter { frames: Vec::new() };
+    }
+
+    /// record the current frame of `ctx`, writing the previous GOP to `output`
+    /// if this frame starts a new one
+    pub fn write_frame<W: Write>(
+        &mut self,
+        ctx: &encode_context::EncodeContext,
+        output: &mut W,
+    ) -> Result<(), io::Error> {
+        let frame = match ctx.current_frame.as_ref() {
+            Some(f) => f,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "No frame has been encoded",
+                ))
+            }
+        };
+
+        if ctx.coding_tools.entropy_coder != c63::EntropyCoder::Huffman {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Optimized huffman tables need the huffman entropy coder",
+            ));
+        }
+
+        if frame.keyframe {
+            self.flush(output)?;
+        }
+
+        /* The tables are only used for their ids while recording */
+        let huffman_tables = HuffmanTables::new();
+        let mut data = SymbolRecorder::new();
+        let mut encoder = HuffmanEncoder::new(&mut data, &huffman_tables);
+        write_interleaved_data(&mut encoder, ctx, frame);
+
+        self.frames.push(RecordedFrame {
+            width: ctx.width,
+            height: ctx.height,
+            header: frame.header(),
+            quanttbl: ctx.quanttbl,
+            coding_tools: ctx.coding_tools,
+            data: data,
+        });
+
+        return Ok(());
+    }
+
+    /// write all recorded frames to `output`
+    pub fn flush<W: Write>(&mut self, output: &mut W) -> Result<(), io::Error> {
+        if self.frames.is_empty() {
+            return Ok(());
+        }
+
+        let recorders: Vec<&SymbolRecorder> = self.frames.iter().map(|f| &f.data).collect();
+        let huffman_tables = HuffmanTables::optimal(&recorders);
+
+        for frame in self.frames.iter() {
+            write_segments(
+                output,
+                frame.width,
+                frame.height,
+                &frame.header,
+                &frame.quanttbl,
+                &frame.coding_tools,
+                Some(&huffman_tables),
+                |writer| frame.data.replay(writer, &huffman_tables),
+            )?;
+        }
+
+        self.frames.clear();
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::testdata;
+    use crate::yuv;
+
+    /// the stream of pan_64x48.yuv encoded at `qp` with no other options
+    fn encode_pan(qp: u8) -> Vec<u8> {
+        let mut ctx = encode_context::EncodeContext::new(64, 48).unwrap();
+        ctx.set_qp(qp);
+        let mut input = std::fs::File::open(testdata("pan_64x48.yuv")).unwrap();
+
+        let mut output = Vec::new();
+        while let Ok(image) = yuv::read_yuv(&mut input, &ctx) {
+            encode_context::encode_image(&mut ctx, image);
+            write_frame(&ctx, &mut output).unwrap();
+        }
+        assert_eq!(ctx.framenum, 3);
+        return output;
+    }
+
+    fn encode_gray_frame() -> Vec<u8> {
+        let mut ctx = encode_context::EncodeContext::new(16, 16).unwrap();
+        let mut image = c63::YUV::new(16 * 16, 8 * 8, 8 * 8);
+        image.y.fill(128);
+        image.u.fill(128);
+        image.v.fill(128);
+        encode_context::encode_image(&mut ctx, image);
+
+        let mut output = Vec::new();
+        write_frame(&ctx, &mut output).unwrap();
+        return output;
+    }
+
+    #[test]
+    fn header_layout_matches_c_reference() {
+        let output = encode_gray_frame();
+
+        /* SOI */
+        assert_eq!(output[0..2], [0xFF, 0xD8]);
+
+        /* DQT, three tables with the default qp 25, the matrices divided by
+        2.5 and truncated like the C reference does */
+        assert_eq!(output[2..6], [0xFF, 0xDB, 0x00, 0xC5]);
+        assert_eq!(output[6], 0);
+        assert_eq!(
+            output[7..71],
+            [
+                6, 4, 4, 5, 4, 4, 6, 5, 5, 5, 7, 6, 6, 7, 9, 16, 10, 9, 8, 8, 9, 19, 14, 14, 11,
+                16, 23, 20, 24, 12, 22, 20, 22, 22, 25, 28, 36, 31, 25, 27, 34, 27, 22, 22, 32, 43,
+                32, 34, 38, 39, 41, 41, 41, 24, 30, 45, 48, 44, 40, 48, 36, 40, 41, 39
+            ]
+        );
+        assert_eq!(output[71], 1);
+        assert_eq!(
+            output[72..88],
+            [6, 7, 7, 9, 8, 9, 18, 10, 10, 18, 39, 26, 22, 26, 39, 39]
+        );
+        assert_eq!(output[136], 2);
+        assert_eq!(output[137..201], output[72..136]);
+
+        /* SOF0 and the keyframe byte */
+        assert_eq!(
+            output[201..221],
+            [
+                0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0x10, 0x00, 0x10, 0x03, 0x01, 0x22, 0x00, 0x02,
+                0x11, 0x01, 0x03, 0x11, 0x02, 0x01
+            ]
+        );
+
+        /* DHT */
+        assert_eq!(output[221..225], [0xFF, 0xC4, 0x01, 0xA2]);
+        assert_eq!(output[225], 0x00);
+        assert_eq!(output[254], 0x01);
+        assert_eq!(output[283], 0x10);
+        assert_eq!(output[462], 0x11);
+
+        /* SOS */
+        assert_eq!(
+            output[641..655],
+            [0xFF, 0xDA, 0x00, 0x0C, 0x03, 0x01, 0x00, 0x02, 0x11, 0x03, 0x11, 0x00, 0x3F, 0x00]
+        );
+
+        /* EOI */
+        assert_eq!(output[output.len() - 2..], [0xFF, 0xD9]);
+    }
+
+    #[test]
+    fn output_matches_golden_file() {
+        let golden = std::fs::read(testdata("pan_64x48_qp10.c63")).unwrap();
+        assert!(
+            encode_pan(10) == golden,
+            "output differs from the golden file"
+        );
+    }
+
+    #[test]
+    #[ignore = "needs the C reference goldens, see testdata/README.md"]
+    fn output_matches_c_reference() {
+        /* The C c63enc always codes at qp 25 */
+        let golden = std::fs::read(testdata("c_pan_64x48.c63")).unwrap();
+        assert!(
+            encode_pan(25) == golden,
+            "output differs from the C c63enc stream"
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use crate::c63;
+use crate::quant;
+
+/// how strongly the edge between blocks `p` and `q` is filtered, 0 leaves it
+/// alone
+///
+/// Intra blocks have the strongest edges. Inter blocks with residuals or with
+/// different motion can have an edge too, two inter blocks with the same motion
+/// and no residual are the same continuous prediction.
+fn edge_strength(p: &c63::MacroBlock, q: &c63::MacroBlock, p_coded: bool, q_coded: bool) -> i32 {
+    if !p.use_mv || !q.use_mv {
+        return 2;
+    }
+    if p_coded || q_coded {
+        return 1;
+    }
+
+    let motion = |mb: &c63::MacroBlock| (mb.ref_index, mb.vector(false), mb.vector(true));
+    return (motion(p) != motion(q)) as i32;
+}
+
+/// Filter thresholds of an edge, from the quantization tables of the blocks on
+/// both sides. Edges between blocks are filtered only where the step across
+/// them is small enough to be a quantization artifact and the pixels on both
+/// sides are smooth.
+struct Thresholds {
+    alpha: i32, // largest step across the edge
+    beta: i32,  // largest step next to the edge on each side
+    tc: i32,    // largest change of a pixel, per edge strength
+}
+
+impl Thresholds {
+    fn new(p: &[u8; 64], q: &[u8; 64]) -> Thresholds {
+        /* The table divides the orthonormal DCT coefficients, so its DC entry
+        is a quantizer step like the one of H.264, whose alpha is about 5/4 of
+        the step and whose beta about half of it. Both shrink with the step,
+        fine quantization leaves smaller steps at the edges than the content
+        has, down to a step of 1 where no edge is smooth enough. Like H.264
+        averages the QPs of the blocks, the step is the mean of both sides */
+        let step = (p[0] as i32 + q[0] as i32 + 1) / 2;
+        return Thresholds {
+            alpha: step * 5 / 4,
+            beta: step / 2,
+            tc: step / 4 + 1,
+        };
+    }
+}
+
+/// filter the line of 8 pixels `stride` apart from `start` across an edge,
+/// the first 4 are in the `p` block and the others in the `q` block
+fn filter_line(pixels: &mut [u8], start: usize, stride: usize, strength: i32, t: &Thresholds) {
+    let at = |i: usize| pixels[start + i * stride] as i32;
+    let (p1, p0, q0, q1) = (at(2), at(3), at(4), at(5));
+
+    if (p0 - q0).abs() >= t.alpha || (p1 - p0).abs() >= t.beta || (q1 - q0).abs() >= t.beta {
+        return;
+    }
+
+    let tc = t.tc * strength;
+    let delta = (((q0 - p0) * 4 + (p1 - q1) + 4) >> 3).clamp(-tc, tc);
+    let mut set = |i: usize, v: i32| pixels[start + i * stride] = v.clamp(0, 255) as u8;
+    set(3, p0 + delta);
+    set(4, q0 - delta);
+
+    /* Intra edges also smooth the second pixel on each side */
+    if strength > 1 {
+        set(2, p1 + delta / 2);
+        set(5, q1 - delta / 2);
+    }
+}
+
+/// whether the block `index` of a plane has any nonzero coefficient
+fn coded(residuals: &[i16], w: i32, index: usize) -> bool {
+    let cols = (w / 8) as usize;
+    let start = (index / cols) * 64 * cols + (index % cols) * 64;
+    return residuals[start..start + 64].iter().any(|&c| c != 0);
+}
+
+/// filter the edges between the 8x8 blocks of a reconstructed plane, first
+/// the vertical edges and then the horizontal ones
+pub fn deblock_plane(
+    recons: &mut [u8],
+    mbs: &[c63::MacroBlock],
+    residuals: &[i16],
+    w: i32,
+    h: i32,
+    quantization: &quant::PlaneQuantization,
+) {
+    let cols = w / 8;
+    let thresholds = |p: i32, q: i32| {
+        let table = |i: i32| quantization.block((i % cols * 8) as usize, (i / cols * 8) as usize);
+        return Thresholds::new(table(p), table(q));
+    };
+    let strength = |p: usize, q: usize| {
+        edge_strength(
+            &mbs[p],
+            &mbs[q],
+            coded(residuals, w, p),
+            coded(residuals, w, q),
+        )
+    };
+
+    for by in 0..h / 8 {
+        for bx in 1..cols {
+            let q = (by * cols + bx) as usize;
+            let s = strength(q - 1, q);
+            if s == 0 {
+                continue;
+            }
+            let t = thresholds(q as i32 - 1, q as i32);
+            for y in by * 8..by * 8 + 8 {
+                filter_line(recons, (y * w + bx * 8 - 4) as usize, 1, s, &t);
+            }
+        }
+    }
+
+    for by in 1..h / 8 {
+        for bx in 0..cols {
+            let q = (by * cols + bx) as usize;
+            let s = strength(q - cols as usize, q);
+            if s == 0 {
+                continue;
+            }
+            let t = thresholds(q as i32 - cols, q as i32);
+            for x in bx * 8..bx * 8 + 8 {
+                filter_line(recons, ((by * 8 - 4) * w + x) as usize, w as usize, s, &t);
+            }
+        }
+    }
+}
+
+/// filter the block edges of all planes of `frame`, which is quantized with
+/// `quantization`
+pub fn deblock_frame(
+    frame: &mut c63::Frame,
+    padw: &c63::PaddingContainer,
+    padh: &c63::PaddingContainer,
+    quantization: &[quant::PlaneQuantization; c63::COLOR_COMPONENTS],
+) {
+    let c63::Frame {
+        mbs,
+        residuals,
+        recons,
+        ..
+    } = frame;
+    for (cc, recons, residuals) in [
+        (c63::COLOR_COMPONENT_Y, &mut recons.y, &residuals.y_dct),
+        (c63::COLOR_COMPONENT_U, &mut recons.u, &residuals.u_dct),
+        (c63::COLOR_COMPONENT_V, &mut recons.v, &residuals.v_dct),
+    ] {
+        deblock_plane(
+            recons,
+            &mbs[cc],
+            residuals,
+            padw[cc],
+            padh[cc],
+            &quantization[cc],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const W: i32 = 16;
+
+    /// two blocks side by side, flat at `left` and `right`
+    fn plane(left: u8, right: u8) -> Vec<u8> {
+        let mut recons = vec![0u8; (W * 8) as usize];
+        for y in 0..8 {
+            for x in 0..W {
+                recons[(y * W + x) as usize] = if x < 8 { left } else { right };
+            }
+        }
+        return recons;
+    }
+
+    #[test]
+    fn small_steps_are_smoothed() {
+        let quantization =
+            quant::PlaneQuantization::new(&[40u8; 64], &[0], 1, c63::COLOR_COMPONENT_Y);
+        let intra = [c63::MacroBlock::default(), c63::MacroBlock::default()];
+        let residuals = [0i16; 128];
+
+        let mut recons = plane(100, 120);
+        deblock_plane(&mut recons, &intra, &residuals, W, 8, &quantization);
+        assert!(recons[6] > 100 && recons[7] > 100);
+        assert!(recons[8] < 120 && recons[9] < 120);
+        assert_eq!((recons[0], recons[15]), (100, 120));
+
+        /* An edge of the content is kept */
+        let mut recons = plane(20, 200);
+        deblock_plane(&mut recons, &intra, &residuals, W, 8, &quantization);
+        assert_eq!(recons, plane(20, 200));
+
+        /* So is the edge between blocks with the same motion and no residual,
+        the step is in the reference */
+        let inter = c63::MacroBlock {
+            use_mv: true,
+            mv_x: 3,
+            ..Default::default()
+        };
+        let mut recons = plane(100, 120);
+        deblock_plane(
+            &mut recons,
+            &[inter.clone(), inter],
+            &residuals,
+            W,
+            8,
+            &quantization,
+        );
+        assert_eq!(recons, plane(100, 120));
+    }
+
+    #[test]
+    fn finer_macroblocks_are_left_alone() {
+        /* Two chroma blocks are two macroblocks, each with its own delta qp */
+        let intra = [c63::MacroBlock::default(), c63::MacroBlock::default()];
+        let residuals = [0i16; 128];
+        let deblocked = |delta_qps: &[i8]| {
+            let quantization =
+                quant::PlaneQuantization::new(&[8u8; 64], delta_qps, 2, c63::COLOR_COMPONENT_U);
+            let mut recons = plane(100, 106);
+            deblock_plane(&mut recons, &intra, &residuals, W, 8, &quantization);
+            return recons;
+        };
+        assert_ne!(deblocked(&[0, 0]), plane(100, 106));
+        assert_eq!(
+            deblocked(&[c63::MAX_DELTA_QP, c63::MAX_DELTA_QP]),
+            plane(100, 106)
+        );
+    }
+
+    #[test]
+    fn coarse_quantization_is_smoothed() {
+        /* Coarse quantization of smooth content leaves steps at the block
+        edges */
+        let psnr_of = |deblocking: bool| {
+            let tools = c63::CodingTools {
+                deblocking,
+                ..c63::CodingTools::new()
+            };
+            let mut ctx = context(LARGE, tools);
+            ctx.set_qp(3);
+            return size_and_psnr(ctx, panning_image, 1).1;
+        };
+        assert!(psnr_of(true) > psnr_of(false));
+    }
+
+    #[test]
+    fn default_qp_is_smoothed() {
+        /* The thresholds shrink with the quantizer step, at the default qp the
+        filter still smooths edges */
+        let psnr_of = |deblocking: bool| {
+            let tools = c63::CodingTools {
+                deblocking,
+                ..c63::CodingTools::new()
+            };
+            let ctx = context(LARGE, tools);
+            assert_eq!(ctx.qp, 25);
+            return size_and_psnr(ctx, panning_color_image, 3).1;
+        };
+        assert!(psnr_of(true) > psnr_of(false));
+    }
+}
@@ -0,0 +1,1262 @@
+use crate::arith::ArithDecoder;
+use crate::bitstream::{BitReader, HuffmanTable, HuffmanTables};
+use crate::c63;
+use crate::deblock;
+use crate::entropy::{self, EntropyDecoder, Flag, HuffmanDecoder};
+use crate::intra;
+use crate::me;
+use crate::quant;
+
+use std::io::{self, Read};
+
+pub struct DecodeContext {
+    pub width: i32,
+    pub height: i32,
+
+    pub padw: c63::PaddingContainer,
+    pub padh: c63::PaddingContainer,
+
+    pub mb_cols: i32,
+    pub mb_rows: i32,
+
+    pub quanttbl: [[u8; 64]; c63::COLOR_COMPONENTS],
+    pub huffman_tables: HuffmanTables,
+    pub coding_tools: c63::CodingTools,
+
+    pub reference_frames: Vec<Box<c63::Frame>>, // most recent first
+    pub current_frame: Option<Box<c63::Frame>>,
+
+    /* Decoded images waiting to be output in display order */
+    decoded_images: Vec<(u32, c63::YUV)>,
+    output_number: u32,
+
+    pub framenum: i32,
+}
+
+impl DecodeContext {
+    pub fn new() -> DecodeContext {
+        return DecodeContext {
+            width: 0,
+            height: 0,
+            padw: [0; c63::COLOR_COMPONENTS],
+            padh: [0; c63::COLOR_COMPONENTS],
+            mb_cols: 0,
+            mb_rows: 0,
+            quanttbl: [[0; 64]; c63::COLOR_COMPONENTS],
+            huffman_tables: HuffmanTables::new(),
+            coding_tools: c63::CodingTools::new(),
+            reference_frames: Vec::new(),
+            current_frame: None,
+            decoded_images: Vec::new(),
+            output_number: 0,
+            framenum: 0,
+        };
+    }
+
+    /// set the image size, recomputing padding if it changed
+    fn set_size(&mut self, width: i32, height: i32) -> Result<(), io::Error> {
+        if width <= 0 || height <= 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid image size in SOF",
+            ));
+        }
+
+        if width != self.width || height != self.height {
+            let (padw, padh) = c63::compute_padding(width, height);
+            self.width = width;
+            self.height = height;
+            self.padw = padw;
+            self.padh = padh;
+            self.mb_cols = padw[c63::COLOR_COMPONENT_Y] / 8;
+            self.mb_rows = padh[c63::COLOR_COMPONENT_Y] / 8;
+            // the old references can not be used with a new size
+            self.reference_frames.clear();
+            self.current_frame = None;
+        }
+
+        return Ok(());
+    }
+}
+
+fn read_dqt<R: Read>(reader: &mut BitReader<R>, ctx: &mut DecodeContext) -> Result<(), io::Error> {
+    let size = reader.get_size()?;
+    let mut remaining = size as i32 - 2;
+
+    while remaining >= 65 {
+        let id = reader.get_byte()? as usize;
+        if id >= c63::COLOR_COMPONENTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid quantization table id in DQT",
+            ));
+        }
+        reader.get_bytes(&mut ctx.quanttbl[id])?;
+        remaining -= 65;
+    }
+
+    return reader.skip_bytes(remaining as usize);
+}
+
+fn read_sof0<R: Read>(reader: &mut BitReader<R>, ctx: &mut DecodeContext) -> Result<(), io::Error> {
+    let size = reader.get_size()?;
+
+    let precision = reader.get_byte()?;
+    if precision != 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Only 8 bit precision is supported",
+        ));
+    }
+
+    let height = reader.get_size()? as i32;
+    let width = reader.get_size()? as i32;
+
+    /* Skip component specifications, the layout is fixed */
+    reader.skip_bytes((size as usize).saturating_sub(7))?;
+
+    /* Is this a keyframe or not? B-frames are 2 */
+    let frame_type = reader.get_byte()?;
+    let bidirectional = frame_type == 2;
+    let keyframe = frame_type != 0 && !bidirectional;
+    if bidirectional && ctx.coding_tools.b_frames == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "B-frame in a stream without B-frames",
+        ));
+    }
+
+    /* With B-frames the frames are not in display order, the numbers are
+    the low 16 bits of the full ones */
+    let decode_number = ctx.framenum as u32;
+    let mut display_number = decode_number;
+    if ctx.coding_tools.b_frames > 0 {
+        if reader.get_size()? != decode_number as u16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Frame out of order",
+            ));
+        }
+        let offset = reader.get_size()?.wrapping_sub(decode_number as u16) as i16;
+        display_number = match decode_number.checked_add_signed(offset as i32) {
+            Some(n) => n,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid display number",
+                ))
+            }
+        };
+    }
+
+    ctx.set_size(width, height)?;
+
+    if let Some(frame) = ctx.current_frame.take() {
+        c63::push_reference_frame(
+            &mut ctx.reference_frames,
+            frame,
+            ctx.coding_tools.dpb_size(),
+        );
+    }
+
+    let mut frame = c63::Frame::new(
+        c63::YUV::new(0, 0, 0),
+        &ctx.padw,
+        &ctx.padh,
+        ctx.mb_cols,
+        ctx.mb_rows,
+        keyframe,
+    );
+    frame.bidirectional = bidirectional;
+    frame.decode_number = decode_number;
+    frame.display_number = display_number;
+
+    if c63::ReferenceFrames::new(&ctx.reference_frames, &frame, &ctx.coding_tools).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Inter frame without its reference frames",
+        ));
+    }
+
+    ctx.current_frame = Some(Box::new(frame));
+
+    return Ok(());
+}
+
+fn read_dht<R: Read>(reader: &mut BitReader<R>, ctx: &mut DecodeContext) -> Result<(), io::Error> {
+    let size = reader.get_size()?;
+    let mut remaining = size as i32 - 2;
+
+    while remaining >= 17 {
+        let id = reader.get_byte()?;
+        let mut num_by_length = [0u8; 16];
+        reader.get_bytes(&mut num_by_length)?;
+
+        let n: usize = num_by_length.iter().map(|&x| x as usize).sum();
+        let mut data = vec![0u8; n];
+        reader.get_bytes(&mut data)?;
+        remaining -= 17 + n as i32;
+
+        let table = match HuffmanTable::new(id, &num_by_length, &data) {
+            Ok(t) => t,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+
+        /* Table class (0 is DC, 1 is AC) in the high nibble, id in the low */
+        let index = (id & 0x0F) as usize;
+        if index > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid huffman table id in DHT",
+            ));
+        }
+        match id >> 4 {
+            0 => ctx.huffman_tables.dc[index] = table,
+            1 => ctx.huffman_tables.ac[index] = table,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid huffman table class in DHT",
+                ))
+            }
+        };
+    }
+
+    if remaining != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DHT size does not match its tables",
+        ));
+    }
+
+    return Ok(());
+}
+
+fn read_app0<R: Read>(reader: &mut BitReader<R>, ctx: &mut DecodeContext) -> Result<(), io::Error> {
+    let size = reader.get_size()?;
+    let mut remaining = (size as usize).saturating_sub(2);
+
+    let mut id = [0u8; 4];
+    if remaining < id.len() {
+        return reader.skip_bytes(remaining);
+    }
+    reader.get_bytes(&mut id)?;
+    remaining -= id.len();
+
+    /* Some other application's data */
+    if id != c63::CODING_TOOLS_ID {
+        return reader.skip_bytes(remaining);
+    }
+
+    /* Fields that are missing keep their defaults */
+    if remaining >= 1 {
+        ctx.coding_tools.entropy_coder = match reader.get_byte()? {
+            0 => c63::EntropyCoder::Huffman,
+            1 => c63::EntropyCoder::Arithmetic,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unknown entropy coder in APP0",
+                ))
+            }
+        };
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.skip_blocks = reader.get_byte()? != 0;
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.mv_precision = reader.get_byte()?;
+        if ctx.coding_tools.mv_precision > 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported motion vector precision in APP0",
+            ));
+        }
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.mv_prediction = reader.get_byte()? != 0;
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.chroma_mv_from_luma = reader.get_byte()? != 0;
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.macroblock_partitions = reader.get_byte()? != 0;
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.reference_frames = reader.get_byte()?;
+        if !(1..=c63::MAX_REFERENCE_FRAMES).contains(&ctx.coding_tools.reference_frames) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported number of reference frames in APP0",
+            ));
+        }
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.b_frames = reader.get_byte()?;
+        if ctx.coding_tools.b_frames > c63::MAX_B_FRAMES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported number of B-frames in APP0",
+            ));
+        }
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.intra_prediction = reader.get_byte()? != 0;
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.deblocking = reader.get_byte()? != 0;
+        remaining -= 1;
+    }
+    if remaining >= 1 {
+        ctx.coding_tools.adaptive_quantization = reader.get_byte()? != 0;
+        remaining -= 1;
+    }
+
+    return reader.skip_bytes(remaining);
+}
+
+fn read_block<D: EntropyDecoder>(
+    decoder: &mut D,
+    mbs: &mut [c63::MacroBlock],
+    out_data: &mut [i16],
+    width: i32,
+    uoffset: i32,
+    voffset: i32,
+    prev_dc: &mut i16,
+    cc: usize,
+    skip_blocks: bool,
+    mcu_size: (i32, i32),
+    mv_prediction: bool,
+    inherited: Option<c63::MacroBlock>,
+    reference_frames: usize,
+    bidirectional: bool,
+    intra_prediction: bool,
+) -> Result<(), io::Error> {
+    let mb_index = (voffset / 8 * width / 8 + uoffset / 8) as usize;
+    let (left, top) = entropy::neighbours(mbs, width, uoffset, voffset);
+    let skip_ctx = entropy::skip_context(left, top);
+    let use_mv_ctx = entropy::use_mv_context(left, top);
+    let (x_ctx, y_ctx) = entropy::mv_contexts(left, top);
+
+    /* Residuals are stored linear in memory */
+    let block_index = (uoffset * 8 + voffset * width) as usize;
+
+    /* Skipped blocks are predicted with the vector they would inherit or the
+    one of me::skip_mv, the DC prediction is left as is */
+    if skip_blocks && decoder.get_flag(Flag::Skip, skip_ctx)? {
+        mbs[mb_index] = match inherited {
+            Some(mb) => c63::MacroBlock { skip: true, ..mb },
+            None => {
+                let (mv_x, mv_y) = me::skip_mv(
+                    mbs,
+                    width / 8,
+                    uoffset / 8,
+                    voffset / 8,
+                    mcu_size.0,
+                    mcu_size.1,
+                    mv_prediction,
+                );
+                c63::MacroBlock {
+                    use_mv: true,
+                    skip: true,
+                    mv_x: mv_x as i8,
+                    mv_y: mv_y as i8,
+                    ..Default::default()
+                }
+            }
+        };
+        out_data[block_index..block_index + 64].fill(0);
+        return Ok(());
+    }
+
+    if let Some(mb) = inherited {
+        /* Chroma vectors derived from luma and the vectors of the other luma
+        blocks of a single vector macroblock are not coded */
+        mbs[mb_index] = mb;
+    } else {
+        /* Read motion vector */
+        let use_mv = decoder.get_flag(Flag::UseMv, use_mv_ctx)?;
+        mbs[mb_index].use_mv = use_mv;
+
+        if use_mv {
+            /* Blocks of B-frames choose the direction they are predicted from */
+            if bidirectional {
+                mbs[mb_index].direction = if decoder.get_flag(Flag::Bidirectional, 0)? {
+                    c63::Direction::Bidirectional
+                } else if decoder.get_flag(Flag::Backward, 0)? {
+                    c63::Direction::Backward
+                } else {
+                    c63::Direction::Forward
+                };
+            }
+
+            let direction = mbs[mb_index].direction;
+            if direction != c63::Direction::Backward {
+                mbs[mb_index].ref_index = entropy::get_ref_index(decoder, reference_frames)?;
+            }
+
+            for backward in [false, true] {
+                let used = match direction {
+                    c63::Direction::Forward => !backward,
+                    c63::Direction::Backward => backward,
+                    c63::Direction::Bidirectional => true,
+                };
+                if !used {
+                    continue;
+                }
+
+                let reuse_prev_mv = decoder.get_flag(Flag::ReuseMv, backward as usize)?;
+
+                /* The vector is relative to the median of the neighbours, or as in
+                the C reference can be reused from the block to the left */
+                let (pred_x, pred_y) = if mv_prediction {
+                    me::predict_mv(
+                        mbs,
+                        width / 8,
+                        uoffset / 8,
+                        voffset / 8,
+                        mcu_size.0,
+                        mcu_size.1,
+                        backward,
+                    )
+                } else if reuse_prev_mv {
+                    if uoffset == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Motion vector reuse in the first column",
+                        ));
+                    }
+                    mbs[mb_index - 1].vector(backward).unwrap_or((0, 0))
+                } else {
+                    (0, 0)
+                };
+
+                let (mv_x, mv_y) = if reuse_prev_mv {
+                    (pred_x, pred_y)
+                } else {
+                    (
+                        pred_x + decoder.get_mv_component(x_ctx)? as i32,
+                        pred_y + decoder.get_mv_component(y_ctx)? as i32,
+                    )
+                };
+                if !(-127..=127).contains(&mv_x) || !(-127..=127).contains(&mv_y) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Motion vector out of range",
+                    ));
+                }
+                if backward {
+                    mbs[mb_index].backward_mv_x = mv_x as i8;
+                    mbs[mb_index].backward_mv_y = mv_y as i8;
+                } else {
+                    mbs[mb_index].mv_x = mv_x as i8;
+                    mbs[mb_index].mv_y = mv_y as i8;
+                }
+            }
+        }
+    }
+
+    /* Intra blocks are predicted from their neighbours */
+    if intra_prediction && !mbs[mb_index].use_mv {
+        mbs[mb_index].intra_mode = entropy::get_intra_mode(decoder)?;
+    }
+
+    return decoder.get_coefficients(
+        cc,
+        mbs[mb_index].use_mv,
+        &mut out_data[block_index..block_index + 64],
+        prev_dc,
+    );
+}
+
+fn read_interleaved_data_mcu<D: EntropyDecoder>(
+    decoder: &mut D,
+    mbs: &mut [c63::MacroBlock],
+    dct: &mut [i16],
+    wi: i32,
+    he: i32,
+    h: u8,
+    v: u8,
+    x: i32,
+    y: i32,
+    prev_dc: &mut i16,
+    cc: usize,
+    skip_blocks: bool,
+    mv_prediction: bool,
+    partition: c63::Partition,
+    luma_mbs: Option<&[c63::MacroBlock]>,
+    reference_frames: usize,
+    bidirectional: bool,
+    intra_prediction: bool,
+) -> Result<(), io::Error> {
+    let h = h as i32;
+    let v = v as i32;
+    let first = (y * v * wi / 8 + x * h) as usize;
+
+    for j in (y * v * 8..(y + 1) * v * 8).step_by(8) {
+        let jj = std::cmp::min(j, he - 8);
+
+        for i in (x * h * 8..(x + 1) * h * 8).step_by(8) {
+            let ii = std::cmp::min(i, wi - 8);
+
+            let inherited = if let Some(luma_mbs) = luma_mbs {
+                Some(me::chroma_mv_from_luma(luma_mbs, wi / 8, ii / 8, jj / 8))
+            } else if partition == c63::Partition::Single16x16 && (i, j) != (x * h * 8, y * v * 8) {
+                Some(c63::MacroBlock {
+                    skip: false,
+                    ..mbs[first].clone()
+                })
+            } else {
+                None
+            };
+
+            read_block(
+                decoder,
+                mbs,
+                dct,
+                wi,
+                ii,
+                jj,
+                prev_dc,
+                cc,
+                skip_blocks,
+                (h, v),
+                mv_prediction,
+                inherited,
+                reference_frames,
+                bidirectional,
+                intra_prediction,
+            )?;
+        }
+    }
+
+    return Ok(());
+}
+
+fn read_interleaved_data<D: EntropyDecoder>(
+    decoder: &mut D,
+    padw: &c63::PaddingContainer,
+    padh: &c63::PaddingContainer,
+    frame: &mut c63::Frame,
+    coding_tools: &c63::CodingTools,
+    reference_frames: usize,
+) -> Result<(), io::Error> {
+    let mut prev_dc: [i16; c63::COLOR_COMPONENTS] = [0; c63::COLOR_COMPONENTS];
+    let mut delta_qp: i8 = 0;
+
+    /* Keyframes have no skipped blocks and no motion to partition */
+    let skip_blocks = coding_tools.skip_blocks && !frame.keyframe;
+    let partitions = coding_tools.macroblock_partitions && !frame.keyframe;
+
+    /* Set up which huffman tables we want to use */
+    let yhtbl = 0;
+    let uhtbl = 1;
+    let vhtbl = 1;
+
+    /* Find the number of MCU's for the intensity */
+    let ypw = padw[c63::COLOR_COMPONENT_Y];
+    let yph = padh[c63::COLOR_COMPONENT_Y];
+    let ublocks = (ypw as f32 / (8.0f32 * c63::COMPONENT_SIZE_YX as f32)).ceil() as i32;
+    let vblocks = (yph as f32 / (8.0f32 * c63::COMPONENT_SIZE_YY as f32)).ceil() as i32;
+
+    let [y_mbs, u_mbs, v_mbs] = &mut frame.mbs;
+
+    /* Read the MCU's interleaved */
+    for v in 0..vblocks {
+        for u in 0..ublocks {
+            /* An MCU is a 16x16 macroblock, its partition comes first */
+            if partitions {
+                let ctx = entropy::partition_context(&frame.partitions, ublocks, u, v);
+                if decoder.get_flag(Flag::Partition, ctx)? {
+                    frame.partitions[(v * ublocks + u) as usize] = c63::Partition::Single16x16;
+                }
+            }
+
+            /* With adaptive quantization the change of its delta qp follows */
+            if coding_tools.adaptive_quantization {
+                delta_qp += entropy::get_delta_qp_change(decoder)?;
+                if delta_qp.abs() > c63::MAX_DELTA_QP {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Delta qp out of range",
+                    ));
+                }
+                frame.delta_qps[(v * ublocks + u) as usize] = delta_qp;
+            }
+            let partition = frame.partitions[(v * ublocks + u) as usize];
+
+            read_interleaved_data_mcu(
+                decoder,
+                y_mbs,
+                &mut frame.residuals.y_dct,
+                padw[c63::COLOR_COMPONENT_Y],
+                padh[c63::COLOR_COMPONENT_Y],
+                c63::COMPONENT_SIZE_YX,
+                c63::COMPONENT_SIZE_YY,
+                u,
+                v,
+                &mut prev_dc[c63::COLOR_COMPONENT_Y],
+                yhtbl,
+                skip_blocks,
+                coding_tools.mv_prediction,
+                partition,
+                None,
+                reference_frames,
+                frame.bidirectional,
+                coding_tools.intra_prediction,
+            )?;
+
+            /* The luma blocks of this MCU are known now */
+            let luma_mbs = coding_tools.chroma_mv_from_luma.then_some(&y_mbs[..]);
+            read_interleaved_data_mcu(
+                decoder,
+                u_mbs,
+                &mut frame.residuals.u_dct,
+                padw[c63::COLOR_COMPONENT_U],
+                padh[c63::COLOR_COMPONENT_U],
+                c63::COMPONENT_SIZE_UX,
+                c63::COMPONENT_SIZE_UY,
+                u,
+                v,
+                &mut prev_dc[c63::COLOR_COMPONENT_U],
+                uhtbl,
+                skip_blocks,
+                coding_tools.mv_prediction,
+                partition,
+                luma_mbs,
+                reference_frames,
+                frame.bidirectional,
+                coding_tools.intra_prediction,
+            )?;
+            read_interleaved_data_mcu(
+                decoder,
+                v_mbs,
+                &mut frame.residuals.v_dct,
+                padw[c63::COLOR_COMPONENT_V],
+                padh[c63::COLOR_COMPONENT_V],
+                c63::COMPONENT_SIZE_VX,
+                c63::COMPONENT_SIZE_VY,
+                u,
+                v,
+                &mut prev_dc[c63::COLOR_COMPONENT_V],
+                vhtbl,
+                skip_blocks,
+                coding_tools.mv_prediction,
+                partition,
+                luma_mbs,
+                reference_frames,
+                frame.bidirectional,
+                coding_tools.intra_prediction,
+            )?;
+        }
+    }
+
+    return Ok(());
+}
+
+fn read_sos<R: Read>(reader: &mut BitReader<R>, ctx: &mut DecodeContext) -> Result<(), io::Error> {
+    let size = reader.get_size()?;
+
+    /* Skip the scan header, the layout is fixed */
+    reader.skip_bytes((size as usize).saturating_sub(2))?;
+
+    let frame = match ctx.current_frame.as_mut() {
+        Some(f) => f,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "SOS before SOF")),
+    };
+    let reference_frames =
+        c63::ReferenceFrames::new(&ctx.reference_frames, frame, &ctx.coding_tools)
+            .map_or(0, |references| references.forward.len());
+
+    match ctx.coding_tools.entropy_coder {
+        c63::EntropyCoder::Huffman => {
+            let mut decoder = HuffmanDecoder::new(reader, &ctx.huffman_tables);
+            read_interleaved_data(
+                &mut decoder,
+                &ctx.padw,
+                &ctx.padh,
+                frame,
+                &ctx.coding_tools,
+                reference_frames,
+            )?;
+        }
+        c63::EntropyCoder::Arithmetic => {
+            let mut decoder = ArithDecoder::new(reader)?;
+            read_interleaved_data(
+                &mut decoder,
+                &ctx.padw,
+                &ctx.padh,
+                frame,
+                &ctx.coding_tools,
+                reference_frames,
+            )?;
+        }
+    };
+
+    reader.flush_bits();
+
+    return Ok(());
+}
+
+/// reconstruct the current frame from its residuals and motion vectors
+fn reconstruct_frame(ctx: &mut DecodeContext) {
+    let current_frame = ctx.current_frame.as_mut().unwrap();
+
+    /* Motion Compensation */
+    if !current_frame.keyframe {
+        let references =
+            c63::ReferenceFrames::new(&ctx.reference_frames, current_frame, &ctx.coding_tools)
+                .unwrap();
+        me::c63_motion_compensate(
+            current_frame,
+            &references,
+            ctx.mb_rows,
+            ctx.mb_cols,
+            &ctx.padw,
+            &ctx.padh,
+            ctx.coding_tools.mv_precision,
+            ctx.coding_tools.chroma_mv_from_luma,
+        );
+    }
+
+    /* The blocks of each macroblock are quantized with its delta qp */
+    let delta_qps = current_frame.delta_qps.clone();
+    let quantization =
+        quant::frame_quantization(&ctx.quanttbl, &delta_qps, ctx.padw[c63::COLOR_COMPONENT_Y]);
+
+    /* Intra blocks are predicted from the blocks decoded before them */
+    if ctx.coding_tools.intra_prediction {
+        let c63::Frame {
+            mbs,
+            predicted,
+            residuals,
+            recons,
+            ..
+        } = &mut **current_frame;
+        for (cc, predicted, residuals, recons) in [
+            (
+                c63::COLOR_COMPONENT_Y,
+                &mut predicted.y,
+                &residuals.y_dct,
+                &mut recons.y,
+            ),
+            (
+                c63::COLOR_COMPONENT_U,
+                &mut predicted.u,
+                &residuals.u_dct,
+                &mut recons.u,
+            ),
+            (
+                c63::COLOR_COMPONENT_V,
+                &mut predicted.v,
+                &residuals.v_dct,
+                &mut recons.v,
+            ),
+        ] {
+            intra::decode_plane(
+                &mbs[cc],
+                predicted,
+                residuals,
+                recons,
+                ctx.padw[cc],
+                ctx.padh[cc],
+                &quantization[cc],
+            );
+        }
+        return;
+    }
+
+    /* Decode residuals */
+    quant::dequantize_idct(
+        &current_frame.residuals.y_dct,
+        &current_frame.predicted.y,
+        ctx.padw[c63::COLOR_COMPONENT_Y],
+        ctx.padh[c63::COLOR_COMPONENT_Y],
+        &mut current_frame.recons.y,
+        &quantization[c63::COLOR_COMPONENT_Y],
+    );
+
+    quant::dequantize_idct(
+        &current_frame.residuals.u_dct,
+        &current_frame.predicted.u,
+        ctx.padw[c63::COLOR_COMPONENT_U],
+        ctx.padh[c63::COLOR_COMPONENT_U],
+        &mut current_frame.recons.u,
+        &quantization[c63::COLOR_COMPONENT_U],
+    );
+
+    quant::dequantize_idct(
+        &current_frame.residuals.v_dct,
+        &current_frame.predicted.v,
+        ctx.padw[c63::COLOR_COMPONENT_V],
+        ctx.padh[c63::COLOR_COMPONENT_V],
+        &mut current_frame.recons.v,
+        &quantization[c63::COLOR_COMPONENT_V],
+    );
+}
+
+/// decode 1 frame from `input` into `ctx.current_frame`
+///
+/// Returns `Ok(false)` if `input` is at end of file before the frame starts.
+pub fn decode_frame<R: Read>(ctx: &mut DecodeContext, input: &mut R) -> Result<bool, io::Error> {
+    let mut reader = BitReader::new(input);
+    let mut first = true;
+
+    loop {
+        let byte = match reader.get_byte() {
+            Err(ref e) if first && e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+            Ok(b) => b,
+        };
+        first = false;
+
+        if byte != c63::JPEGMarker::DEF as u8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected marker",
+            ));
+        }
+
+        let marker = reader.get_byte()?;
+        if marker == c63::JPEGMarker::SOI as u8 {
+            /* Start Of Image, frames without APP0 use the default tools */
+            ctx.coding_tools = c63::CodingTools::new();
+        } else if marker == c63::JPEGMarker::APP0 as u8 {
+            read_app0(&mut reader, ctx)?;
+        } else if marker == c63::JPEGMarker::DQT as u8 {
+            read_dqt(&mut reader, ctx)?;
+        } else if marker == c63::JPEGMarker::SOF as u8 {
+            read_sof0(&mut reader, ctx)?;
+        } else if marker == c63::JPEGMarker::DHT as u8 {
+            read_dht(&mut reader, ctx)?;
+        } else if marker == c63::JPEGMarker::SOS as u8 {
+            read_sos(&mut reader, ctx)?;
+        } else if marker == c63::JPEGMarker::EOI as u8 {
+            break;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown marker 0x{:02X}", marker),
+            ));
+        }
+    }
+
+    if ctx.current_frame.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "EOI without a frame",
+        ));
+    }
+
+    reconstruct_frame(ctx);
+    if ctx.coding_tools.deblocking {
+        let frame = ctx.current_frame.as_mut().unwrap();
+        let delta_qps = frame.delta_qps.clone();
+        let quantization =
+            quant::frame_quantization(&ctx.quanttbl, &delta_qps, ctx.padw[c63::COLOR_COMPONENT_Y]);
+        deblock::deblock_frame(frame, &ctx.padw, &ctx.padh, &quantization);
+    }
+    ctx.framenum += 1;
+
+    let frame = ctx.current_frame.as_ref().unwrap();
+    ctx.decoded_images
+        .push((frame.display_number, frame.recons.clone()));
+
+    return Ok(true);
+}
+
+/// the next decoded image in display order, None if it has not been decoded
+/// yet. With `flush` at the end of the stream the remaining images are
+/// returned.
+pub fn next_display_image(ctx: &mut DecodeContext, flush: bool) -> Option<c63::YUV> {
+    let next = ctx
+        .decoded_images
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, (display_number, _))| *display_number)
+        .map(|(i, (display_number, _))| (i, *display_number))?;
+
+    /* A stream that does not start at display number 0 still comes out once
+    more frames are waiting than can be reordered */
+    let waiting = ctx.decoded_images.len() > ctx.coding_tools.b_frames as usize + 1;
+    if next.1 != ctx.output_number && !waiting && !flush {
+        return None;
+    }
+
+    ctx.output_number = next.1 + 1;
+    return Some(ctx.decoded_images.swap_remove(next.0).1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::{c63_write, encode_context, quant, ratecontrol};
+
+    /// the defaults with the tools that skip, share, derive or predict vectors
+    fn inter_tools() -> c63::CodingTools {
+        return c63::CodingTools {
+            skip_blocks: true,
+            macroblock_partitions: true,
+            mv_prediction: true,
+            chroma_mv_from_luma: true,
+            ..c63::CodingTools::new()
+        };
+    }
+
+    #[test]
+    fn entropy_coders_round_trip() {
+        assert_round_trips(SMALL, c63::CodingTools::new(), |_| {}, test_image, 3);
+    }
+
+    #[test]
+    fn per_frame_qp_round_trip() {
+        /* Keyframes and inter frames get different tables */
+        assert_round_trips(
+            SMALL,
+            c63::CodingTools::new(),
+            |ctx| ctx.rate_control = ratecontrol::RateControl::with_quality(25.0),
+            test_image,
+            3,
+        );
+    }
+
+    #[test]
+    fn skip_blocks_round_trip() {
+        let tools = c63::CodingTools {
+            skip_blocks: true,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, test_image, 3);
+        assert_round_trips(LARGE, tools, |_| {}, static_image, 8);
+
+        /* Blocks of a pan are skipped with the vector they predict */
+        for mv_prediction in [false, true] {
+            let tools = c63::CodingTools {
+                mv_prediction,
+                ..tools
+            };
+            assert_round_trips(LARGE, tools, |ctx| ctx.set_qp(8), panning_image, 4);
+        }
+    }
+
+    #[test]
+    fn subpel_round_trip() {
+        for mv_precision in 1..=2 {
+            let tools = c63::CodingTools {
+                mv_precision,
+                ..c63::CodingTools::new()
+            };
+            assert_round_trips(SMALL, tools, |_| {}, test_image, 3);
+        }
+    }
+
+    #[test]
+    fn mv_prediction_round_trip() {
+        let tools = c63::CodingTools {
+            mv_prediction: true,
+            mv_precision: 2,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, test_image, 3);
+    }
+
+    #[test]
+    fn mixed_motion_round_trip() {
+        /* The vectors of the first row and column differ from the ones they
+        are predicted from at the edges */
+        let tools = c63::CodingTools {
+            mv_prediction: true,
+            ..c63::CodingTools::new()
+        };
+        let mut ctx = context(LARGE, tools);
+        for i in 0..2 {
+            let image = mixed_motion_image(&ctx, i);
+            encode_context::encode_image(&mut ctx, image);
+        }
+        let mbs = &ctx.current_frame.as_ref().unwrap().mbs[c63::COLOR_COMPONENT_Y];
+        let cols = ctx.mb_cols as usize;
+        let vector = |i: usize| mbs[i].vector(false);
+        assert!((1..cols).any(|x| vector(x).is_some() && vector(x) != vector(x - 1)));
+        assert!((1..ctx.mb_rows as usize)
+            .any(|y| vector(y * cols).is_some() && vector(y * cols) != vector((y - 1) * cols)));
+
+        for mv_precision in 0..=2 {
+            let tools = c63::CodingTools {
+                mv_precision,
+                ..tools
+            };
+            assert_round_trips(LARGE, tools, |_| {}, mixed_motion_image, 3);
+        }
+        let tools = c63::CodingTools {
+            b_frames: 1,
+            ..inter_tools()
+        };
+        assert_round_trips(LARGE, tools, |_| {}, mixed_motion_image, 4);
+    }
+
+    #[test]
+    fn chroma_mv_from_luma_round_trip() {
+        for mv_precision in 0..=2 {
+            let tools = c63::CodingTools {
+                chroma_mv_from_luma: true,
+                mv_precision,
+                ..c63::CodingTools::new()
+            };
+            assert_round_trips(SMALL, tools, |_| {}, panning_color_image, 3);
+        }
+    }
+
+    #[test]
+    fn macroblock_partitions_round_trip() {
+        let tools = c63::CodingTools {
+            macroblock_partitions: true,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, test_image, 3);
+
+        /* Together with the tools that derive or predict vectors */
+        let tools = c63::CodingTools {
+            mv_precision: 1,
+            ..inter_tools()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, panning_color_image, 3);
+    }
+
+    #[test]
+    fn reference_frames_round_trip() {
+        let tools = c63::CodingTools {
+            reference_frames: 2,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(LARGE, tools, |_| {}, blinking_image, 5);
+
+        /* Together with the tools that share or derive vectors */
+        let tools = c63::CodingTools {
+            reference_frames: 3,
+            ..inter_tools()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, blinking_image, 6);
+
+        /* The buffer starts over at keyframes */
+        let tools = c63::CodingTools {
+            reference_frames: c63::MAX_REFERENCE_FRAMES,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(SMALL, tools, |ctx| ctx.keyframe_interval = 3, test_image, 7);
+    }
+
+    #[test]
+    fn b_frames_round_trip() {
+        /* Most blocks of a fade are the average of the frames on both sides */
+        let tools = c63::CodingTools {
+            b_frames: 1,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(LARGE, tools, |_| {}, fading_image, 7);
+
+        /* Together with the other inter tools, and an input that does not end
+        on a full group of B-frames */
+        let tools = c63::CodingTools {
+            b_frames: 3,
+            reference_frames: 2,
+            mv_precision: 2,
+            ..inter_tools()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, panning_color_image, 6);
+
+        /* B-frames are not predicted across keyframes */
+        let tools = c63::CodingTools {
+            b_frames: c63::MAX_B_FRAMES,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(
+            SMALL,
+            tools,
+            |ctx| ctx.keyframe_interval = 4,
+            test_image,
+            11,
+        );
+    }
+
+    #[test]
+    fn intra_prediction_round_trip() {
+        let tools = c63::CodingTools {
+            intra_prediction: true,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(LARGE, tools, |_| {}, panning_color_image, 1);
+
+        /* Intra blocks of inter frames, next to inter blocks */
+        let tools = c63::CodingTools {
+            intra_prediction: true,
+            mv_prediction: false,
+            b_frames: 1,
+            ..inter_tools()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, blinking_image, 5);
+    }
+
+    #[test]
+    fn deblocking_round_trip() {
+        let tools = c63::CodingTools {
+            deblocking: true,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(SMALL, tools, |ctx| ctx.set_qp(5), panning_color_image, 3);
+
+        /* Together with the tools that change what the edges are */
+        let tools = c63::CodingTools {
+            deblocking: true,
+            intra_prediction: true,
+            mv_prediction: false,
+            chroma_mv_from_luma: false,
+            b_frames: 1,
+            ..inter_tools()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, blinking_image, 5);
+    }
+
+    #[test]
+    fn deblocking_is_no_worse_after_decoding() {
+        /* Coarse quantization leaves steps at the block edges for the filter
+        to smooth, fine quantization leaves hardly any it could blur */
+        for qp in [2, 5, 10, 25, 50, 100] {
+            let psnr_of = |deblocking: bool| {
+                let tools = c63::CodingTools {
+                    deblocking,
+                    ..c63::CodingTools::new()
+                };
+                let mut ctx = context(LARGE, tools);
+                ctx.set_qp(qp);
+                return decoded_psnr(ctx, panning_color_image, 3);
+            };
+            assert!(psnr_of(true) >= psnr_of(false));
+        }
+    }
+
+    #[test]
+    fn rdoq_round_trip() {
+        /* The levels change, the syntax does not */
+        for intra_prediction in [false, true] {
+            let tools = c63::CodingTools {
+                intra_prediction,
+                ..c63::CodingTools::new()
+            };
+            assert_round_trips(SMALL, tools, |ctx| ctx.rdoq = true, panning_color_image, 3);
+        }
+    }
+
+    #[test]
+    fn dead_zone_round_trip() {
+        /* Only the levels change, the decoder is the same */
+        let tools = c63::CodingTools {
+            intra_prediction: true,
+            ..c63::CodingTools::new()
+        };
+        let configure = |ctx: &mut encode_context::EncodeContext| {
+            ctx.rounding = quant::Rounding::DEAD_ZONE;
+            ctx.rdoq = true;
+        };
+        assert_round_trips(SMALL, tools, configure, noisy_image, 3);
+    }
+
+    #[test]
+    fn quant_matrices_round_trip() {
+        let flat = quant::QuantMatrices {
+            luma: [4; 64],
+            chroma: [6; 64],
+        };
+
+        /* The tables scaled from the matrices are in the DQT segment */
+        let mut ctx = context(SMALL, c63::CodingTools::new());
+        ctx.set_quant_matrices(flat.clone());
+        assert!(ctx.quanttbl[c63::COLOR_COMPONENT_Y]
+            .iter()
+            .all(|&q| q == ctx.quanttbl[c63::COLOR_COMPONENT_Y][0]));
+        let image = panning_color_image(&ctx, 0);
+        encode_context::encode_image(&mut ctx, image);
+        let mut stream = Vec::new();
+        c63_write::write_frame(&mut ctx, &mut stream).unwrap();
+        let mut dctx = DecodeContext::new();
+        assert!(decode_frame(&mut dctx, &mut &stream[..]).unwrap());
+        assert_eq!(dctx.quanttbl, ctx.quanttbl);
+
+        let tools = c63::CodingTools {
+            b_frames: 1,
+            ..c63::CodingTools::new()
+        };
+        let configure = |ctx: &mut encode_context::EncodeContext| {
+            ctx.set_quant_matrices(flat.clone());
+        };
+        assert_round_trips(SMALL, tools, configure, panning_color_image, 4);
+    }
+
+    #[test]
+    fn adaptive_quantization_round_trip() {
+        /* The delta qps of the flat and the textured half are decoded along */
+        let tools = c63::CodingTools {
+            adaptive_quantization: true,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(SMALL, tools, |_| {}, half_flat_image, 3);
+
+        /* Together with the tools that skip or predict blocks */
+        let tools = c63::CodingTools {
+            adaptive_quantization: true,
+            intra_prediction: true,
+            skip_blocks: true,
+            deblocking: true,
+            b_frames: 1,
+            ..c63::CodingTools::new()
+        };
+        assert_round_trips(SMALL, tools, |ctx| ctx.rdoq = true, blinking_image, 5);
+    }
+
+    /// the frames of the stream `name` in testdata decoded, one after the
+    /// other like the .yuv files of c63dec
+    fn decode_testdata(name: &str) -> Vec<u8> {
+        let stream = std::fs::read(testdata(name)).unwrap();
+
+        let mut ctx = DecodeContext::new();
+        let mut input = &stream[..];
+        let mut output = Vec::new();
+        while decode_frame(&mut ctx, &mut input).unwrap() {
+            let image = next_display_image(&mut ctx, false).unwrap();
+            let size = (ctx.width * ctx.height) as usize;
+            output.extend_from_slice(&image.y[..size]);
+            output.extend_from_slice(&image.u[..size / 4]);
+            output.extend_from_slice(&image.v[..size / 4]);
+        }
+        assert_eq!(ctx.framenum, 3);
+        return output;
+    }
+
+    #[test]
+    fn decodes_golden_file() {
+        let golden = std::fs::read(testdata("pan_64x48_qp10_decoded.yuv")).unwrap();
+        assert!(
+            decode_testdata("pan_64x48_qp10.c63") == golden,
+            "decoded frames differ from the golden file"
+        );
+    }
+
+    #[test]
+    #[ignore = "needs the C reference goldens, see testdata/README.md"]
+    fn decodes_c_reference_stream() {
+        let golden = std::fs::read(testdata("c_pan_64x48_decoded.yuv")).unwrap();
+        assert!(
+            decode_testdata("c_pan_64x48.c63") == golden,
+            "decoded frames differ from the C c63dec output"
+        );
+    }
+}
@@ -7,8 +7,7 @@ pub fn sad_block_8x8(block1: &[u8], block2: &[u8], stride: i32) -> i32 {
     for v in 0..8 {
         for u in 0..8 {
             result += i32::abs(
-                block2[(v * stride + u) as usize].wrapping_sub(block1[(v * stride + u) as usize])
-                    as i32,
+                block2[(v * stride + u) as usize] as i32 - block1[(v * stride + u) as usize] as i32,
             );
         }
     }
@@ -16,6 +15,26 @@ pub fn sad_block_8x8(block1: &[u8], block2: &[u8], stride: i32) -> i32 {
     return result;
 }
 
+/// sum of the absolute differences between the pixels of a block and its mean
+pub fn deviation_block_8x8(block: &[u8], stride: i32) -> i32 {
+    let mut sum = 0;
+    for v in 0..8 {
+        for u in 0..8 {
+            sum += block[(v * stride + u) as usize] as i32;
+        }
+    }
+    let mean = (sum + 32) / 64;
+
+    let mut result = 0;
+    for v in 0..8 {
+        for u in 0..8 {
+            result += i32::abs(block[(v * stride + u) as usize] as i32 - mean);
+        }
+    }
+
+    return result;
+}
+
 fn transpose_block(in_data: &[f32; 64], out_data: &mut [f32; 64]) {
     for i in 0..8 {
         for j in 0..8 {
@@ -59,15 +78,29 @@ fn scale_block(in_data: &[f32; 64], out_data: &mut [f32; 64]) {
     }
 }
 
-fn quantize_block(in_data: &[f32; 64], out_data: &mut [f32; 64], quant_tbl: &[u8; 64]) {
+/// quantize an unrounded `level`, rounding its magnitude up from the fraction
+/// `rounding` on. 0.5 is rounding to nearest, less widens the zero bin into a
+/// dead zone.
+pub fn quantize_level(level: f64, rounding: f64) -> f64 {
+    return level.signum() * (level.abs() + rounding).floor();
+}
+
+fn quantize_block(
+    in_data: &[f32; 64],
+    out_data: &mut [f32; 64],
+    quant_tbl: &[u8; 64],
+    rounding: f64,
+) {
     for zigzag in 0..64 {
         let u = tables::ZIGZAG_U[zigzag];
         let v = tables::ZIGZAG_V[zigzag];
 
         let dct = in_data[(v * 8 + u) as usize];
 
-        /* Zig-zag and quantize */
-        out_data[zigzag] = ((dct / 4.0f32) / quant_tbl[zigzag] as f32).round();
+        /* Zig-zag and quantize. Done in double precision like the C reference,
+        so coefficients rounding at .5 end up the same */
+        let level = (dct as f64 / 4.0f64) / quant_tbl[zigzag] as f64;
+        out_data[zigzag] = quantize_level(level, rounding) as f32;
     }
 }
 
@@ -76,14 +109,17 @@ fn dequantize_block(in_data: &[f32; 64], out_data: &mut [f32; 64], quant_tbl: &[
         let u = tables::ZIGZAG_U[zigzag];
         let v = tables::ZIGZAG_V[zigzag];
 
-        let dct = in_data[(v * 8 + u) as usize];
+        let dct = in_data[zigzag];
 
         /* Zig-zag and de-quantize */
-        out_data[zigzag] = (dct * quant_tbl[zigzag] as f32).round() / 4.0f32;
+        out_data[(v * 8 + u) as usize] =
+            ((dct as f64 * quant_tbl[zigzag] as f64) / 4.0f64).round() as f32;
     }
 }
 
-pub fn dct_quant_block_8x8(in_data: &[i16; 64], out_data: &mut [i16], quant_tbl: &[u8; 64]) {
+/// DCT of a block of residuals, scaled so that dividing by 4 and the
+/// quantization table gives the coefficients
+pub fn dct_block_8x8(in_data: &[i16; 64], out_data: &mut [f32; 64]) {
     let mut mb: [f32; 64] = [0.0f32; 64];
     let mut mb2: [f32; 64] = [0.0f32; 64];
 
@@ -103,8 +139,20 @@ pub fn dct_quant_block_8x8(in_data: &[i16; 64], out_data: &mut [i16], quant_tbl:
     }
     transpose_block(&mb, &mut mb2);
 
-    scale_block(&mb2, &mut mb);
-    quantize_block(&mb, &mut mb2, quant_tbl);
+    scale_block(&mb2, out_data);
+}
+
+pub fn dct_quant_block_8x8(
+    in_data: &[i16; 64],
+    out_data: &mut [i16],
+    quant_tbl: &[u8; 64],
+    rounding: f64,
+) {
+    let mut mb: [f32; 64] = [0.0f32; 64];
+    let mut mb2: [f32; 64] = [0.0f32; 64];
+
+    dct_block_8x8(in_data, &mut mb);
+    quantize_block(&mb, &mut mb2, quant_tbl, rounding);
 
     for i in 0..64 {
         out_data[i] = mb2[i] as i16;
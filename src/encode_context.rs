@@ -1,9 +1,28 @@
+use crate::aq;
+use crate::arith;
+use crate::bitstream;
 use crate::c63;
+use crate::deblock;
+use crate::intra;
 use crate::me;
+use crate::motion_search;
 use crate::quant;
-use crate::tables;
+use crate::ratecontrol;
+use crate::rdoq;
 use crate::yuv::dump_image;
 
+use std::collections::VecDeque;
+
+/// qp of B-frames relative to the other frames
+const B_FRAME_QP_RATIO: f64 = 1.3;
+
+/// An input image waiting to be coded
+pub struct InputImage {
+    pub image: c63::YUV,
+    pub display_number: u32,
+    pub keyframe: bool,
+}
+
 pub struct EncodeContext {
     pub width: i32,
     pub height: i32,
@@ -24,16 +43,32 @@ pub struct EncodeContext {
     pub qp: u8, // Quality parameter
 
     pub me_search_range: i32,
+    pub me_method: motion_search::SearchMethod,
+    pub rdoq: bool, // rate-distortion optimized quantization
+    pub rounding: quant::Rounding,
+    pub aq_strength: f64, // delta qp per doubling of the activity of a macroblock
 
     pub quanttbl: [[u8; 64]; c63::COLOR_COMPONENTS],
+    quant_matrices: quant::QuantMatrices, // scaled by the qp into quanttbl
 
-    pub reference_frame: Option<Box<c63::Frame>>,
+    pub reference_frames: Vec<Box<c63::Frame>>, // most recent first
     pub current_frame: Option<Box<c63::Frame>>,
 
-    pub framenum: i32,
+    /* Input images in display order, the frame after a run of B-frames is
+    coded before them */
+    pub input_images: VecDeque<InputImage>,
+    pub b_frame_images: VecDeque<InputImage>, // their later reference is coded
+    pub display_number: u32,                  // of the next input image
+    pub end_of_input: bool,
+
+    pub framenum: i32, // frames coded so far
 
     pub keyframe_interval: i32,
     pub frames_since_keyframe: i32,
+
+    pub coding_tools: c63::CodingTools,
+
+    pub rate_control: ratecontrol::RateControl,
 }
 
 impl EncodeContext {
@@ -46,41 +81,20 @@ impl EncodeContext {
         }
 
         // compute padding (or something like that idk...)
-        let ypw: i32 = (width as f32 / 16.0f32).ceil() as i32 * 16;
-        let yph: i32 = (height as f32 / 16.0f32).ceil() as i32 * 16;
-        let upw: i32 = (((width * c63::COMPONENT_SIZE_UX as i32) as f32)
-            / (c63::COMPONENT_SIZE_YX as f32 * 8.0f32))
-            .ceil() as i32
-            * 8;
-        let uph: i32 = (((height * c63::COMPONENT_SIZE_UY as i32) as f32)
-            / (c63::COMPONENT_SIZE_YY as f32 * 8.0f32))
-            .ceil() as i32
-            * 8;
-        let vpw: i32 = (((width * c63::COMPONENT_SIZE_VX as i32) as f32)
-            / (c63::COMPONENT_SIZE_YX as f32 * 8.0f32))
-            .ceil() as i32
-            * 8;
-        let vph: i32 = (((height * c63::COMPONENT_SIZE_VY as i32) as f32)
-            / (c63::COMPONENT_SIZE_YY as f32 * 8.0f32))
-            .ceil() as i32
-            * 8;
+        let (padw, padh) = c63::compute_padding(width, height);
+        let ypw: i32 = padw[c63::COLOR_COMPONENT_Y];
+        let yph: i32 = padh[c63::COLOR_COMPONENT_Y];
+        let upw: i32 = padw[c63::COLOR_COMPONENT_U];
+        let uph: i32 = padh[c63::COLOR_COMPONENT_U];
+        let vpw: i32 = padw[c63::COLOR_COMPONENT_V];
+        let vph: i32 = padh[c63::COLOR_COMPONENT_V];
 
         // Quality parameters
         let qp: u8 = 25;
         let me_search_range: i32 = 16;
         let keyframe_interval: i32 = 100;
 
-        let mut quanttbl: [[u8; 64]; c63::COLOR_COMPONENTS] = [[0; 64]; c63::COLOR_COMPONENTS];
-        for i in 0..64 {
-            quanttbl[c63::COLOR_COMPONENT_Y][i] =
-                tables::YQUANTTBL_DEF[i] / (qp as f64 / 10f64) as u8;
-            quanttbl[c63::COLOR_COMPONENT_U][i] =
-                tables::UVQUANTTBL_DEF[i] / (qp as f64 / 10f64) as u8;
-            quanttbl[c63::COLOR_COMPONENT_V][i] =
-                tables::UVQUANTTBL_DEF[i] / (qp as f64 / 10f64) as u8;
-        }
-
-        return Ok(EncodeContext {
+        let mut ctx = EncodeContext {
             width: width,
             height: height,
             ypw: ypw,
@@ -89,127 +103,506 @@ impl EncodeContext {
             uph: uph,
             vpw: vpw,
             vph: vph,
-            padw: [ypw, upw, vpw],
-            padh: [yph, uph, vph],
+            padw: padw,
+            padh: padh,
             mb_cols: ypw / 8,
             mb_rows: yph / 8,
             // Quality parameters
             qp: qp,
             me_search_range: me_search_range,
+            me_method: motion_search::SearchMethod::Full,
+            rdoq: false,
+            rounding: quant::Rounding::NEAREST,
+            aq_strength: 1.0,
 
-            quanttbl: quanttbl,
+            quanttbl: [[0; 64]; c63::COLOR_COMPONENTS],
+            quant_matrices: quant::QuantMatrices::default(),
 
-            reference_frame: None,
+            reference_frames: Vec::new(),
             current_frame: None,
 
+            input_images: VecDeque::new(),
+            b_frame_images: VecDeque::new(),
+            display_number: 0,
+            end_of_input: false,
+
             framenum: 0,
 
             keyframe_interval: keyframe_interval,
             frames_since_keyframe: 0,
-        });
+            coding_tools: c63::CodingTools::new(),
+            rate_control: ratecontrol::RateControl::new(),
+        };
+        ctx.set_qp(qp);
+
+        return Ok(ctx);
+    }
+
+    /// set the quality parameter and derive the quantization tables from it,
+    /// qp is clamped to 1..=MAX_QP
+    pub fn set_qp(&mut self, qp: u8) {
+        self.qp = qp.clamp(1, c63::MAX_QP);
+        self.set_quant_tables(self.qp);
+    }
+
+    /// use `matrices` instead of the default quantization matrices, the tables
+    /// of each qp are scaled from them
+    pub fn set_quant_matrices(&mut self, matrices: quant::QuantMatrices) {
+        self.quant_matrices = matrices;
+        self.set_quant_tables(self.qp);
+    }
+
+    /// derive the quantization tables from `qp`, without changing the quality
+    /// parameter of the following frames
+    ///
+    /// The tables are the quantization matrices times 10 / qp: qp 10 uses the
+    /// matrices as they are, each step of qp quantizes a little finer. With
+    /// the default matrices every qp from 1 to MAX_QP has its own tables.
+    fn set_quant_tables(&mut self, qp: u8) {
+        let qp = qp.clamp(1, c63::MAX_QP);
+
+        /* qp runs from 1, the coarsest, to MAX_QP, ten times finer than the
+        matrices. Same as the C reference: floating point division, so qps
+        below 10 scale the matrices up instead of dividing by zero, truncated
+        when stored in the table. Entries are clamped to what a table can
+        hold, at least 1 so that the quantizer never divides by zero */
+        let scale = |def: u8| (def as f64 / (qp as f64 / 10f64)).clamp(1.0, 255.0) as u8;
+        for i in 0..64 {
+            self.quanttbl[c63::COLOR_COMPONENT_Y][i] = scale(self.quant_matrices.luma[i]);
+            self.quanttbl[c63::COLOR_COMPONENT_U][i] = scale(self.quant_matrices.chroma[i]);
+            self.quanttbl[c63::COLOR_COMPONENT_V][i] = scale(self.quant_matrices.chroma[i]);
+        }
+    }
+}
+
+/// mean absolute difference between the luma of a frame and its prediction
+fn temporal_complexity(frame: &c63::Frame) -> f64 {
+    let sum: u64 = frame
+        .orig
+        .y
+        .iter()
+        .zip(frame.predicted.y.iter())
+        .map(|(&o, &p)| (o as i32 - p as i32).unsigned_abs() as u64)
+        .sum();
+
+    return sum as f64 / frame.orig.y.len() as f64;
+}
+
+/// mark the blocks of an inter frame that can be skipped, they are predicted
+/// from the most recent reference frame with the vector a skipped block gets
+/// (see me::skip_mv) and have no residual left after quantization
+///
+/// Blocks whose vector is not coded, chroma following luma and the other luma
+/// blocks of a single vector macroblock, only need to have no residual.
+fn mark_skipped_blocks(
+    frame: &mut c63::Frame,
+    padw: &c63::PaddingContainer,
+    padh: &c63::PaddingContainer,
+    coding_tools: &c63::CodingTools,
+) {
+    let residuals = [
+        &frame.residuals.y_dct,
+        &frame.residuals.u_dct,
+        &frame.residuals.v_dct,
+    ];
+    let mcu_sizes = [
+        (c63::COMPONENT_SIZE_YX, c63::COMPONENT_SIZE_YY),
+        (c63::COMPONENT_SIZE_UX, c63::COMPONENT_SIZE_UY),
+        (c63::COMPONENT_SIZE_VX, c63::COMPONENT_SIZE_VY),
+    ];
+    let mb_cols = padw[c63::COLOR_COMPONENT_Y] / 16;
+
+    for c in 0..c63::COLOR_COMPONENTS {
+        let w = padw[c];
+        let (h, v) = (mcu_sizes[c].0 as i32, mcu_sizes[c].1 as i32);
+        for mb_y in 0..padh[c] / 8 {
+            for mb_x in 0..w / 8 {
+                let partition = frame.partitions[(mb_y / v * mb_cols + mb_x / h) as usize];
+                let derived = (coding_tools.chroma_mv_from_luma && c != c63::COLOR_COMPONENT_Y)
+                    || (partition == c63::Partition::Single16x16 && (mb_x % h, mb_y % v) != (0, 0));
+                let skip_mv = if derived {
+                    frame.mbs[c][(mb_y * w / 8 + mb_x) as usize]
+                        .vector(false)
+                        .unwrap_or((0, 0))
+                } else {
+                    me::skip_mv(
+                        &frame.mbs[c],
+                        w / 8,
+                        mb_x,
+                        mb_y,
+                        h,
+                        v,
+                        coding_tools.mv_prediction,
+                    )
+                };
+
+                let mb = &mut frame.mbs[c][(mb_y * w / 8 + mb_x) as usize];
+                /* Residuals are stored linear in memory */
+                let block_index = (mb_y * 8 * w + mb_x * 64) as usize;
+                let block = &residuals[c][block_index..block_index + 64];
+
+                mb.skip = mb.use_mv
+                    && mb.direction == c63::Direction::Forward
+                    && mb.ref_index == 0
+                    && mb.vector(false) == Some(skip_mv)
+                    && block.iter().all(|&coeff| coeff == 0);
+            }
+        }
     }
 }
 
+/// encode `image` as the next frame, for streams without B-frames
 pub fn encode_image(ctx: &mut EncodeContext, image: c63::YUV) {
-    ctx.reference_frame = ctx.current_frame.take();
+    push_image(ctx, image);
+    let coded = encode_next_frame(ctx);
+    assert!(coded, "encode_image can not code B-frames");
+}
 
+/// add `image` to the input, in display order
+pub fn push_image(ctx: &mut EncodeContext, image: c63::YUV) {
     // check if this is a keyframe
     let mut keyframe: bool = false;
-    if ctx.framenum == 0 || ctx.frames_since_keyframe == ctx.keyframe_interval {
+    if ctx.display_number == 0 || ctx.frames_since_keyframe == ctx.keyframe_interval {
         keyframe = true;
         ctx.frames_since_keyframe = 0;
     }
-    ctx.current_frame = Some(Box::new(c63::Frame::new(
-        image,
+
+    ctx.input_images.push_back(InputImage {
+        image: image,
+        display_number: ctx.display_number,
+        keyframe: keyframe,
+    });
+
+    ctx.display_number += 1;
+    ctx.frames_since_keyframe += 1;
+}
+
+/// mark the end of the input, the images still waiting are coded
+pub fn finish_input(ctx: &mut EncodeContext) {
+    ctx.end_of_input = true;
+}
+
+/// encode the next frame in coding order into `ctx.current_frame`
+///
+/// The frames between two frames that are not B-frames are coded after both of
+/// them. Returns false if the input has no frame that can be coded yet.
+pub fn encode_next_frame(ctx: &mut EncodeContext) -> bool {
+    if let Some(input) = ctx.b_frame_images.pop_front() {
+        encode_frame(ctx, input, true);
+        return true;
+    }
+
+    /* The next frame that is not a B-frame is the one after the B-frames, the
+    last one of the input, or the one before a keyframe because B-frames can
+    not be predicted across it */
+    let b_frames = ctx.coding_tools.b_frames as usize;
+    let mut next = None;
+    for (i, input) in ctx.input_images.iter().enumerate() {
+        if input.keyframe {
+            next = Some(i.saturating_sub(1));
+            break;
+        }
+        if i == b_frames || (ctx.end_of_input && i + 1 == ctx.input_images.len()) {
+            next = Some(i);
+            break;
+        }
+    }
+
+    let next = match next {
+        Some(i) => i,
+        None => return false,
+    };
+
+    ctx.b_frame_images = ctx.input_images.drain(..next).collect();
+    let input = ctx.input_images.pop_front().unwrap();
+    encode_frame(ctx, input, false);
+
+    return true;
+}
+
+fn encode_frame(ctx: &mut EncodeContext, input: InputImage, bidirectional: bool) {
+    if let Some(frame) = ctx.current_frame.take() {
+        c63::push_reference_frame(
+            &mut ctx.reference_frames,
+            frame,
+            ctx.coding_tools.dpb_size(),
+        );
+    }
+
+    let mut frame = c63::Frame::new(
+        input.image,
         &ctx.padw,
         &ctx.padh,
         ctx.mb_cols,
         ctx.mb_rows,
-        keyframe,
-    )));
+        input.keyframe,
+    );
+    frame.bidirectional = bidirectional;
+    frame.decode_number = ctx.framenum as u32;
+    frame.display_number = input.display_number;
+    ctx.current_frame = Some(Box::new(frame));
+    let keyframe = input.keyframe;
     let current_frame = ctx.current_frame.as_mut().unwrap();
 
+    let mut complexity = 0.0;
     if !current_frame.keyframe {
-        let reference_frame = ctx.reference_frame.as_ref().unwrap();
+        let references =
+            c63::ReferenceFrames::new(&ctx.reference_frames, current_frame, &ctx.coding_tools)
+                .unwrap();
 
-        /* Motion Estimation */
+        /* Motion Estimation, the qp of the frame depends on its motion so the
+        bits of the vectors are weighed with the tables of the previous frame.
+        C compatible streams have the vectors of the C encoder, the ones with
+        the lowest SAD */
+        let lambda = if ctx.coding_tools.c_compatible() {
+            0.0
+        } else {
+            me::lambda(&ctx.quanttbl[c63::COLOR_COMPONENT_Y])
+        };
         me::c63_motion_estimate(
             current_frame,
-            reference_frame,
+            &references,
             ctx.mb_rows,
             ctx.mb_cols,
             &ctx.padw,
             &ctx.padh,
             ctx.me_search_range,
+            &ctx.coding_tools,
+            lambda,
+            ctx.me_method.searcher(),
         );
 
         /* Motion Compensation */
         me::c63_motion_compensate(
             current_frame,
-            reference_frame,
+            &references,
             ctx.mb_rows,
             ctx.mb_cols,
             &ctx.padw,
             &ctx.padh,
-        )
+            ctx.coding_tools.mv_precision,
+            ctx.coding_tools.chroma_mv_from_luma,
+        );
+
+        complexity = temporal_complexity(current_frame);
     }
 
-    /* DCT and Quantization */
-    quant::dct_quantize(
-        &current_frame.orig.y,
-        &current_frame.predicted.y,
-        ctx.padw[c63::COLOR_COMPONENT_Y],
-        ctx.padh[c63::COLOR_COMPONENT_Y],
-        &mut current_frame.residuals.y_dct,
-        &ctx.quanttbl[c63::COLOR_COMPONENT_Y],
-    );
+    /* The quantization tables of this frame, they are written in its DQT */
+    let qp = ctx.rate_control.frame_qp(keyframe, ctx.qp, complexity);
+    ctx.set_qp(qp);
+    /* Nothing is predicted from B-frames, so their errors do not propagate
+    and they can be quantized more coarsely */
+    if bidirectional {
+        let b_frame_qp = (qp as f64 / B_FRAME_QP_RATIO).round().max(1.0);
+        ctx.set_quant_tables(b_frame_qp as u8);
+    }
+    /* The quantizer weighs the levels with the bits of the entropy coder, the
+    code sizes of the default AC tables, luma uses table 0 and chroma table 1,
+    or the bins of the arithmetic coder */
+    let rdoq_tables = ctx.rdoq.then(bitstream::HuffmanTables::new);
+    let entropy_coder = ctx.coding_tools.entropy_coder;
+    let rounding = ctx.rounding;
+    let quantizer = |cc: usize| quant::Quantizer {
+        rounding,
+        rdoq: rdoq_tables.as_ref().map(|tables| match entropy_coder {
+            c63::EntropyCoder::Huffman => {
+                &tables.ac[(cc != c63::COLOR_COMPONENT_Y) as usize] as &dyn rdoq::AcBits
+            }
+            c63::EntropyCoder::Arithmetic => &arith::AcBins,
+        }),
+    };
+    let current_frame = ctx.current_frame.as_mut().unwrap();
 
-    quant::dct_quantize(
-        &current_frame.orig.u,
-        &current_frame.predicted.u,
-        ctx.padw[c63::COLOR_COMPONENT_U],
-        ctx.padh[c63::COLOR_COMPONENT_U],
-        &mut current_frame.residuals.u_dct,
-        &ctx.quanttbl[c63::COLOR_COMPONENT_U],
-    );
+    /* Flat macroblocks are quantized finer than textured ones */
+    if ctx.coding_tools.adaptive_quantization {
+        aq::set_delta_qps(current_frame, ctx.ypw, ctx.yph, ctx.aq_strength);
+    }
+    let delta_qps = current_frame.delta_qps.clone();
+    let quantization = quant::frame_quantization(&ctx.quanttbl, &delta_qps, ctx.ypw);
 
-    quant::dct_quantize(
-        &current_frame.orig.v,
-        &current_frame.predicted.v,
-        ctx.padw[c63::COLOR_COMPONENT_V],
-        ctx.padh[c63::COLOR_COMPONENT_V],
-        &mut current_frame.residuals.v_dct,
-        &ctx.quanttbl[c63::COLOR_COMPONENT_V],
-    );
+    if ctx.coding_tools.intra_prediction {
+        /* Intra blocks are predicted from the blocks reconstructed before
+        them, so each block is reconstructed right after it is quantized */
+        let c63::Frame {
+            orig,
+            mbs,
+            predicted,
+            residuals,
+            recons,
+            ..
+        } = &mut **current_frame;
+        for (cc, original, predicted, residuals, recons) in [
+            (
+                c63::COLOR_COMPONENT_Y,
+                &orig.y,
+                &mut predicted.y,
+                &mut residuals.y_dct,
+                &mut recons.y,
+            ),
+            (
+                c63::COLOR_COMPONENT_U,
+                &orig.u,
+                &mut predicted.u,
+                &mut residuals.u_dct,
+                &mut recons.u,
+            ),
+            (
+                c63::COLOR_COMPONENT_V,
+                &orig.v,
+                &mut predicted.v,
+                &mut residuals.v_dct,
+                &mut recons.v,
+            ),
+        ] {
+            intra::encode_plane(
+                original,
+                &mut mbs[cc],
+                predicted,
+                residuals,
+                recons,
+                ctx.padw[cc],
+                ctx.padh[cc],
+                &quantization[cc],
+                &quantizer(cc),
+            );
+        }
+    } else {
+        /* DCT and Quantization */
+        quant::dct_quantize(
+            &current_frame.orig.y,
+            &current_frame.predicted.y,
+            ctx.padw[c63::COLOR_COMPONENT_Y],
+            ctx.padh[c63::COLOR_COMPONENT_Y],
+            &mut current_frame.residuals.y_dct,
+            &quantization[c63::COLOR_COMPONENT_Y],
+            &current_frame.mbs[c63::COLOR_COMPONENT_Y],
+            &quantizer(c63::COLOR_COMPONENT_Y),
+        );
 
-    /* Reconstruct frame for inter-prediction */
-    quant::dequantize_idct(
-        &current_frame.residuals.y_dct,
-        &current_frame.predicted.y,
-        ctx.padw[c63::COLOR_COMPONENT_Y],
-        ctx.padh[c63::COLOR_COMPONENT_Y],
-        &mut current_frame.recons.y,
-        &ctx.quanttbl[c63::COLOR_COMPONENT_Y],
-    );
+        quant::dct_quantize(
+            &current_frame.orig.u,
+            &current_frame.predicted.u,
+            ctx.padw[c63::COLOR_COMPONENT_U],
+            ctx.padh[c63::COLOR_COMPONENT_U],
+            &mut current_frame.residuals.u_dct,
+            &quantization[c63::COLOR_COMPONENT_U],
+            &current_frame.mbs[c63::COLOR_COMPONENT_U],
+            &quantizer(c63::COLOR_COMPONENT_U),
+        );
 
-    quant::dequantize_idct(
-        &current_frame.residuals.u_dct,
-        &current_frame.predicted.u,
-        ctx.padw[c63::COLOR_COMPONENT_U],
-        ctx.padh[c63::COLOR_COMPONENT_U],
-        &mut current_frame.recons.u,
-        &ctx.quanttbl[c63::COLOR_COMPONENT_U],
-    );
+        quant::dct_quantize(
+            &current_frame.orig.v,
+            &current_frame.predicted.v,
+            ctx.padw[c63::COLOR_COMPONENT_V],
+            ctx.padh[c63::COLOR_COMPONENT_V],
+            &mut current_frame.residuals.v_dct,
+            &quantization[c63::COLOR_COMPONENT_V],
+            &current_frame.mbs[c63::COLOR_COMPONENT_V],
+            &quantizer(c63::COLOR_COMPONENT_V),
+        );
 
-    quant::dequantize_idct(
-        &current_frame.residuals.u_dct,
-        &current_frame.predicted.u,
-        ctx.padw[c63::COLOR_COMPONENT_U],
-        ctx.padh[c63::COLOR_COMPONENT_U],
-        &mut current_frame.recons.u,
-        &ctx.quanttbl[c63::COLOR_COMPONENT_U],
-    );
+        /* Reconstruct frame for inter-prediction */
+        quant::dequantize_idct(
+            &current_frame.residuals.y_dct,
+            &current_frame.predicted.y,
+            ctx.padw[c63::COLOR_COMPONENT_Y],
+            ctx.padh[c63::COLOR_COMPONENT_Y],
+            &mut current_frame.recons.y,
+            &quantization[c63::COLOR_COMPONENT_Y],
+        );
+
+        quant::dequantize_idct(
+            &current_frame.residuals.u_dct,
+            &current_frame.predicted.u,
+            ctx.padw[c63::COLOR_COMPONENT_U],
+            ctx.padh[c63::COLOR_COMPONENT_U],
+            &mut current_frame.recons.u,
+            &quantization[c63::COLOR_COMPONENT_U],
+        );
+
+        quant::dequantize_idct(
+            &current_frame.residuals.v_dct,
+            &current_frame.predicted.v,
+            ctx.padw[c63::COLOR_COMPONENT_V],
+            ctx.padh[c63::COLOR_COMPONENT_V],
+            &mut current_frame.recons.v,
+            &quantization[c63::COLOR_COMPONENT_V],
+        );
+    }
+
+    if ctx.coding_tools.skip_blocks && !current_frame.keyframe {
+        mark_skipped_blocks(current_frame, &ctx.padw, &ctx.padh, &ctx.coding_tools);
+    }
+
+    /* Filter the block edges before the frame is used as a reference */
+    if ctx.coding_tools.deblocking {
+        deblock::deblock_frame(current_frame, &ctx.padw, &ctx.padh, &quantization);
+    }
 
     ctx.framenum += 1;
-    ctx.frames_since_keyframe += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tables;
+    use crate::testing::*;
+
+    #[test]
+    fn every_qp_has_distinct_tables() {
+        let mut ctx = EncodeContext::new(16, 16).unwrap();
+        let mut previous: Option<[[u8; 64]; c63::COLOR_COMPONENTS]> = None;
+        for qp in 1..=c63::MAX_QP {
+            ctx.set_qp(qp);
+            assert!(ctx.quanttbl.iter().flatten().all(|&q| q >= 1));
+
+            /* Higher qps are finer, never coarser in any entry */
+            if let Some(previous) = previous {
+                assert_ne!(ctx.quanttbl, previous);
+                for (q, p) in ctx.quanttbl.iter().flatten().zip(previous.iter().flatten()) {
+                    assert!(q <= p);
+                }
+            }
+            previous = Some(ctx.quanttbl);
+        }
+
+        /* qp 10 is the matrices as they are */
+        ctx.set_qp(10);
+        assert_eq!(ctx.quanttbl[c63::COLOR_COMPONENT_Y], tables::YQUANTTBL_DEF);
+        assert_eq!(ctx.quanttbl[c63::COLOR_COMPONENT_V], tables::UVQUANTTBL_DEF);
+
+        /* Out of range qps are clamped */
+        ctx.set_qp(0);
+        assert_eq!(ctx.qp, 1);
+        ctx.set_qp(255);
+        assert_eq!(ctx.qp, c63::MAX_QP);
+    }
+
+    #[test]
+    fn skipped_blocks_are_smaller() {
+        let size_of = |size: (i32, i32), tools: c63::CodingTools, qp: u8, image: Image| {
+            let with = c63::CodingTools {
+                skip_blocks: true,
+                ..tools
+            };
+            let skipped = size_and_psnr_of_each_coder(size, with, |ctx| ctx.set_qp(qp), image, 4);
+            let coded = size_and_psnr_of_each_coder(size, tools, |ctx| ctx.set_qp(qp), image, 4);
+            for (skipped, coded) in skipped.iter().zip(coded.iter()) {
+                assert!(skipped.0 < coded.0);
+            }
+        };
+
+        /* Blocks that did not change */
+        size_of(LARGE, c63::CodingTools::new(), 10, static_image);
+
+        /* A pan skips the blocks with the predicted vector, which costs less
+        than coding their vectors and empty residuals */
+        for mv_prediction in [false, true] {
+            let tools = c63::CodingTools {
+                mv_prediction,
+                ..c63::CodingTools::new()
+            };
+            size_of((192, 64), tools, 8, panning_image);
+        }
+    }
 }
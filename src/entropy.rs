@@ -0,0 +1,285 @@
+use crate::bitstream::{self, BitReader, HuffmanTables, SymbolWriter};
+use crate::c63;
+
+use std::io::{self, Read};
+
+/// The binary syntax elements of a block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flag {
+    UseMv,
+    ReuseMv,
+    Skip,
+    Partition,     // one vector for the 16x16 macroblock
+    RefIndex,      // one bin of the truncated unary reference index
+    Bidirectional, // a block of a B-frame predicted from both directions
+    Backward,      // a block of a B-frame predicted from the later frame
+    IntraMode,     // one bin of the truncated unary intra prediction mode
+    DeltaQp,       // the delta qp of a macroblock changes, its sign, then the unary magnitude
+}
+
+/// Entropy coding of the syntax elements of the frame data
+///
+/// The `ctx` arguments are derived from already coded neighbouring blocks, they
+/// are only used by adaptive coders. `cc` is the huffman table id of the color
+/// component (0 for luma, 1 for chroma).
+pub trait EntropyEncoder {
+    fn put_flag(&mut self, flag: Flag, ctx: usize, bit: bool);
+
+    fn put_mv_component(&mut self, ctx: usize, val: i16);
+
+    /// Write the 64 zig-zagged coefficients of a block, the DC is coded as a
+    /// difference to `prev_dc`
+    fn put_coefficients(&mut self, cc: usize, inter: bool, block: &[i16], prev_dc: &mut i16);
+
+    /// Write any data still buffered by the coder
+    fn finish(&mut self);
+}
+
+pub trait EntropyDecoder {
+    fn get_flag(&mut self, flag: Flag, ctx: usize) -> Result<bool, io::Error>;
+
+    fn get_mv_component(&mut self, ctx: usize) -> Result<i16, io::Error>;
+
+    fn get_coefficients(
+        &mut self,
+        cc: usize,
+        inter: bool,
+        block: &mut [i16],
+        prev_dc: &mut i16,
+    ) -> Result<(), io::Error>;
+}
+
+/// write the reference index of a block predicted from one of
+/// `reference_frames` frames, truncated unary with a flag per bin
+///
+/// The context of each flag is its bin, nothing is written with a single
+/// reference frame.
+pub fn put_ref_index<E: EntropyEncoder>(encoder: &mut E, ref_index: u8, reference_frames: usize) {
+    for bin in 0..reference_frames.saturating_sub(1) {
+        let more = ref_index as usize > bin;
+        encoder.put_flag(Flag::RefIndex, bin, more);
+        if !more {
+            break;
+        }
+    }
+}
+
+/// read a reference index written by `put_ref_index`
+pub fn get_ref_index<D: EntropyDecoder>(
+    decoder: &mut D,
+    reference_frames: usize,
+) -> Result<u8, io::Error> {
+    let mut ref_index = 0;
+    while ref_index + 1 < reference_frames && decoder.get_flag(Flag::RefIndex, ref_index)? {
+        ref_index += 1;
+    }
+    return Ok(ref_index as u8);
+}
+
+/// write the intra prediction mode of a block, truncated unary over
+/// `c63::INTRA_MODES` with the bin as context
+pub fn put_intra_mode<E: EntropyEncoder>(encoder: &mut E, mode: c63::IntraMode) {
+    let index = c63::INTRA_MODES.iter().position(|&m| m == mode).unwrap();
+    for bin in 0..c63::INTRA_MODES.len() - 1 {
+        let more = index > bin;
+        encoder.put_flag(Flag::IntraMode, bin, more);
+        if !more {
+            break;
+        }
+    }
+}
+
+/// the number of bins `put_intra_mode` writes for `mode`, its bits with the
+/// Huffman coder
+pub fn intra_mode_bits(mode: c63::IntraMode) -> i32 {
+    let index = c63::INTRA_MODES.iter().position(|&m| m == mode).unwrap();
+    return (index + 1).min(c63::INTRA_MODES.len() - 1) as i32;
+}
+
+/// read an intra prediction mode written by `put_intra_mode`
+pub fn get_intra_mode<D: EntropyDecoder>(decoder: &mut D) -> Result<c63::IntraMode, io::Error> {
+    let mut index = 0;
+    while index + 1 < c63::INTRA_MODES.len() && decoder.get_flag(Flag::IntraMode, index)? {
+        index += 1;
+    }
+    return Ok(c63::INTRA_MODES[index]);
+}
+
+/// write the change of the delta qp from the previous macroblock, a flag if it
+/// changes, the sign and the magnitude in truncated unary
+pub fn put_delta_qp_change<E: EntropyEncoder>(encoder: &mut E, change: i8) {
+    encoder.put_flag(Flag::DeltaQp, 0, change != 0);
+    if change == 0 {
+        return;
+    }
+    encoder.put_flag(Flag::DeltaQp, 1, change < 0);
+    for bin in 1..2 * c63::MAX_DELTA_QP {
+        let more = change.abs() > bin;
+        encoder.put_flag(Flag::DeltaQp, 2, more);
+        if !more {
+            break;
+        }
+    }
+}
+
+/// read a change of the delta qp written by `put_delta_qp_change`
+pub fn get_delta_qp_change<D: EntropyDecoder>(decoder: &mut D) -> Result<i8, io::Error> {
+    if !decoder.get_flag(Flag::DeltaQp, 0)? {
+        return Ok(0);
+    }
+    let negative = decoder.get_flag(Flag::DeltaQp, 1)?;
+    let mut magnitude = 1;
+    while magnitude < 2 * c63::MAX_DELTA_QP && decoder.get_flag(Flag::DeltaQp, 2)? {
+        magnitude += 1;
+    }
+    return Ok(if negative { -magnitude } else { magnitude });
+}
+
+/// left and top neighbours of the block at `uoffset`, `voffset` in a plane of
+/// `width` pixels, they are always coded before the block itself
+pub fn neighbours(
+    mbs: &[c63::MacroBlock],
+    width: i32,
+    uoffset: i32,
+    voffset: i32,
+) -> (Option<&c63::MacroBlock>, Option<&c63::MacroBlock>) {
+    let mb_index = (voffset / 8 * width / 8 + uoffset / 8) as usize;
+    let left = if uoffset > 0 {
+        Some(&mbs[mb_index - 1])
+    } else {
+        None
+    };
+    let top = if voffset > 0 {
+        Some(&mbs[mb_index - (width / 8) as usize])
+    } else {
+        None
+    };
+    return (left, top);
+}
+
+/// context of the use_mv flag, the number of neighbours using inter prediction
+pub fn use_mv_context(left: Option<&c63::MacroBlock>, top: Option<&c63::MacroBlock>) -> usize {
+    return [left, top]
+        .iter()
+        .filter(|mb| mb.map_or(false, |mb| mb.use_mv))
+        .count();
+}
+
+/// context of the skip flag, the number of skipped neighbours
+pub fn skip_context(left: Option<&c63::MacroBlock>, top: Option<&c63::MacroBlock>) -> usize {
+    return [left, top]
+        .iter()
+        .filter(|mb| mb.map_or(false, |mb| mb.skip))
+        .count();
+}
+
+/// context of the partition flag of the 16x16 macroblock at `mb_x`, `mb_y`,
+/// the number of left and top neighbours with a single vector
+pub fn partition_context(partitions: &[c63::Partition], cols: i32, mb_x: i32, mb_y: i32) -> usize {
+    let single =
+        |x: i32, y: i32| partitions[(y * cols + x) as usize] == c63::Partition::Single16x16;
+    return (mb_x > 0 && single(mb_x - 1, mb_y)) as usize
+        + (mb_y > 0 && single(mb_x, mb_y - 1)) as usize;
+}
+
+/// contexts of the x and y motion vector components, from the amount of
+/// motion of the neighbours along each axis
+pub fn mv_contexts(
+    left: Option<&c63::MacroBlock>,
+    top: Option<&c63::MacroBlock>,
+) -> (usize, usize) {
+    let mut motion = [0i32; 2];
+    for mb in [left, top].iter().flatten() {
+        if mb.use_mv {
+            motion[0] += (mb.mv_x as i32).abs();
+            motion[1] += (mb.mv_y as i32).abs();
+        }
+    }
+
+    let class = |m: i32| match m {
+        0..=1 => 0,
+        2..=7 => 1,
+        _ => 2,
+    };
+    return (class(motion[0]), 3 + class(motion[1]));
+}
+
+/// Huffman coding as done by the C reference, flags are written as single bits
+pub struct HuffmanEncoder<'a, S: SymbolWriter> {
+    writer: &'a mut S,
+    huffman_tables: &'a HuffmanTables,
+}
+
+impl<'a, S: SymbolWriter> HuffmanEncoder<'a, S> {
+    pub fn new(writer: &'a mut S, huffman_tables: &'a HuffmanTables) -> HuffmanEncoder<'a, S> {
+        return HuffmanEncoder {
+            writer: writer,
+            huffman_tables: huffman_tables,
+        };
+    }
+}
+
+impl<'a, S: SymbolWriter> EntropyEncoder for HuffmanEncoder<'a, S> {
+    fn put_flag(&mut self, _flag: Flag, _ctx: usize, bit: bool) {
+        self.writer.put_bits(bit as u16, 1);
+    }
+
+    fn put_mv_component(&mut self, _ctx: usize, val: i16) {
+        bitstream::write_value(self.writer, &self.huffman_tables.mv, val);
+    }
+
+    fn put_coefficients(&mut self, cc: usize, _inter: bool, block: &[i16], prev_dc: &mut i16) {
+        bitstream::write_coefficients(
+            self.writer,
+            &self.huffman_tables.dc[cc],
+            &self.huffman_tables.ac[cc],
+            block,
+            prev_dc,
+        );
+    }
+
+    fn finish(&mut self) {}
+}
+
+pub struct HuffmanDecoder<'a, 'b, R: Read> {
+    reader: &'a mut BitReader<'b, R>,
+    huffman_tables: &'a HuffmanTables,
+}
+
+impl<'a, 'b, R: Read> HuffmanDecoder<'a, 'b, R> {
+    pub fn new(
+        reader: &'a mut BitReader<'b, R>,
+        huffman_tables: &'a HuffmanTables,
+    ) -> HuffmanDecoder<'a, 'b, R> {
+        return HuffmanDecoder {
+            reader: reader,
+            huffman_tables: huffman_tables,
+        };
+    }
+}
+
+impl<'a, 'b, R: Read> EntropyDecoder for HuffmanDecoder<'a, 'b, R> {
+    fn get_flag(&mut self, _flag: Flag, _ctx: usize) -> Result<bool, io::Error> {
+        return Ok(self.reader.get_bits(1)? != 0);
+    }
+
+    fn get_mv_component(&mut self, _ctx: usize) -> Result<i16, io::Error> {
+        return bitstream::read_value(self.reader, &self.huffman_tables.mv);
+    }
+
+    fn get_coefficients(
+        &mut self,
+        cc: usize,
+        _inter: bool,
+        block: &mut [i16],
+        prev_dc: &mut i16,
+    ) -> Result<(), io::Error> {
+        return bitstream::read_coefficients(
+            self.reader,
+            &self.huffman_tables.dc[cc],
+            &self.huffman_tables.ac[cc],
+            block,
+            prev_dc,
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use crate::c63;
+use crate::entropy;
+use crate::me;
+use crate::quant;
+
+/// the reconstructed row above and column to the left of the block at `x`,
+/// `y`, None where the block is at the edge of the plane
+fn neighbours(recons: &[u8], w: i32, x: i32, y: i32) -> (Option<[u8; 8]>, Option<[u8; 8]>) {
+    let mut top = None;
+    if y > 0 {
+        let start = ((y - 1) * w + x) as usize;
+        top = Some(recons[start..start + 8].try_into().unwrap());
+    }
+
+    let mut left = None;
+    if x > 0 {
+        let mut column = [0u8; 8];
+        for (i, p) in column.iter_mut().enumerate() {
+            *p = recons[((y + i as i32) * w + x - 1) as usize];
+        }
+        left = Some(column);
+    }
+
+    return (top, left);
+}
+
+/// predict the 8x8 block at `x`, `y` of a plane `w` pixels wide with `mode`
+/// from the pixels already reconstructed in `recons`
+///
+/// Missing neighbours at the edges of the plane are taken as mid gray, the DC
+/// mode uses only the neighbours that exist.
+pub fn predict_block_8x8(
+    recons: &[u8],
+    w: i32,
+    x: i32,
+    y: i32,
+    mode: c63::IntraMode,
+    out: &mut [u8; 64],
+) {
+    let (top, left) = neighbours(recons, w, x, y);
+
+    if mode == c63::IntraMode::Dc {
+        let sum = |side: Option<[u8; 8]>| side.map(|p| p.iter().map(|&v| v as u32).sum::<u32>());
+        let dc = match (sum(top), sum(left)) {
+            (Some(t), Some(l)) => (t + l + 8) >> 4,
+            (Some(s), None) | (None, Some(s)) => (s + 4) >> 3,
+            (None, None) => 128,
+        };
+        out.fill(dc as u8);
+        return;
+    }
+
+    let top = top.unwrap_or([128; 8]);
+    let left = left.unwrap_or([128; 8]);
+
+    for i in 0..8 {
+        for j in 0..8 {
+            out[i * 8 + j] = match mode {
+                c63::IntraMode::Vertical => top[j],
+                c63::IntraMode::Horizontal => left[i],
+                /* Between the left column and the last pixel above on each
+                line, and between the top row and the last pixel to the left
+                on each column */
+                _ => {
+                    let horizontal = (7 - j) * left[i] as usize + (j + 1) * top[7] as usize;
+                    let vertical = (7 - i) * top[j] as usize + (i + 1) * left[7] as usize;
+                    ((horizontal + vertical + 8) >> 4) as u8
+                }
+            };
+        }
+    }
+}
+
+/// SAD between the block at the start of `original` and `predicted`
+fn sad_predicted_8x8(original: &[u8], stride: i32, predicted: &[u8; 64]) -> i32 {
+    let mut result = 0;
+    for v in 0..8 {
+        for u in 0..8 {
+            result += i32::abs(
+                original[(v * stride + u) as usize] as i32 - predicted[(v * 8 + u) as usize] as i32,
+            );
+        }
+    }
+    return result;
+}
+
+/// the mode of the block at `x`, `y` of `original` with the lowest SAD plus
+/// `lambda` times the bits of its code, ties go to the mode with the shorter
+/// code
+pub fn choose_mode(
+    original: &[u8],
+    recons: &[u8],
+    w: i32,
+    x: i32,
+    y: i32,
+    lambda: f64,
+) -> c63::IntraMode {
+    let block = &original[(y * w + x) as usize..];
+    let mut predicted = [0u8; 64];
+
+    let mut best = (c63::IntraMode::Dc, i32::MAX);
+    for mode in c63::INTRA_MODES {
+        predict_block_8x8(recons, w, x, y, mode, &mut predicted);
+        let sad = sad_predicted_8x8(block, w, &predicted);
+        let cost = sad + (lambda * entropy::intra_mode_bits(mode) as f64).round() as i32;
+        if cost < best.1 {
+            best = (mode, cost);
+        }
+    }
+
+    return best.0;
+}
+
+/// write the prediction of the intra block at `x`, `y` into `predicted`
+fn predict_intra_block(
+    mb: &c63::MacroBlock,
+    recons: &[u8],
+    w: i32,
+    x: i32,
+    y: i32,
+    predicted: &mut [u8],
+) {
+    let mut block = [0u8; 64];
+    predict_block_8x8(recons, w, x, y, mb.intra_mode, &mut block);
+    for i in 0..8 {
+        let start = ((y + i) * w + x) as usize;
+        predicted[start..start + 8].copy_from_slice(&block[(i * 8) as usize..(i * 8 + 8) as usize]);
+    }
+}
+
+/// transform, quantize and reconstruct a plane block by block in raster order,
+/// choosing the mode of each intra block and predicting it from the blocks
+/// reconstructed before it
+///
+/// `predicted` already holds the prediction of the inter blocks.
+pub fn encode_plane(
+    original: &[u8],
+    mbs: &mut [c63::MacroBlock],
+    predicted: &mut [u8],
+    residuals: &mut [i16],
+    recons: &mut [u8],
+    w: i32,
+    h: i32,
+    quantization: &quant::PlaneQuantization,
+    quantizer: &quant::Quantizer,
+) {
+    for y in (0..h).step_by(8) {
+        for x in (0..w).step_by(8) {
+            let mb = &mut mbs[(y / 8 * w / 8 + x / 8) as usize];
+            if !mb.use_mv {
+                let lambda = me::lambda(quantization.block(x as usize, y as usize));
+                mb.intra_mode = choose_mode(original, recons, w, x, y, lambda);
+                predict_intra_block(mb, recons, w, x, y, predicted);
+            }
+
+            /* Residuals are stored linear in memory */
+            let pixel = (y * w + x) as usize;
+            let block = (y * w + x * 8) as usize;
+            quant::dct_quantize_block(
+                &original[pixel..],
+                &predicted[pixel..],
+                w as usize,
+                &mut residuals[block..block + 64],
+                quantization.block(x as usize, y as usize),
+                quantizer,
+                !mb.use_mv,
+            );
+            quant::dequantize_idct_block(
+                &residuals[block..block + 64],
+                &predicted[pixel..],
+                w as usize,
+                &mut recons[pixel..],
+                quantization.block(x as usize, y as usize),
+            );
+        }
+    }
+}
+
+/// reconstruct a plane coded by `encode_plane`
+pub fn decode_plane(
+    mbs: &[c63::MacroBlock],
+    predicted: &mut [u8],
+    residuals: &[i16],
+    recons: &mut [u8],
+    w: i32,
+    h: i32,
+    quantization: &quant::PlaneQuantization,
+) {
+    for y in (0..h).step_by(8) {
+        for x in (0..w).step_by(8) {
+            let mb = &mbs[(y / 8 * w / 8 + x / 8) as usize];
+            if !mb.use_mv {
+                predict_intra_block(mb, recons, w, x, y, predicted);
+            }
+
+            let pixel = (y * w + x) as usize;
+            let block = (y * w + x * 8) as usize;
+            quant::dequantize_idct_block(
+                &residuals[block..block + 64],
+                &predicted[pixel..],
+                w as usize,
+                &mut recons[pixel..],
+                quantization.block(x as usize, y as usize),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const W: i32 = 16;
+
+    #[test]
+    fn modes_extend_the_neighbours() {
+        /* The block at 8, 8 below a gradient and right of a ramp */
+        let mut recons = vec![0u8; (W * W) as usize];
+        for x in 0..W {
+            recons[(7 * W + x) as usize] = (x * 10) as u8;
+        }
+        for y in 0..W {
+            recons[(y * W + 7) as usize] = (y * 10) as u8;
+        }
+
+        let mut out = [0u8; 64];
+        predict_block_8x8(&recons, W, 8, 8, c63::IntraMode::Vertical, &mut out);
+        assert_eq!(out[5 * 8 + 3], 110);
+        predict_block_8x8(&recons, W, 8, 8, c63::IntraMode::Horizontal, &mut out);
+        assert_eq!(out[5 * 8 + 3], 130);
+        predict_block_8x8(&recons, W, 8, 8, c63::IntraMode::Dc, &mut out);
+        assert!(out.iter().all(|&p| p == 115));
+        /* The gradient continues down and right */
+        predict_block_8x8(&recons, W, 8, 8, c63::IntraMode::Planar, &mut out);
+        assert!(out[0] < out[9] && out[9] < out[63]);
+
+        /* Only the neighbours that exist are used */
+        predict_block_8x8(&recons, W, 0, 8, c63::IntraMode::Dc, &mut out);
+        assert!(out.iter().all(|&p| p == 35));
+        predict_block_8x8(&recons, W, 0, 0, c63::IntraMode::Dc, &mut out);
+        assert!(out.iter().all(|&p| p == 128));
+    }
+
+    #[test]
+    fn best_mode_is_chosen() {
+        /* Vertical stripes continue from the row above */
+        let mut original = vec![0u8; (W * W) as usize];
+        for y in 0..W {
+            for x in 0..W {
+                original[(y * W + x) as usize] = if x % 2 == 0 { 40 } else { 200 };
+            }
+        }
+        assert_eq!(
+            choose_mode(&original, &original, W, 8, 8, 0.0),
+            c63::IntraMode::Vertical
+        );
+
+        /* Without neighbours every mode is the same, DC is cheapest */
+        assert_eq!(
+            choose_mode(&original, &original, W, 0, 0, 0.0),
+            c63::IntraMode::Dc
+        );
+    }
+
+    #[test]
+    fn longer_codes_need_a_lower_sad() {
+        /* Vertical predicts the one brighter column, a SAD of 8 better than
+        DC for a code one bit longer */
+        let mut recons = vec![100u8; (W * W) as usize];
+        recons[(7 * W + 11) as usize] = 101;
+        let mut original = vec![100u8; (W * W) as usize];
+        for y in 8..W {
+            original[(y * W + 11) as usize] = 101;
+        }
+        assert_eq!(
+            choose_mode(&original, &recons, W, 8, 8, 0.0),
+            c63::IntraMode::Vertical
+        );
+        assert_eq!(
+            choose_mode(&original, &recons, W, 8, 8, 10.0),
+            c63::IntraMode::Dc
+        );
+    }
+
+    #[test]
+    fn smooth_keyframes_are_smaller() {
+        let tools = c63::CodingTools {
+            intra_prediction: true,
+            ..c63::CodingTools::new()
+        };
+        let predicted = size_and_psnr_of_each_coder(LARGE, tools, |_| {}, panning_color_image, 1);
+        let coded = size_and_psnr_of_each_coder(
+            LARGE,
+            c63::CodingTools::new(),
+            |_| {},
+            panning_color_image,
+            1,
+        );
+        for (predicted, coded) in predicted.iter().zip(coded.iter()) {
+            assert!(predicted.0 < coded.0);
+        }
+    }
+}
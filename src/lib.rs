@@ -1,7 +1,20 @@
+pub mod aq;
+pub mod arith;
+pub mod bitstream;
 pub mod c63;
+pub mod c63_write;
+pub mod deblock;
+pub mod decode;
 pub mod dsp;
 pub mod encode_context;
+pub mod entropy;
+pub mod intra;
 pub mod me;
+pub mod motion_search;
 pub mod quant;
+pub mod ratecontrol;
+pub mod rdoq;
 pub mod tables;
+#[cfg(test)]
+mod testing;
 pub mod yuv;
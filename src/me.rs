@@ -1,22 +1,473 @@
 use crate::c63;
 use crate::dsp;
 use crate::encode_context;
+use crate::motion_search::{Candidate, MotionSearch, MvCost, SearchWindow};
+use crate::rdoq;
 
-fn me_block_8x8(
-    macroblocks: &mut c63::MacroBlockContainer, // current frame
+/* Intra blocks have no prediction at all, so their DC is expensive to code. As
+in the H.263 test model, inter coding is kept unless intra is better by this
+much. Scaled from 500 for 16x16 blocks */
+const INTRA_SAD_BIAS: i32 = 128;
+
+/* Blocks predicted from an older reference frame code a longer index, so they
+need a lower SAD by this much per step back */
+const REF_INDEX_SAD_BIAS: i32 = 16;
+
+/// the lambda of the motion search with the quantization `quant_tbl`, the SAD
+/// one bit of a vector is worth
+///
+/// As in the H.264 reference software it is the square root of the lambda of
+/// the quantizer, which weighs squared errors.
+pub fn lambda(quant_tbl: &[u8; 64]) -> f64 {
+    return rdoq::lambda(quant_tbl).sqrt();
+}
+
+/// predict the block at `x`, `y` from `reference` displaced by `mv_x`, `mv_y`
+/// in units of 1/2^`mv_precision` pixels
+///
+/// Sub-pixel positions are interpolated bilinearly from the 4 surrounding
+/// pixels in eighth pixel steps, samples outside the plane are clamped to its
+/// edge. Eighths are only used by chroma vectors derived from quarter pixel
+/// luma vectors.
+fn predict_block_8x8(
+    reference: &[u8],
+    w: i32,
+    h: i32,
+    x: i32,
+    y: i32,
+    mv_x: i32,
+    mv_y: i32,
+    mv_precision: u8,
+    out: &mut [u8; 64],
+) {
+    let scale = 1 << mv_precision;
+    let ix = x + mv_x.div_euclid(scale);
+    let iy = y + mv_y.div_euclid(scale);
+    let fx = mv_x.rem_euclid(scale) << (3 - mv_precision);
+    let fy = mv_y.rem_euclid(scale) << (3 - mv_precision);
+
+    let sample =
+        |sx: i32, sy: i32| reference[(sy.clamp(0, h - 1) * w + sx.clamp(0, w - 1)) as usize] as i32;
+
+    for v in 0..8 {
+        for u in 0..8 {
+            let px = ix + u;
+            let py = iy + v;
+
+            out[(v * 8 + u) as usize] = if fx == 0 && fy == 0 {
+                sample(px, py) as u8
+            } else {
+                (((8 - fx) * (8 - fy) * sample(px, py)
+                    + fx * (8 - fy) * sample(px + 1, py)
+                    + (8 - fx) * fy * sample(px, py + 1)
+                    + fx * fy * sample(px + 1, py + 1)
+                    + 32)
+                    >> 6) as u8
+            };
+        }
+    }
+}
+
+/// median prediction of the forward or `backward` motion vector of the block at
+/// `mb_x`, `mb_y` in a plane `cols` blocks wide, from its left, top and
+/// top-right neighbours
+///
+/// Blocks are coded `h` x `v` at a time, so the top-right neighbour is only
+/// known if it is in an earlier row of MCUs or in the same MCU, otherwise the
+/// top-left neighbour is used instead like in H.264. Intra and missing
+/// neighbours and those without a vector in that direction count as the zero
+/// vector, in the first row the left vector is used alone.
+pub fn predict_mv(
+    mbs: &[c63::MacroBlock],
+    cols: i32,
+    mb_x: i32,
+    mb_y: i32,
+    h: i32,
+    v: i32,
+    backward: bool,
+) -> (i32, i32) {
+    let vector = |x: i32, y: i32| {
+        mbs[(y * cols + x) as usize]
+            .vector(backward)
+            .unwrap_or((0, 0))
+    };
+
+    let left = if mb_x > 0 {
+        vector(mb_x - 1, mb_y)
+    } else {
+        (0, 0)
+    };
+    if mb_y == 0 {
+        return left;
+    }
+
+    let top = vector(mb_x, mb_y - 1);
+    let top_right_coded =
+        mb_x + 1 < cols && ((mb_y - 1) / v < mb_y / v || (mb_x + 1) / h == mb_x / h);
+    let diagonal = if top_right_coded {
+        vector(mb_x + 1, mb_y - 1)
+    } else if mb_x > 0 {
+        vector(mb_x - 1, mb_y - 1)
+    } else {
+        (0, 0)
+    };
+
+    let median = |a: i32, b: i32, c: i32| a.max(b).min(a.min(b).max(c));
+    return (
+        median(left.0, top.0, diagonal.0),
+        median(left.1, top.1, diagonal.1),
+    );
+}
+
+/// the forward motion vector a skipped block at `mb_x`, `mb_y` has, the median
+/// prediction or without motion vector prediction the vector of the block to
+/// the left, the one its reuse flag would copy
+pub fn skip_mv(
+    mbs: &[c63::MacroBlock],
+    cols: i32,
+    mb_x: i32,
+    mb_y: i32,
+    h: i32,
+    v: i32,
+    mv_prediction: bool,
+) -> (i32, i32) {
+    if mv_prediction {
+        return predict_mv(mbs, cols, mb_x, mb_y, h, v, false);
+    }
+    if mb_x == 0 {
+        return (0, 0);
+    }
+    return mbs[(mb_y * cols + mb_x - 1) as usize]
+        .vector(false)
+        .unwrap_or((0, 0));
+}
+
+fn sad_predicted_8x8(original: &[u8], stride: i32, predicted: &[u8; 64]) -> i32 {
+    let mut result = 0;
+    for v in 0..8 {
+        for u in 0..8 {
+            result += i32::abs(
+                original[(v * stride + u) as usize] as i32 - predicted[(v * 8 + u) as usize] as i32,
+            );
+        }
+    }
+    return result;
+}
+
+/// motion of the chroma block at `mb_x`, `mb_y` in a plane `cols` blocks wide,
+/// derived from the 2x2 co-located luma blocks of a 4:2:0 frame
+///
+/// The reference frames are those of the first luma block using inter
+/// prediction, the vectors are the rounded means of the luma blocks predicted
+/// from the same frames. The block is intra only if all of them are. Chroma has half the
+/// resolution, so the vector in luma units is the chroma vector with one more
+/// bit of precision.
+pub fn chroma_mv_from_luma(
+    luma_mbs: &[c63::MacroBlock],
+    cols: i32,
     mb_x: i32,
     mb_y: i32,
+) -> c63::MacroBlock {
+    let luma_cols = cols * 2;
+
+    let mut references = None;
+    let mut n = 0;
+    let mut sum = [0; 4];
+    for y in mb_y * 2..mb_y * 2 + 2 {
+        for x in mb_x * 2..mb_x * 2 + 2 {
+            let mb = &luma_mbs[(y * luma_cols + x) as usize];
+            let block_references = (mb.direction, mb.ref_index);
+            if mb.use_mv && *references.get_or_insert(block_references) == block_references {
+                n += 1;
+                sum[0] += mb.mv_x as i32;
+                sum[1] += mb.mv_y as i32;
+                sum[2] += mb.backward_mv_x as i32;
+                sum[3] += mb.backward_mv_y as i32;
+            }
+        }
+    }
+
+    let (direction, ref_index) = match references {
+        Some(references) => references,
+        None => return c63::MacroBlock::default(),
+    };
+
+    let mean = |sum: i32| (2 * sum + n).div_euclid(2 * n) as i8;
+    return c63::MacroBlock {
+        use_mv: true,
+        mv_x: mean(sum[0]),
+        mv_y: mean(sum[1]),
+        ref_index: ref_index,
+        direction: direction,
+        backward_mv_x: mean(sum[2]),
+        backward_mv_y: mean(sum[3]),
+        ..Default::default()
+    };
+}
+
+/// blocks of `color_component` in each direction of an MCU
+fn mcu_size(color_component: c63::ColorComponent) -> (i32, i32) {
+    let (h, v) = match color_component {
+        c63::COLOR_COMPONENT_Y => (c63::COMPONENT_SIZE_YX, c63::COMPONENT_SIZE_YY),
+        c63::COLOR_COMPONENT_U => (c63::COMPONENT_SIZE_UX, c63::COMPONENT_SIZE_UY),
+        _ => (c63::COMPONENT_SIZE_VX, c63::COMPONENT_SIZE_VY),
+    };
+    return (h as i32, v as i32);
+}
+
+/// plane `color_component` of `image`
+fn plane(image: &c63::YUV, color_component: c63::ColorComponent) -> &[u8] {
+    return match color_component {
+        c63::COLOR_COMPONENT_Y => &image.y,
+        c63::COLOR_COMPONENT_U => &image.u,
+        _ => &image.v,
+    };
+}
+
+/// predict the block at `x`, `y` of plane `color_component` with the motion of
+/// `mb`
+fn predict_mb_8x8(
+    references: &c63::ReferenceFrames,
+    mb: &c63::MacroBlock,
+    color_component: c63::ColorComponent,
+    w: i32,
+    h: i32,
+    x: i32,
+    y: i32,
+    mv_precision: u8,
+    out: &mut [u8; 64],
+) {
+    let predict = |backward: bool, out: &mut [u8; 64]| {
+        let (reference, (mv_x, mv_y)) = if backward {
+            (references.backward.unwrap(), mb.vector(true).unwrap())
+        } else {
+            (
+                references.forward[mb.ref_index as usize],
+                mb.vector(false).unwrap(),
+            )
+        };
+        predict_block_8x8(
+            plane(&reference.recons, color_component),
+            w,
+            h,
+            x,
+            y,
+            mv_x,
+            mv_y,
+            mv_precision,
+            out,
+        );
+    };
+
+    match mb.direction {
+        c63::Direction::Forward => predict(false, out),
+        c63::Direction::Backward => predict(true, out),
+        c63::Direction::Bidirectional => {
+            let mut backward = [0u8; 64];
+            predict(false, out);
+            predict(true, &mut backward);
+            for (p, b) in out.iter_mut().zip(backward.iter()) {
+                *p = ((*p as u16 + *b as u16 + 1) >> 1) as u8;
+            }
+        }
+    }
+}
+
+/// SAD of the `size` x `size` block at `x`, `y` of plane `color_component`
+/// predicted with the motion of `mb`
+fn sad_predicted(
+    original: &[u8],
+    references: &c63::ReferenceFrames,
+    mb: &c63::MacroBlock,
+    color_component: c63::ColorComponent,
+    w: i32,
+    h: i32,
+    x: i32,
+    y: i32,
+    size: i32,
+    mv_precision: u8,
+) -> i32 {
+    let mut predicted = [0u8; 64];
+    let mut result = 0;
+    for v in (y..y + size).step_by(8) {
+        for u in (x..x + size).step_by(8) {
+            predict_mb_8x8(
+                references,
+                mb,
+                color_component,
+                w,
+                h,
+                u,
+                v,
+                mv_precision,
+                &mut predicted,
+            );
+            result += sad_predicted_8x8(&original[(v * w + u) as usize..], w, &predicted);
+        }
+    }
+    return result;
+}
+
+/// refine the integer vector `best` of the `size` x `size` block at `x`, `y` in
+/// half and then quarter pixel steps, down to the precision of `mv_cost`
+///
+/// Returns the vector in units of the precision, its SAD and its SAD plus rate.
+fn refine_subpel(
     original: &[u8],
     reference: &[u8],
+    w: i32,
+    h: i32,
+    x: i32,
+    y: i32,
+    size: i32,
+    best: Candidate,
+    mv_cost: &MvCost,
+) -> ((i32, i32), i32, i32) {
+    let mv_precision = mv_cost.mv_precision;
+    let mut predicted = [0u8; 64];
+    let mut best_sad = best.sad;
+    let mut best_cost = best.cost;
+    let mut best = (best.mv_x << mv_precision, best.mv_y << mv_precision);
+
+    for step_shift in 1..=mv_precision {
+        let step = 1 << (mv_precision - step_shift);
+        let center = best;
+
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let candidate = (center.0 + dx * step, center.1 + dy * step);
+                if (dx == 0 && dy == 0) || candidate.0.abs() > 127 || candidate.1.abs() > 127 {
+                    continue;
+                }
+
+                let mut sad = 0;
+                for v in (y..y + size).step_by(8) {
+                    for u in (x..x + size).step_by(8) {
+                        predict_block_8x8(
+                            reference,
+                            w,
+                            h,
+                            u,
+                            v,
+                            candidate.0,
+                            candidate.1,
+                            mv_precision,
+                            &mut predicted,
+                        );
+                        sad += sad_predicted_8x8(&original[(v * w + u) as usize..], w, &predicted);
+                    }
+                }
+                let cost = mv_cost.cost(sad, candidate);
+
+                if cost < best_cost {
+                    best = candidate;
+                    best_sad = sad;
+                    best_cost = cost;
+                }
+            }
+        }
+    }
+
+    return (best, best_sad, best_cost);
+}
+
+/// the rate of the forward or `backward` vector of the block at `mb_x`, `mb_y`
+/// in the plane `color_component` of `macroblocks`, coded as write_block does
+/// after the blocks before it in coding order
+fn mv_cost(
+    macroblocks: &c63::MacroBlockContainer,
+    mb_x: i32,
+    mb_y: i32,
+    backward: bool,
+    color_component: c63::ColorComponent,
+    padw: &c63::PaddingContainer,
+    mv_precision: u8,
+    mv_prediction: bool,
+    lambda: f64,
+) -> MvCost {
+    let mbs = &macroblocks[color_component];
+    let cols = padw[color_component] / 8;
+
+    /* The vector is coded relative to the median of the neighbours, or as in
+    the C reference reused from the block to the left and coded as it is */
+    if mv_prediction {
+        let (h, v) = mcu_size(color_component);
+        let median = predict_mv(mbs, cols, mb_x, mb_y, h, v, backward);
+        return MvCost {
+            reused: Some(median),
+            predicted: median,
+            mv_precision: mv_precision,
+            lambda: lambda,
+        };
+    }
+    let left = if mb_x > 0 {
+        mbs[(mb_y * cols + mb_x - 1) as usize].vector(backward)
+    } else {
+        None
+    };
+    return MvCost {
+        reused: left,
+        predicted: (0, 0),
+        mv_precision: mv_precision,
+        lambda: lambda,
+    };
+}
+
+/// `lambda` times the bits of the vectors and the reference index of `mb` if
+/// it is the block at `mb_x`, `mb_y` in the plane `color_component`, see mv_cost
+fn vector_rate(
+    macroblocks: &c63::MacroBlockContainer,
+    mb: &c63::MacroBlock,
+    mb_x: i32,
+    mb_y: i32,
+    color_component: c63::ColorComponent,
+    padw: &c63::PaddingContainer,
+    mv_precision: u8,
+    mv_prediction: bool,
+    lambda: f64,
+) -> i32 {
+    let mut rate = mb.ref_index as i32 * REF_INDEX_SAD_BIAS;
+    for backward in [false, true] {
+        if let Some(mv) = mb.vector(backward) {
+            let cost = mv_cost(
+                macroblocks,
+                mb_x,
+                mb_y,
+                backward,
+                color_component,
+                padw,
+                mv_precision,
+                mv_prediction,
+                lambda,
+            );
+            rate += cost.cost(0, mv);
+        }
+    }
+    return rate;
+}
+
+/// best vector of the `size` x `size` block whose top-left 8x8 block is at
+/// `mb_x`, `mb_y` into `reference_frame`, in the forward or `backward`
+/// direction, its SAD and its SAD plus `lambda` times its bits
+fn search_reference(
+    macroblocks: &c63::MacroBlockContainer, // current frame
+    mb_x: i32,
+    mb_y: i32,
+    size: i32,
+    original: &[u8],
+    reference_frame: &c63::Frame,
+    backward: bool,
     color_component: c63::ColorComponent,
     padw: &c63::PaddingContainer,
     padh: &c63::PaddingContainer,
     me_search_range: i32,
-) {
-    let mut mb =
-        &mut macroblocks[color_component][(mb_y * padw[color_component] / 8 + mb_x) as usize];
-
-    let mut range = me_search_range;
+    mv_precision: u8,
+    mv_prediction: bool,
+    lambda: f64,
+    search: &dyn MotionSearch,
+) -> ((i32, i32), i32, i32) {
+    /* Motion vectors are stored in 8 bits in units of the precision */
+    let mut range = me_search_range.min((127 >> mv_precision) - 1);
 
     /* Quarter resolution for chroma channels. */
     if color_component != c63::COLOR_COMPONENT_Y {
@@ -37,48 +488,383 @@ fn me_block_8x8(
     if (top < 0) {
         top = 0;
     }
-    if (right > (w - 8)) {
-        right = w - 8;
+    if (right > (w - size)) {
+        right = w - size;
     }
-    if (bottom > (h - 8)) {
-        bottom = h - 8;
+    if (bottom > (h - size)) {
+        bottom = h - size;
     }
 
     let mx = mb_x * 8;
     let my = mb_y * 8;
 
-    let mut best_sad = std::i32::MAX;
+    /* The vector the block is coded relative to, then the vectors of the
+    neighbours already searched, of the 8x8 blocks of a larger block and of the
+    same block in the reference frame, in whole pixels */
+    let cols = padw[color_component] / 8;
+    let (mcu_w, mcu_h) = mcu_size(color_component);
+    let median = predict_mv(
+        &macroblocks[color_component],
+        cols,
+        mb_x,
+        mb_y,
+        mcu_w,
+        mcu_h,
+        backward,
+    );
+    let vector = |mb: &c63::MacroBlock| {
+        mb.vector(backward)
+            .map(|(mv_x, mv_y)| (mv_x >> mv_precision, mv_y >> mv_precision))
+    };
+    let mbs = &macroblocks[color_component];
+    let blocks = size / 8;
+    let mut predictors = Vec::with_capacity(5);
+    predictors.push((median.0 >> mv_precision, median.1 >> mv_precision));
+    if mb_x > 0 {
+        predictors.extend(vector(&mbs[(mb_y * cols + mb_x - 1) as usize]));
+    }
+    if mb_y > 0 {
+        predictors.extend(vector(&mbs[((mb_y - 1) * cols + mb_x) as usize]));
+        if mb_x + blocks < cols {
+            predictors.extend(vector(&mbs[((mb_y - 1) * cols + mb_x + blocks) as usize]));
+        }
+    }
+    if blocks > 1 {
+        for y in mb_y..mb_y + blocks {
+            for x in mb_x..mb_x + blocks {
+                predictors.extend(vector(&mbs[(y * cols + x) as usize]));
+            }
+        }
+    }
+    predictors.extend(vector(
+        &reference_frame.mbs[color_component][(mb_y * cols + mb_x) as usize],
+    ));
+
+    let mv_cost = mv_cost(
+        macroblocks,
+        mb_x,
+        mb_y,
+        backward,
+        color_component,
+        padw,
+        mv_precision,
+        mv_prediction,
+        lambda,
+    );
+    let reference = plane(&reference_frame.recons, color_component);
+    let window = SearchWindow {
+        original: original,
+        reference: reference,
+        stride: w,
+        x: mx,
+        y: my,
+        size: size,
+        left: left,
+        top: top,
+        right: right,
+        bottom: bottom,
+        mv_cost: mv_cost,
+    };
+    let best = search.search(&window, &predictors);
 
-    for y in top..bottom {
-        for x in left..right {
-            let sad = dsp::sad_block_8x8(&original, &reference, w);
+    /* Refine around the best integer vector in half and then quarter pixel
+    steps, down to the precision of the stream */
+    return refine_subpel(original, reference, w, h, mx, my, size, best, &mv_cost);
+}
 
-            if sad < best_sad {
-                mb.mv_x = (x - mx) as i8;
-                mb.mv_y = (y - my) as i8;
-                best_sad = sad;
-            }
+/// best inter prediction of the `size` x `size` block whose top-left 8x8 block
+/// is at `mb_x`, `mb_y`, its SAD and its SAD plus the rate of its vectors
+fn best_inter(
+    macroblocks: &c63::MacroBlockContainer, // current frame
+    mb_x: i32,
+    mb_y: i32,
+    size: i32,
+    original: &[u8],
+    references: &c63::ReferenceFrames,
+    color_component: c63::ColorComponent,
+    padw: &c63::PaddingContainer,
+    padh: &c63::PaddingContainer,
+    me_search_range: i32,
+    mv_precision: u8,
+    mv_prediction: bool,
+    lambda: f64,
+    search: &dyn MotionSearch,
+) -> (c63::MacroBlock, i32, i32) {
+    let search_reference = |reference_frame: &c63::Frame, backward: bool| {
+        search_reference(
+            macroblocks,
+            mb_x,
+            mb_y,
+            size,
+            original,
+            reference_frame,
+            backward,
+            color_component,
+            padw,
+            padh,
+            me_search_range,
+            mv_precision,
+            mv_prediction,
+            lambda,
+            search,
+        )
+    };
+
+    /* Search every reference frame, later ones have to be better by the cost
+    of their index */
+    let mut best = c63::MacroBlock::default();
+    let mut best_sad = i32::MAX;
+    let mut best_cost = i32::MAX;
+    let mut forward_rate = 0;
+    for (ref_index, &reference_frame) in references.forward.iter().enumerate() {
+        let (mv, sad, cost) = search_reference(reference_frame, false);
+
+        let cost = cost + ref_index as i32 * REF_INDEX_SAD_BIAS;
+        if cost < best_cost {
+            best = c63::MacroBlock {
+                use_mv: true,
+                mv_x: mv.0 as i8,
+                mv_y: mv.1 as i8,
+                ref_index: ref_index as u8,
+                ..Default::default()
+            };
+            best_sad = sad;
+            best_cost = cost;
+            forward_rate = cost - sad;
         }
     }
 
-    /* Here, there should be a threshold on SAD that checks if the motion vector
-    is cheaper than intraprediction. We always assume MV to be beneficial */
+    /* Blocks of B-frames can also be predicted from the later frame, or from
+    the average of the best vectors in both directions, which codes both */
+    if let Some(reference_frame) = references.backward {
+        let (mv, sad, cost) = search_reference(reference_frame, true);
+        let backward = c63::MacroBlock {
+            use_mv: true,
+            direction: c63::Direction::Backward,
+            backward_mv_x: mv.0 as i8,
+            backward_mv_y: mv.1 as i8,
+            ..Default::default()
+        };
+        let bidirectional = c63::MacroBlock {
+            direction: c63::Direction::Bidirectional,
+            backward_mv_x: mv.0 as i8,
+            backward_mv_y: mv.1 as i8,
+            ..best.clone()
+        };
+        let backward_rate = cost - sad;
+
+        if cost < best_cost {
+            best = backward;
+            best_sad = sad;
+            best_cost = cost;
+        }
+
+        let sad = sad_predicted(
+            original,
+            references,
+            &bidirectional,
+            color_component,
+            padw[color_component],
+            padh[color_component],
+            mb_x * 8,
+            mb_y * 8,
+            size,
+            mv_precision,
+        );
+        let cost = sad + forward_rate + backward_rate;
+        if cost < best_cost {
+            best = bidirectional;
+            best_sad = sad;
+            best_cost = cost;
+        }
+    }
+
+    return (best, best_sad, best_cost);
+}
+
+fn me_block_8x8(
+    macroblocks: &mut c63::MacroBlockContainer, // current frame
+    mb_x: i32,
+    mb_y: i32,
+    original: &[u8],
+    references: &c63::ReferenceFrames,
+    color_component: c63::ColorComponent,
+    padw: &c63::PaddingContainer,
+    padh: &c63::PaddingContainer,
+    me_search_range: i32,
+    mv_precision: u8,
+    mv_prediction: bool,
+    lambda: f64,
+    search: &dyn MotionSearch,
+) {
+    let w = padw[color_component];
+    let mx = mb_x * 8;
+    let my = mb_y * 8;
+
+    let (best, best_sad, _) = best_inter(
+        macroblocks,
+        mb_x,
+        mb_y,
+        8,
+        original,
+        references,
+        color_component,
+        padw,
+        padh,
+        me_search_range,
+        mv_precision,
+        mv_prediction,
+        lambda,
+        search,
+    );
+
+    /* Use the motion vector only if it predicts the block better than its own
+    mean, that is what is left for the AC coefficients of an intra block */
+    let deviation = dsp::deviation_block_8x8(&original[(my * w + mx) as usize..], w);
 
     /* printf("Using motion vector (%d, %d) with SAD %d\n", mb->mv_x, mb->mv_y,
     best_sad); */
 
-    mb.use_mv = true;
+    let mb = &mut macroblocks[color_component][(mb_y * w / 8 + mb_x) as usize];
+    if best_sad < deviation + INTRA_SAD_BIAS {
+        *mb = best;
+    } else {
+        *mb = c63::MacroBlock::default();
+    }
+}
+
+/// choose the partition of each 16x16 macroblock whose 4 luma blocks all use
+/// inter prediction
+///
+/// The single vector is searched for the whole macroblock like the vectors of
+/// the 8x8 blocks, or is one of theirs if that is better. It is used if its
+/// SAD plus rate is not above the sum of those of the 4 blocks with their own
+/// vectors.
+fn choose_partitions(
+    current_frame: &mut c63::Frame,
+    references: &c63::ReferenceFrames,
+    mb_rows: i32,
+    mb_cols: i32,
+    padw: &c63::PaddingContainer,
+    padh: &c63::PaddingContainer,
+    me_search_range: i32,
+    mv_precision: u8,
+    mv_prediction: bool,
+    lambda: f64,
+    search: &dyn MotionSearch,
+) {
+    let w = padw[c63::COLOR_COMPONENT_Y];
+    let h = padh[c63::COLOR_COMPONENT_Y];
+
+    for mb_y in 0..mb_rows / 2 {
+        for mb_x in 0..mb_cols / 2 {
+            let macroblock = current_frame.macroblock(mb_x, mb_y);
+            if !macroblock.y.iter().all(|mb| mb.use_mv) {
+                continue;
+            }
+
+            /* Each of the 4 blocks codes its vectors after the ones before it
+            in the MCU */
+            let mut split_cost = 0;
+            for (i, mb) in macroblock.y.iter().enumerate() {
+                let x = mb_x * 2 + i as i32 % 2;
+                let y = mb_y * 2 + i as i32 / 2;
+                split_cost += sad_predicted(
+                    &current_frame.orig.y,
+                    references,
+                    mb,
+                    c63::COLOR_COMPONENT_Y,
+                    w,
+                    h,
+                    x * 8,
+                    y * 8,
+                    8,
+                    mv_precision,
+                ) + vector_rate(
+                    &current_frame.mbs,
+                    mb,
+                    x,
+                    y,
+                    c63::COLOR_COMPONENT_Y,
+                    padw,
+                    mv_precision,
+                    mv_prediction,
+                    lambda,
+                );
+            }
+
+            let (mut best, _, mut single_cost) = best_inter(
+                &current_frame.mbs,
+                mb_x * 2,
+                mb_y * 2,
+                16,
+                &current_frame.orig.y,
+                references,
+                c63::COLOR_COMPONENT_Y,
+                padw,
+                padh,
+                me_search_range,
+                mv_precision,
+                mv_prediction,
+                lambda,
+                search,
+            );
+
+            /* The search window of the macroblock ends 16 pixels before the
+            right and bottom edge, the vectors of its left and top blocks may
+            point further */
+            for mb in &macroblock.y {
+                let cost = sad_predicted(
+                    &current_frame.orig.y,
+                    references,
+                    mb,
+                    c63::COLOR_COMPONENT_Y,
+                    w,
+                    h,
+                    mb_x * 16,
+                    mb_y * 16,
+                    16,
+                    mv_precision,
+                ) + vector_rate(
+                    &current_frame.mbs,
+                    mb,
+                    mb_x * 2,
+                    mb_y * 2,
+                    c63::COLOR_COMPONENT_Y,
+                    padw,
+                    mv_precision,
+                    mv_prediction,
+                    lambda,
+                );
+                if cost < single_cost {
+                    best = (*mb).clone();
+                    single_cost = cost;
+                }
+            }
+
+            if single_cost <= split_cost {
+                current_frame.set_single_vector(mb_x, mb_y, best);
+            }
+        }
+    }
 }
 
+/// find the motion of `current_frame` relative to `references`, the vectors
+/// with the lowest SAD plus `lambda` times their bits
 pub fn c63_motion_estimate(
     current_frame: &mut c63::Frame,
-    reference_frame: &c63::Frame,
+    references: &c63::ReferenceFrames,
     mb_rows: i32,
     mb_cols: i32,
     padw: &c63::PaddingContainer,
     padh: &c63::PaddingContainer,
     me_search_range: i32,
+    coding_tools: &c63::CodingTools,
+    lambda: f64,
+    search: &dyn MotionSearch,
 ) {
+    let mv_precision = coding_tools.mv_precision;
+
     for mb_y in 0..mb_rows {
         for mb_x in 0..mb_cols {
             me_block_8x8(
@@ -86,15 +872,48 @@ pub fn c63_motion_estimate(
                 mb_x,
                 mb_y,
                 &current_frame.orig.y,
-                &reference_frame.recons.y,
+                references,
                 c63::COLOR_COMPONENT_Y,
                 padw,
                 padh,
                 me_search_range,
+                mv_precision,
+                coding_tools.mv_prediction,
+                lambda,
+                search,
             );
         }
     }
 
+    if coding_tools.macroblock_partitions {
+        choose_partitions(
+            current_frame,
+            references,
+            mb_rows,
+            mb_cols,
+            padw,
+            padh,
+            me_search_range,
+            mv_precision,
+            coding_tools.mv_prediction,
+            lambda,
+            search,
+        );
+    }
+
+    if coding_tools.chroma_mv_from_luma {
+        let [y_mbs, u_mbs, v_mbs] = &mut current_frame.mbs;
+        let cols = padw[c63::COLOR_COMPONENT_U] / 8;
+        for mb_y in 0..mb_rows / 2 {
+            for mb_x in 0..mb_cols / 2 {
+                let mb = self::chroma_mv_from_luma(y_mbs, cols, mb_x, mb_y);
+                u_mbs[(mb_y * cols + mb_x) as usize] = mb.clone();
+                v_mbs[(mb_y * cols + mb_x) as usize] = mb;
+            }
+        }
+        return;
+    }
+
     for mb_y in 0..mb_rows / 2 {
         for mb_x in 0..mb_cols / 2 {
             me_block_8x8(
@@ -102,22 +921,30 @@ pub fn c63_motion_estimate(
                 mb_x,
                 mb_y,
                 &current_frame.orig.u,
-                &reference_frame.recons.u,
+                references,
                 c63::COLOR_COMPONENT_U,
                 padw,
                 padh,
                 me_search_range,
+                mv_precision,
+                coding_tools.mv_prediction,
+                lambda,
+                search,
             );
             me_block_8x8(
                 &mut current_frame.mbs,
                 mb_x,
                 mb_y,
                 &current_frame.orig.v,
-                &reference_frame.recons.v,
+                references,
                 c63::COLOR_COMPONENT_V,
                 padw,
                 padh,
                 me_search_range,
+                mv_precision,
+                coding_tools.mv_prediction,
+                lambda,
+                search,
             );
         }
     }
@@ -128,10 +955,11 @@ fn mc_block_8x8(
     mb_x: i32,
     mb_y: i32,
     predicted: &mut [u8],
-    reference: &[u8],
+    references: &c63::ReferenceFrames,
     color_component: c63::ColorComponent,
     padw: &c63::PaddingContainer,
     padh: &c63::PaddingContainer,
+    mv_precision: u8,
 ) {
     let mb = &macroblocks[color_component][(mb_y * padw[color_component] / 8 + mb_x) as usize];
 
@@ -141,28 +969,44 @@ fn mc_block_8x8(
 
     let left = mb_x * 8;
     let top = mb_y * 8;
-    let right = left + 8;
-    let bottom = top + 8;
 
     let w = padw[color_component];
+    let h = padh[color_component];
 
     /* Copy block from ref mandated by MV */
-    for y in top..bottom {
-        for x in left..right {
-            predicted[(y * w + x) as usize] =
-                reference[((y + mb.mv_y as i32) * w + (x + mb.mv_x as i32)) as usize];
-        }
+    let mut block = [0u8; 64];
+    predict_mb_8x8(
+        references,
+        mb,
+        color_component,
+        w,
+        h,
+        left,
+        top,
+        mv_precision,
+        &mut block,
+    );
+
+    for v in 0..8 {
+        let row = ((top + v) * w + left) as usize;
+        predicted[row..row + 8].copy_from_slice(&block[(v * 8) as usize..(v * 8 + 8) as usize]);
     }
 }
 
+/// predict `current_frame` from `references` with its motion vectors
 pub fn c63_motion_compensate(
     current_frame: &mut c63::Frame,
-    reference_frame: &c63::Frame,
+    references: &c63::ReferenceFrames,
     mb_rows: i32,
     mb_cols: i32,
     padw: &c63::PaddingContainer,
     padh: &c63::PaddingContainer,
+    mv_precision: u8,
+    chroma_mv_from_luma: bool,
 ) {
+    /* Chroma vectors derived from luma have one more bit of precision */
+    let chroma_precision = mv_precision + chroma_mv_from_luma as u8;
+
     /* Luma */
     for mb_y in 0..mb_rows {
         for mb_x in 0..mb_cols {
@@ -171,10 +1015,11 @@ pub fn c63_motion_compensate(
                 mb_x,
                 mb_y,
                 &mut current_frame.predicted.y,
-                &reference_frame.recons.y,
+                references,
                 c63::COLOR_COMPONENT_Y,
                 padw,
                 padh,
+                mv_precision,
             );
         }
     }
@@ -187,21 +1032,472 @@ pub fn c63_motion_compensate(
                 mb_x,
                 mb_y,
                 &mut current_frame.predicted.u,
-                &reference_frame.recons.u,
+                references,
                 c63::COLOR_COMPONENT_U,
                 padw,
                 padh,
+                chroma_precision,
             );
             mc_block_8x8(
                 &current_frame.mbs,
                 mb_x,
                 mb_y,
                 &mut current_frame.predicted.v,
-                &reference_frame.recons.v,
+                references,
                 c63::COLOR_COMPONENT_V,
                 padw,
                 padh,
+                chroma_precision,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::c63_write;
+    use crate::motion_search::FullSearch;
+    use crate::tables;
+    use crate::testing::*;
+
+    fn estimate(current: &c63::YUV, reference: &c63::YUV, mv_precision: u8) -> c63::Frame {
+        return estimate_with(
+            current,
+            &[reference],
+            None,
+            &c63::CodingTools {
+                mv_precision: mv_precision,
+                ..c63::CodingTools::new()
+            },
+        );
+    }
+
+    fn estimate_with(
+        current: &c63::YUV,
+        references: &[&c63::YUV],
+        backward: Option<&c63::YUV>,
+        coding_tools: &c63::CodingTools,
+    ) -> c63::Frame {
+        let (padw, padh) = c63::compute_padding(32, 32);
+        let mut current_frame = c63::Frame::new(
+            c63::YUV {
+                y: current.y.clone(),
+                u: current.u.clone(),
+                v: current.v.clone(),
+            },
+            &padw,
+            &padh,
+            4,
+            4,
+            false,
+        );
+        let reference_frames: Vec<Box<c63::Frame>> = references
+            .iter()
+            .chain(backward.as_ref())
+            .map(|reference| {
+                let mut frame = c63::Frame::new(c63::YUV::new(0, 0, 0), &padw, &padh, 4, 4, true);
+                frame.recons = c63::YUV {
+                    y: reference.y.clone(),
+                    u: reference.u.clone(),
+                    v: reference.v.clone(),
+                };
+                Box::new(frame)
+            })
+            .collect();
+
+        let (forward, backward) = reference_frames.split_at(references.len());
+        let references = c63::ReferenceFrames {
+            forward: forward.iter().map(|frame| &**frame).collect(),
+            backward: backward.first().map(|frame| &**frame),
+        };
+        c63_motion_estimate(
+            &mut current_frame,
+            &references,
+            4,
+            4,
+            &padw,
+            &padh,
+            16,
+            coding_tools,
+            lambda(&tables::YQUANTTBL_DEF),
+            &FullSearch,
+        );
+        return current_frame;
+    }
+
+    fn textured(seed: u32) -> c63::YUV {
+        let mut image = c63::YUV::new(32 * 32, 16 * 16, 16 * 16);
+        let mut state = seed;
+        for p in image.y.iter_mut() {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            *p = (state >> 16) as u8;
+        }
+        image.u.fill(128);
+        image.v.fill(128);
+        return image;
+    }
+
+    #[test]
+    fn shifted_content_uses_motion_vectors() {
+        let reference = textured(1);
+        let mut current = textured(1);
+        /* Shift everything one pixel to the left */
+        for y in 0..32 {
+            for x in 0..31 {
+                current.y[y * 32 + x] = reference.y[y * 32 + x + 1];
+            }
+        }
+
+        let frame = estimate(&current, &reference, 0);
+        let mb = &frame.mbs[c63::COLOR_COMPONENT_Y][0];
+        assert!(mb.use_mv);
+        assert_eq!((mb.mv_x, mb.mv_y), (1, 0));
+    }
+
+    #[test]
+    fn uniform_motion_uses_single_vectors() {
+        let reference = textured(1);
+        let mut current = textured(1);
+        for y in 0..32 {
+            for x in 0..31 {
+                current.y[y * 32 + x] = reference.y[y * 32 + x + 1];
+            }
+        }
+        /* The second block of the first macroblock is an object of its own */
+        for y in 0..8 {
+            for x in 8..16 {
+                current.y[y * 32 + x] = reference.y[(y + 3) * 32 + x + 2];
+            }
+        }
+
+        let coding_tools = c63::CodingTools {
+            macroblock_partitions: true,
+            ..c63::CodingTools::new()
+        };
+        let frame = estimate_with(&current, &[&reference], None, &coding_tools);
+        assert_eq!(frame.partitions[0], c63::Partition::Split8x8);
+        assert_eq!(frame.partitions[2], c63::Partition::Single16x16);
+
+        let macroblock = frame.macroblock(0, 1);
+        for mb in &macroblock.y {
+            assert_eq!((mb.mv_x, mb.mv_y), (1, 0));
+        }
+    }
+
+    #[test]
+    fn macroblock_search_finds_the_common_motion() {
+        /* The macroblock at 0, 16 moved by 2, 0 with some noise, and an exact
+        copy of each of its blocks is elsewhere in the reference */
+        let mut reference = textured(1);
+        let mut current = textured(9);
+        for y in 16..32 {
+            for x in 0..16 {
+                let noise = if (x + y) % 2 == 0 { 3 } else { -3 };
+                current.y[y * 32 + x] =
+                    (reference.y[y * 32 + x + 2] as i32 + noise).clamp(0, 255) as u8;
+            }
+        }
+        let copies: [(i32, i32); 4] = [(8, 0), (0, 0), (8, 8), (24, 8)];
+        for (i, &(to_x, to_y)) in copies.iter().enumerate() {
+            let (from_x, from_y) = (i % 2 * 8, 16 + i / 2 * 8);
+            for v in 0..8 {
+                for u in 0..8 {
+                    reference.y[(to_y as usize + v) * 32 + to_x as usize + u] =
+                        current.y[(from_y + v) * 32 + from_x + u];
+                }
+            }
+        }
+
+        /* Each block finds its copy, the macroblock the motion they share */
+        let frame = estimate(&current, &reference, 0);
+        let macroblock = frame.macroblock(0, 1);
+        for (i, mb) in macroblock.y.iter().enumerate() {
+            let (to_x, to_y) = copies[i];
+            let (from_x, from_y) = (i % 2 * 8, 16 + i / 2 * 8);
+            assert_eq!(
+                (mb.mv_x as i32, mb.mv_y as i32),
+                (to_x - from_x as i32, to_y - from_y as i32)
+            );
+        }
+
+        let (padw, padh) = c63::compute_padding(32, 32);
+        let mut reference_frame = c63::Frame::new(c63::YUV::new(0, 0, 0), &padw, &padh, 4, 4, true);
+        reference_frame.recons = reference;
+        let references = c63::ReferenceFrames {
+            forward: vec![&reference_frame],
+            backward: None,
+        };
+        let (mb, sad, _) = best_inter(
+            &frame.mbs,
+            0,
+            2,
+            16,
+            &current.y,
+            &references,
+            c63::COLOR_COMPONENT_Y,
+            &padw,
+            &padh,
+            16,
+            0,
+            false,
+            lambda(&tables::YQUANTTBL_DEF),
+            &FullSearch,
+        );
+        assert_eq!((mb.mv_x, mb.mv_y), (2, 0));
+        assert!(sad <= 256 * 3);
+    }
+
+    #[test]
+    fn occluded_block_uses_older_reference() {
+        /* An object covers the first block in the previous frame, but not in
+        the one before it */
+        let older = textured(1);
+        let mut previous = textured(1);
+        for y in 0..8 {
+            for x in 0..8 {
+                previous.y[y * 32 + x] = 200;
+            }
+        }
+        let current = textured(1);
+
+        let frame = estimate_with(
+            &current,
+            &[&previous, &older],
+            None,
+            &c63::CodingTools::new(),
+        );
+        let mb = &frame.mbs[c63::COLOR_COMPONENT_Y][0];
+        assert!(mb.use_mv);
+        assert_eq!((mb.ref_index, mb.mv_x, mb.mv_y), (1, 0, 0));
+        /* Ties go to the most recent frame, its index is cheaper */
+        assert_eq!(frame.mbs[c63::COLOR_COMPONENT_Y][5].ref_index, 0);
+    }
+
+    #[test]
+    fn average_of_both_references_is_bidirectional() {
+        let earlier = textured(1);
+        let later = textured(2);
+        let mut current = textured(1);
+        for (p, &l) in current.y.iter_mut().zip(later.y.iter()) {
+            *p = ((*p as u16 + l as u16 + 1) >> 1) as u8;
+        }
+        /* The later frame is the same as this one in the first block */
+        for y in 0..8 {
+            for x in 0..8 {
+                current.y[y * 32 + x] = later.y[y * 32 + x];
+            }
+        }
+
+        let frame = estimate_with(
+            &current,
+            &[&earlier],
+            Some(&later),
+            &c63::CodingTools::new(),
+        );
+        let mb = &frame.mbs[c63::COLOR_COMPONENT_Y][0];
+        assert_eq!(mb.direction, c63::Direction::Backward);
+        assert_eq!(mb.vector(true), Some((0, 0)));
+        let mb = &frame.mbs[c63::COLOR_COMPONENT_Y][5];
+        assert_eq!(mb.direction, c63::Direction::Bidirectional);
+        assert_eq!(
+            (mb.vector(false), mb.vector(true)),
+            (Some((0, 0)), Some((0, 0)))
+        );
+    }
+
+    #[test]
+    fn occluded_block_is_intra() {
+        let reference = textured(1);
+        let mut current = textured(1);
+        /* A flat object that is not in the reference covers the first block */
+        for y in 0..8 {
+            for x in 0..8 {
+                current.y[y * 32 + x] = 200;
+            }
+        }
+
+        let frame = estimate(&current, &reference, 0);
+        assert!(!frame.mbs[c63::COLOR_COMPONENT_Y][0].use_mv);
+        assert!(frame.mbs[c63::COLOR_COMPONENT_Y][5].use_mv);
+    }
+
+    #[test]
+    fn half_pixel_shift_is_found() {
+        /* Horizontally smooth content, bilinear interpolation is exact for it */
+        let mut reference = c63::YUV::new(32 * 32, 16 * 16, 16 * 16);
+        let mut current = c63::YUV::new(32 * 32, 16 * 16, 16 * 16);
+        for y in 0..32 {
+            for x in 0..32 {
+                reference.y[y * 32 + x] = (x * 4 + (y % 4) * 20) as u8;
+                current.y[y * 32 + x] = (x * 4 + 2 + (y % 4) * 20) as u8;
+            }
+        }
+
+        let integer = estimate(&current, &reference, 0);
+        let mb = &integer.mbs[c63::COLOR_COMPONENT_Y][5];
+        assert!(mb.mv_x == 0 || mb.mv_x == 1);
+
+        for (mv_precision, expected) in [(1, 1), (2, 2)] {
+            let frame = estimate(&current, &reference, mv_precision);
+            let mb = &frame.mbs[c63::COLOR_COMPONENT_Y][5];
+            assert!(mb.use_mv);
+            assert_eq!((mb.mv_x, mb.mv_y), (expected, 0));
+
+            /* Compensation gives back the current frame */
+            let mut compensated = c63::Frame::new(
+                c63::YUV::new(0, 0, 0),
+                &[32, 16, 16],
+                &[32, 16, 16],
+                4,
+                4,
+                false,
+            );
+            compensated.mbs = frame.mbs.clone();
+            let mut reference_frame = c63::Frame::new(
+                c63::YUV::new(0, 0, 0),
+                &[32, 16, 16],
+                &[32, 16, 16],
+                4,
+                4,
+                true,
             );
+            reference_frame.recons.y = reference.y.clone();
+            c63_motion_compensate(
+                &mut compensated,
+                &c63::ReferenceFrames {
+                    forward: vec![&reference_frame],
+                    backward: None,
+                },
+                4,
+                4,
+                &[32, 16, 16],
+                &[32, 16, 16],
+                mv_precision,
+                false,
+            );
+            assert_eq!(compensated.predicted.y[8 * 32 + 8], current.y[8 * 32 + 8]);
         }
     }
+
+    #[test]
+    fn median_prediction_uses_coded_neighbours() {
+        let vector = |mv_x, mv_y| c63::MacroBlock {
+            use_mv: true,
+            mv_x: mv_x,
+            mv_y: mv_y,
+            ..Default::default()
+        };
+        /* 4x2 blocks, coded in 2x2 MCUs */
+        let mut mbs = vec![c63::MacroBlock::default(); 8];
+        mbs[0] = vector(1, 1);
+        mbs[1] = vector(4, -2);
+        mbs[2] = vector(8, 8);
+        mbs[4] = vector(3, 0);
+        mbs[5] = vector(2, 6);
+
+        /* The first row only has a left neighbour */
+        assert_eq!(predict_mv(&mbs, 4, 1, 0, 2, 2, false), (1, 1));
+        /* Top-right in the same MCU, the missing left neighbour is zero */
+        assert_eq!(predict_mv(&mbs, 4, 0, 1, 2, 2, false), (1, 0));
+        /* Top-right in the next MCU is not coded yet, top-left is used */
+        assert_eq!(predict_mv(&mbs, 4, 1, 1, 2, 2, false), (3, 0));
+        /* In raster order it is coded */
+        assert_eq!(predict_mv(&mbs, 4, 1, 1, 1, 1, false), (4, 0));
+    }
+
+    /// assert that with each entropy coder, the stream of `frames` frames of
+    /// `image` is smaller when `tool` changes `tools`
+    fn assert_smaller_with(
+        tools: c63::CodingTools,
+        tool: fn(&mut c63::CodingTools),
+        image: Image,
+        frames: i32,
+    ) {
+        let mut with = tools;
+        tool(&mut with);
+        let smaller = size_and_psnr_of_each_coder(LARGE, with, |_| {}, image, frames);
+        let larger = size_and_psnr_of_each_coder(LARGE, tools, |_| {}, image, frames);
+        for (smaller, larger) in smaller.iter().zip(larger.iter()) {
+            assert!(smaller.0 < larger.0);
+        }
+    }
+
+    #[test]
+    fn predicted_vectors_are_smaller() {
+        /* Both streams have the APP0 segment */
+        let tools = c63::CodingTools {
+            skip_blocks: true,
+            ..c63::CodingTools::new()
+        };
+        assert_smaller_with(tools, |tools| tools.mv_prediction = true, panning_image, 3);
+    }
+
+    #[test]
+    fn single_vectors_are_smaller() {
+        /* Every block has the same motion, so single vectors save coding the
+        other 3 */
+        let tools = c63::CodingTools {
+            skip_blocks: true,
+            ..c63::CodingTools::new()
+        };
+        assert_smaller_with(
+            tools,
+            |tools| tools.macroblock_partitions = true,
+            panning_image,
+            3,
+        );
+    }
+
+    #[test]
+    fn older_references_are_smaller() {
+        /* Each frame is the same as the one two frames back, the first two
+        frames are the same either way */
+        let tools = c63::CodingTools {
+            reference_frames: 2,
+            ..c63::CodingTools::new()
+        };
+        let two_references = size_and_psnr_of_each_coder(LARGE, tools, |_| {}, blinking_image, 5);
+        let one_reference =
+            size_and_psnr_of_each_coder(LARGE, c63::CodingTools::new(), |_| {}, blinking_image, 5);
+        for (two, one) in two_references.iter().zip(one_reference.iter()) {
+            assert!(two.0 * 3 < one.0 * 2);
+        }
+    }
+
+    #[test]
+    fn chroma_vectors_from_luma_are_smaller() {
+        /* Chroma quality and size of the stream with chroma vectors searched
+        separately and derived from luma */
+        let encode = |chroma_mv_from_luma: bool| {
+            let tools = c63::CodingTools {
+                skip_blocks: true,
+                chroma_mv_from_luma,
+                ..c63::CodingTools::new()
+            };
+            let mut ctx = context(LARGE, tools);
+
+            let mut quality = 0.0;
+            let mut size = 0;
+            for i in 0..4 {
+                let image = panning_color_image(&ctx, i);
+                encode_context::encode_image(&mut ctx, image);
+                let mut stream = Vec::new();
+                c63_write::write_frame(&ctx, &mut stream).unwrap();
+                size += stream.len();
+
+                let frame = ctx.current_frame.as_ref().unwrap();
+                quality +=
+                    psnr(&frame.orig.u, &frame.recons.u) + psnr(&frame.orig.v, &frame.recons.v);
+            }
+            (quality / 8.0, size)
+        };
+
+        let (searched_psnr, searched_size) = encode(false);
+        let (derived_psnr, derived_size) = encode(true);
+        /* The vectors are chosen for luma alone, so some chroma quality is
+        traded for not coding them */
+        assert!(derived_size < searched_size);
+        assert!(derived_psnr > searched_psnr - 1.0);
+    }
 }
@@ -0,0 +1,449 @@
+use crate::bitstream;
+use crate::dsp;
+use crate::tables;
+
+/// Motion search strategies, selected in the encoder configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchMethod {
+    Full,
+    ThreeStep,
+    Diamond,
+    Hexagon,
+    Epzs,
+}
+
+impl SearchMethod {
+    pub fn searcher(self) -> &'static dyn MotionSearch {
+        match self {
+            SearchMethod::Full => &FullSearch,
+            SearchMethod::ThreeStep => &ThreeStepSearch,
+            SearchMethod::Diamond => &DiamondSearch,
+            SearchMethod::Hexagon => &HexagonSearch,
+            SearchMethod::Epzs => &EpzsSearch,
+        }
+    }
+}
+
+/// bits of a component `val` of a coded vector difference, its MVVLC size
+/// symbol and the value bits
+pub fn mv_component_bits(val: i32) -> i32 {
+    let size = bitstream::bit_width(val as i16);
+    return tables::MVVLC_SIZE[size as usize] as i32 + size as i32;
+}
+
+/// The rate of the vectors of a block, the bits of its reuse flag and of the
+/// difference to the vector it is predicted from, weighed by `lambda`
+///
+/// The sizes are those of the Huffman coded MVVLC, the arithmetic coder codes
+/// the same differences in about as many bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MvCost {
+    pub reused: Option<(i32, i32)>, // the vector coded with just the reuse flag
+    pub predicted: (i32, i32),      // the other vectors are coded relative to it
+    pub mv_precision: u8,
+    pub lambda: f64, // SAD per bit
+}
+
+impl MvCost {
+    /// bits of the vector `mv` in units of the precision
+    pub fn bits(&self, mv: (i32, i32)) -> i32 {
+        if self.reused == Some(mv) {
+            return 1;
+        }
+        return 1
+            + mv_component_bits(mv.0 - self.predicted.0)
+            + mv_component_bits(mv.1 - self.predicted.1);
+    }
+
+    /// `sad` plus the rate of the vector `mv` in units of the precision
+    pub fn cost(&self, sad: i32, mv: (i32, i32)) -> i32 {
+        return sad + (self.lambda * self.bits(mv) as f64).round() as i32;
+    }
+}
+
+/// A block of the current frame and the area of the reference frame its
+/// integer motion vectors may point into
+pub struct SearchWindow<'a> {
+    pub original: &'a [u8],  // plane of the current frame
+    pub reference: &'a [u8], // plane of the reference frame
+    pub stride: i32,
+
+    pub x: i32, // position of the block
+    pub y: i32,
+    pub size: i32, // 8 or 16, the block is made of 8x8 blocks
+
+    /* Candidate positions are left..=right and top..=bottom. The C reference
+    excluded right and bottom, so the last column and row could not point
+    towards the edge at all */
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+
+    pub mv_cost: MvCost,
+}
+
+impl SearchWindow<'_> {
+    /// SAD of the block predicted by the vector `mv_x`, `mv_y`, or None if it
+    /// points outside the window
+    pub fn sad(&self, mv_x: i32, mv_y: i32) -> Option<i32> {
+        let x = self.x + mv_x;
+        let y = self.y + mv_y;
+
+        if x < self.left || x > self.right || y < self.top || y > self.bottom {
+            return None;
+        }
+
+        let mut result = 0;
+        for v in (0..self.size).step_by(8) {
+            for u in (0..self.size).step_by(8) {
+                result += dsp::sad_block_8x8(
+                    &self.original[((self.y + v) * self.stride + self.x + u) as usize..],
+                    &self.reference[((y + v) * self.stride + x + u) as usize..],
+                    self.stride,
+                );
+            }
+        }
+        return Some(result);
+    }
+
+    /// the SAD of the integer vector `mv_x`, `mv_y` plus its rate, or None if
+    /// it points outside the window
+    pub fn cost(&self, mv_x: i32, mv_y: i32) -> Option<Candidate> {
+        let sad = self.sad(mv_x, mv_y)?;
+        let precision = self.mv_cost.mv_precision;
+        return Some(Candidate {
+            mv_x,
+            mv_y,
+            sad,
+            cost: self
+                .mv_cost
+                .cost(sad, (mv_x << precision, mv_y << precision)),
+        });
+    }
+}
+
+/// The best integer motion vector found so far, its SAD and its SAD plus rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candidate {
+    pub mv_x: i32,
+    pub mv_y: i32,
+    pub sad: i32,
+    pub cost: i32,
+}
+
+impl Candidate {
+    /// evaluate the vector `mv_x`, `mv_y` and keep it if it is strictly better
+    fn try_mv(&mut self, window: &SearchWindow, mv_x: i32, mv_y: i32) {
+        if let Some(candidate) = window.cost(mv_x, mv_y) {
+            if candidate.cost < self.cost {
+                *self = candidate;
+            }
+        }
+    }
+}
+
+/// A strategy for finding the integer motion vector of a block
+pub trait MotionSearch {
+    /// vector in `window` with the lowest SAD plus rate
+    ///
+    /// `predictors` are integer vectors of neighbouring blocks that are likely
+    /// to be close to the motion of this block, searches start from the best of
+    /// them and the zero vector.
+    fn search(&self, window: &SearchWindow, predictors: &[(i32, i32)]) -> Candidate;
+}
+
+/// the zero vector, replaced only by a strictly better predictor so that the
+/// zero vector is kept on ties
+fn start_candidate(window: &SearchWindow, predictors: &[(i32, i32)]) -> Candidate {
+    let mut best = window.cost(0, 0).unwrap();
+
+    for &(mv_x, mv_y) in predictors {
+        best.try_mv(window, mv_x, mv_y);
+    }
+
+    return best;
+}
+
+/// move around `best` with `pattern` until none of its points is better
+fn pattern_search(window: &SearchWindow, mut best: Candidate, pattern: &[(i32, i32)]) -> Candidate {
+    /* Every move lowers the cost, so this ends, the bound only guards against
+    very long walks across flat areas */
+    for _ in 0..window.stride {
+        let center = best;
+        for &(dx, dy) in pattern {
+            best.try_mv(window, center.mv_x + dx, center.mv_y + dy);
+        }
+
+        if best == center {
+            break;
+        }
+    }
+
+    return best;
+}
+
+const SQUARE: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+const SMALL_DIAMOND: [(i32, i32); 4] = [(0, -1), (-1, 0), (1, 0), (0, 1)];
+
+const LARGE_DIAMOND: [(i32, i32); 8] = [
+    (0, -2),
+    (-1, -1),
+    (1, -1),
+    (-2, 0),
+    (2, 0),
+    (-1, 1),
+    (1, 1),
+    (0, 2),
+];
+
+const HEXAGON: [(i32, i32); 6] = [(-2, 0), (-1, -2), (1, -2), (2, 0), (1, 2), (-1, 2)];
+
+/// Exhaustive search over the whole window, the reference behaviour
+pub struct FullSearch;
+
+impl MotionSearch for FullSearch {
+    fn search(&self, window: &SearchWindow, predictors: &[(i32, i32)]) -> Candidate {
+        let mut best = start_candidate(window, predictors);
+
+        for y in window.top..=window.bottom {
+            for x in window.left..=window.right {
+                best.try_mv(window, x - window.x, y - window.y);
+            }
+        }
+
+        return best;
+    }
+}
+
+/// Three step search: the 8 neighbours at a distance of half the range, then
+/// around the best of them at half that distance, down to one pixel
+pub struct ThreeStepSearch;
+
+impl MotionSearch for ThreeStepSearch {
+    fn search(&self, window: &SearchWindow, predictors: &[(i32, i32)]) -> Candidate {
+        let range = (window.right - window.left).max(window.bottom - window.top) / 2;
+
+        let mut step = 1;
+        while step * 2 <= range / 2 {
+            step *= 2;
+        }
+
+        let mut best = start_candidate(window, predictors);
+        while step >= 1 {
+            let center = best;
+            for &(dx, dy) in &SQUARE {
+                best.try_mv(window, center.mv_x + dx * step, center.mv_y + dy * step);
+            }
+            step /= 2;
+        }
+
+        return best;
+    }
+}
+
+/// Diamond search: the large diamond until its center is best, then one step
+/// of the small diamond
+pub struct DiamondSearch;
+
+impl MotionSearch for DiamondSearch {
+    fn search(&self, window: &SearchWindow, predictors: &[(i32, i32)]) -> Candidate {
+        let start = start_candidate(window, predictors);
+        let best = pattern_search(window, start, &LARGE_DIAMOND);
+
+        let mut refined = best;
+        for &(dx, dy) in &SMALL_DIAMOND {
+            refined.try_mv(window, best.mv_x + dx, best.mv_y + dy);
+        }
+
+        return refined;
+    }
+}
+
+/// Hexagon search: a hexagon until its center is best, then the 8 neighbours
+/// of the center. Fewer points per step than the diamond for the same reach
+pub struct HexagonSearch;
+
+impl MotionSearch for HexagonSearch {
+    fn search(&self, window: &SearchWindow, predictors: &[(i32, i32)]) -> Candidate {
+        let start = start_candidate(window, predictors);
+        let best = pattern_search(window, start, &HEXAGON);
+
+        let mut refined = best;
+        for &(dx, dy) in &SQUARE {
+            refined.try_mv(window, best.mv_x + dx, best.mv_y + dy);
+        }
+
+        return refined;
+    }
+}
+
+/// SAD per 8x8 block below which EPZS stops after the predictors, about one
+/// level per pixel
+const EPZS_THRESHOLD: i32 = 64;
+
+/// Enhanced predictive zonal search: the predictors usually are right already,
+/// so the search stops at them if they are good enough and otherwise refines
+/// the best with the small diamond
+pub struct EpzsSearch;
+
+impl MotionSearch for EpzsSearch {
+    fn search(&self, window: &SearchWindow, predictors: &[(i32, i32)]) -> Candidate {
+        let start = start_candidate(window, predictors);
+        let blocks = window.size / 8;
+        if start.sad < EPZS_THRESHOLD * blocks * blocks {
+            return start;
+        }
+
+        return pattern_search(window, start, &SMALL_DIAMOND);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: i32 = 64;
+
+    const NO_MV_COST: MvCost = MvCost {
+        reused: None,
+        predicted: (0, 0),
+        mv_precision: 0,
+        lambda: 0.0,
+    };
+
+    /* Smooth content, so that the SAD falls towards the true motion */
+    fn smooth(shift_x: i32, shift_y: i32) -> Vec<u8> {
+        let mut plane = vec![0u8; (SIZE * SIZE) as usize];
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let fx = (x + shift_x) as f64;
+                let fy = (y + shift_y) as f64;
+                plane[(y * SIZE + x) as usize] =
+                    (128.0 + 60.0 * (fx / 6.0).sin() * (fy / 7.0).cos() + fx) as u8;
+            }
+        }
+        return plane;
+    }
+
+    #[test]
+    fn all_methods_find_the_motion() {
+        let reference = smooth(0, 0);
+        /* The current frame is the reference moved by (3, -2) */
+        let original = smooth(3, -2);
+
+        let window = SearchWindow {
+            original: &original,
+            reference: &reference,
+            stride: SIZE,
+            x: 24,
+            y: 24,
+            size: 8,
+            left: 24 - 16,
+            top: 24 - 16,
+            right: 24 + 16,
+            bottom: 24 + 16,
+            mv_cost: NO_MV_COST,
+        };
+
+        for method in [
+            SearchMethod::Full,
+            SearchMethod::ThreeStep,
+            SearchMethod::Diamond,
+            SearchMethod::Hexagon,
+            SearchMethod::Epzs,
+        ] {
+            /* EPZS relies on the predictors, the others on the search */
+            let predictors: &[(i32, i32)] = if method == SearchMethod::Epzs {
+                &[(2, -2), (9, 9)]
+            } else {
+                &[]
+            };
+
+            let best = method.searcher().search(&window, predictors);
+            assert_eq!((best.mv_x, best.mv_y, best.sad), (3, -2, 0), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn flat_blocks_keep_the_predicted_vector() {
+        /* Every vector predicts a flat block equally well, the predicted one
+        costs the fewest bits */
+        let flat = vec![100u8; (SIZE * SIZE) as usize];
+        let window = SearchWindow {
+            original: &flat,
+            reference: &flat,
+            stride: SIZE,
+            x: 24,
+            y: 24,
+            size: 8,
+            left: 24 - 16,
+            top: 24 - 16,
+            right: 24 + 16,
+            bottom: 24 + 16,
+            mv_cost: MvCost {
+                reused: Some((6, -2)),
+                predicted: (6, -2),
+                mv_precision: 1,
+                lambda: 4.0,
+            },
+        };
+        let best = FullSearch.search(&window, &[]);
+        assert_eq!((best.mv_x, best.mv_y, best.cost), (3, -1, 4));
+
+        /* Without the reuse flag the predicted vector still codes the
+        shortest difference */
+        let window = SearchWindow {
+            mv_cost: MvCost {
+                reused: None,
+                predicted: (5, -3),
+                mv_precision: 0,
+                lambda: 4.0,
+            },
+            ..window
+        };
+        let best = FullSearch.search(&window, &[]);
+        assert_eq!((best.mv_x, best.mv_y, best.cost), (5, -3, 4 * (1 + 2 + 2)));
+    }
+
+    #[test]
+    fn search_stays_in_window() {
+        let reference = smooth(0, 0);
+        let original = smooth(12, 0);
+
+        /* The motion is outside the window */
+        let window = SearchWindow {
+            original: &original,
+            reference: &reference,
+            stride: SIZE,
+            x: 24,
+            y: 24,
+            size: 8,
+            left: 20,
+            top: 20,
+            right: 28,
+            bottom: 28,
+            mv_cost: NO_MV_COST,
+        };
+
+        for method in [
+            SearchMethod::Full,
+            SearchMethod::Diamond,
+            SearchMethod::Hexagon,
+        ] {
+            let best = method.searcher().search(&window, &[]);
+            assert!(best.mv_x >= -4 && best.mv_x <= 4, "{:?}", method);
+            assert!(best.mv_y >= -4 && best.mv_y <= 4, "{:?}", method);
+        }
+    }
+}
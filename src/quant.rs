@@ -1,29 +1,291 @@
+use crate::c63;
 use crate::dsp;
+use crate::rdoq;
+use crate::tables;
 
-fn dct_quantize_row(
+use std::io::{self, BufRead};
+
+/// Fractions from which the magnitude of a level is rounded up, for intra and
+/// inter blocks. Below 0.5 the zero bin is wider than the others, a dead zone
+/// that drops small noisy coefficients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rounding {
+    pub intra: f64,
+    pub inter: f64,
+}
+
+impl Rounding {
+    /// Round to the nearest level
+    pub const NEAREST: Rounding = Rounding {
+        intra: 0.5,
+        inter: 0.5,
+    };
+
+    /// The offsets of the H.264 reference encoder, inter residuals are mostly
+    /// noise
+    pub const DEAD_ZONE: Rounding = Rounding {
+        intra: 1.0 / 3.0,
+        inter: 1.0 / 6.0,
+    };
+
+    pub fn of(&self, intra: bool) -> f64 {
+        return if intra { self.intra } else { self.inter };
+    }
+}
+
+impl Default for Rounding {
+    fn default() -> Rounding {
+        return Rounding::NEAREST;
+    }
+}
+
+/// How the encoder chooses the levels of the coefficients
+#[derive(Clone, Copy, Default)]
+pub struct Quantizer<'a> {
+    pub rounding: Rounding,
+    /// Rate-distortion optimize the levels for the bits of this entropy coder
+    pub rdoq: Option<&'a dyn rdoq::AcBits>,
+}
+
+/// The luma and chroma quantization matrices the tables of each qp are scaled
+/// from, in zig-zag order like the DQT segment
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantMatrices {
+    pub luma: [u8; 64],
+    pub chroma: [u8; 64],
+}
+
+impl Default for QuantMatrices {
+    fn default() -> QuantMatrices {
+        return QuantMatrices {
+            luma: tables::YQUANTTBL_DEF,
+            chroma: tables::UVQUANTTBL_DEF,
+        };
+    }
+}
+
+/// Read quantization matrices from a text file of 64 luma and then 64 chroma
+/// values between 1 and 255, separated by whitespace or commas. The values
+/// are in raster order, 8 rows of 8 from the DC, unless the first word is
+/// `zigzag`. It can also be `raster`. Text after a `#` is a comment.
+pub fn read_qmatrix<R: BufRead>(input: R) -> Result<QuantMatrices, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut words = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let content = line.split('#').next().unwrap_or("");
+        words.extend(
+            content
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|w| !w.is_empty())
+                .map(|w| w.to_string()),
+        );
+    }
+
+    let mut zigzag = false;
+    if let Some(order) = words.first().filter(|w| w.parse::<u8>().is_err()) {
+        zigzag = match order.as_str() {
+            "zigzag" => true,
+            "raster" => false,
+            _ => return Err(invalid("Unknown quantization matrix order")),
+        };
+        words.remove(0);
+    }
+
+    if words.len() != 128 {
+        return Err(invalid(
+            "Quantization matrices need 64 luma and 64 chroma values",
+        ));
+    }
+
+    let mut values = [0u8; 128];
+    for (value, word) in values.iter_mut().zip(&words) {
+        *value = match word.parse::<u8>() {
+            Ok(v) if v > 0 => v,
+            _ => return Err(invalid("Quantization matrix values must be 1 to 255")),
+        };
+    }
+
+    /* The tables are zig-zagged like the coefficients */
+    let matrix = |values: &[u8]| {
+        let mut out = [0u8; 64];
+        for (i, q) in out.iter_mut().enumerate() {
+            *q = match zigzag {
+                true => values[i],
+                false => values[(tables::ZIGZAG_V[i] * 8 + tables::ZIGZAG_U[i]) as usize],
+            };
+        }
+        out
+    };
+
+    return Ok(QuantMatrices {
+        luma: matrix(&values[..64]),
+        chroma: matrix(&values[64..]),
+    });
+}
+
+/// `table` with the quantizer steps scaled for a macroblock `delta_qp` sixths of
+/// an octave finer, positive deltas are finer like higher qps
+pub fn delta_qp_table(table: &[u8; 64], delta_qp: i8) -> [u8; 64] {
+    let scale = 2f64.powf(-delta_qp as f64 / 6.0);
+    let mut out = [0u8; 64];
+    for (q, &t) in out.iter_mut().zip(table) {
+        *q = (t as f64 * scale).round().clamp(1.0, 255.0) as u8;
+    }
+    return out;
+}
+
+/// The quantization tables of the blocks of a plane, the table of the frame
+/// adjusted by the delta qp of the macroblock of each block
+pub struct PlaneQuantization<'a> {
+    tables: Vec<[u8; 64]>, // for each delta qp from -MAX_DELTA_QP
+    delta_qps: &'a [i8],   // per 16x16 macroblock
+    mb_cols: usize,
+    mb_size: usize, // pixels of the plane per macroblock side
+}
+
+impl<'a> PlaneQuantization<'a> {
+    /// the quantization of the plane `cc` of a frame with the macroblock
+    /// `delta_qps` of `Frame::delta_qps`, `mb_cols` macroblocks wide
+    pub fn new(
+        table: &[u8; 64],
+        delta_qps: &'a [i8],
+        mb_cols: usize,
+        cc: usize,
+    ) -> PlaneQuantization<'a> {
+        let blocks = match cc {
+            c63::COLOR_COMPONENT_Y => c63::COMPONENT_SIZE_YX,
+            c63::COLOR_COMPONENT_U => c63::COMPONENT_SIZE_UX,
+            _ => c63::COMPONENT_SIZE_VX,
+        };
+        return PlaneQuantization {
+            tables: (-c63::MAX_DELTA_QP..=c63::MAX_DELTA_QP)
+                .map(|delta_qp| delta_qp_table(table, delta_qp))
+                .collect(),
+            delta_qps: delta_qps,
+            mb_cols: mb_cols,
+            mb_size: blocks as usize * 8,
+        };
+    }
+
+    /// the table of the block at `x`, `y` of the plane
+    pub fn block(&self, x: usize, y: usize) -> &[u8; 64] {
+        let delta_qp = self.delta_qps[y / self.mb_size * self.mb_cols + x / self.mb_size];
+        return &self.tables[(delta_qp + c63::MAX_DELTA_QP) as usize];
+    }
+}
+
+/// the quantization of each plane of a frame `ypw` luma pixels wide, with the
+/// tables of the frame and the macroblock `delta_qps`
+pub fn frame_quantization<'a>(
+    quanttbl: &[[u8; 64]; c63::COLOR_COMPONENTS],
+    delta_qps: &'a [i8],
+    ypw: i32,
+) -> [PlaneQuantization<'a>; c63::COLOR_COMPONENTS] {
+    let mb_cols = (ypw / (8 * c63::COMPONENT_SIZE_YX as i32)) as usize;
+    return [
+        c63::COLOR_COMPONENT_Y,
+        c63::COLOR_COMPONENT_U,
+        c63::COLOR_COMPONENT_V,
+    ]
+    .map(|cc| PlaneQuantization::new(&quanttbl[cc], delta_qps, mb_cols, cc));
+}
+
+/// transform and quantize the residual of the 8x8 block at the start of
+/// `in_data` and `prediction`, which have `stride` pixels per line
+pub fn dct_quantize_block(
     in_data: &[u8],
     prediction: &[u8],
-    w: i32,
-    _h: i32,
+    stride: usize,
     out_data: &mut [i16],
     quantization: &[u8; 64],
+    quantizer: &Quantizer,
+    intra: bool,
 ) {
     let mut block = [0i16; 64];
 
-    /* Perform the DCT and quantization */
-    for x in (0..w as usize).step_by(8) {
-        for i in 0..8usize {
-            for j in 0..8usize {
-                let data_index = i * w as usize + j + x;
-                block[(i * 8 + j) as usize] =
-                    in_data[data_index] as i16 - prediction[data_index] as i16;
+    for i in 0..8usize {
+        for j in 0..8usize {
+            let data_index = i * stride + j;
+            block[i * 8 + j] = in_data[data_index] as i16 - prediction[data_index] as i16;
+        }
+    }
+
+    let rounding = quantizer.rounding.of(intra);
+    match quantizer.rdoq {
+        Some(ac_bits) => {
+            let mut dct = [0f32; 64];
+            dsp::dct_block_8x8(&block, &mut dct);
+            let lambda = rdoq::lambda(quantization);
+            rdoq::quantize_block(
+                &dct,
+                &mut out_data[..64],
+                quantization,
+                rounding,
+                ac_bits,
+                lambda,
+            );
+        }
+        None => dsp::dct_quant_block_8x8(&block, &mut out_data[..64], quantization, rounding),
+    }
+}
+
+/// reconstruct the 8x8 block at the start of `out_data` from its prediction
+/// and quantized coefficients
+pub fn dequantize_idct_block(
+    in_data: &[i16],
+    prediction: &[u8],
+    stride: usize,
+    out_data: &mut [u8],
+    quantization: &[u8; 64],
+) {
+    let mut block = [0i16; 64];
+
+    dsp::dequant_idct_block_8x8(in_data, &mut block, quantization);
+
+    for i in 0..8 {
+        for j in 0..8 {
+            /* Add prediction block. Note: DCT is not precise -
+            Clamp to legal values */
+            let frame_index = i * stride + j;
+            let mut tmp = block[i * 8 + j] + prediction[frame_index] as i16;
+
+            if tmp < 0 {
+                tmp = 0
+            } else if tmp > 255 {
+                tmp = 255
             }
+
+            out_data[frame_index] = tmp as u8;
         }
+    }
+}
 
+fn dct_quantize_row(
+    in_data: &[u8],
+    prediction: &[u8],
+    w: i32,
+    y: usize,
+    out_data: &mut [i16],
+    quantization: &PlaneQuantization,
+    mbs: &[c63::MacroBlock],
+    quantizer: &Quantizer,
+) {
+    /* Perform the DCT and quantization */
+    for x in (0..w as usize).step_by(8) {
         /* Store MBs linear in memory, i.e. the 64 coefficients are stored
         continous. This allows us to ignore stride in DCT/iDCT and other
         functions. */
-        dsp::dct_quant_block_8x8(&block, &mut out_data[x..x + 64 as usize], quantization);
+        dct_quantize_block(
+            &in_data[x..],
+            &prediction[x..],
+            w as usize,
+            &mut out_data[x * 8..x * 8 + 64],
+            quantization.block(x, y),
+            quantizer,
+            !mbs[x / 8].use_mv,
+        );
     }
 }
 
@@ -31,32 +293,19 @@ fn dequantize_idct_row(
     in_data: &[i16],
     prediction: &[u8],
     w: i32,
-    _h: i32,
+    y: usize,
     out_data: &mut [u8],
-    quantization: &[u8; 64],
+    quantization: &PlaneQuantization,
 ) {
-    let mut block = [0i16; 64];
-
     /* Perform the dequantization and iDCT */
     for x in (0..w as usize).step_by(8) {
-        dsp::dequant_idct_block_8x8(&in_data[x * 8..], &mut block, quantization);
-
-        for i in 0..8 {
-            for j in 0..8 {
-                /* Add prediction block. Note: DCT is not precise -
-                Clamp to legal values */
-                let frame_index = i * w as usize + j + x;
-                let mut tmp = block[i * 8 + j] + prediction[frame_index] as i16;
-
-                if tmp < 0 {
-                    tmp = 0
-                } else if tmp > 255 {
-                    tmp = 255
-                }
-
-                out_data[frame_index] = tmp as u8;
-            }
-        }
+        dequantize_idct_block(
+            &in_data[x * 8..],
+            &prediction[x..],
+            w as usize,
+            &mut out_data[x..],
+            quantization.block(x, y),
+        );
     }
 }
 
@@ -66,7 +315,9 @@ pub fn dct_quantize(
     width: i32,
     height: i32,
     out_data: &mut [i16],
-    quantization: &[u8; 64],
+    quantization: &PlaneQuantization,
+    mbs: &[c63::MacroBlock],
+    quantizer: &Quantizer,
 ) {
     for y in (0..height as usize).step_by(8) {
         let data_index = y * width as usize;
@@ -74,9 +325,11 @@ pub fn dct_quantize(
             &in_data[data_index..],
             &prediction[data_index..],
             width,
-            height,
+            y,
             &mut out_data[data_index..],
             quantization,
+            &mbs[y / 8 * width as usize / 8..],
+            quantizer,
         );
     }
 }
@@ -87,7 +340,7 @@ pub fn dequantize_idct(
     width: i32,
     height: i32,
     out_data: &mut [u8],
-    quantization: &[u8; 64],
+    quantization: &PlaneQuantization,
 ) {
     for y in (0..height as usize).step_by(8) {
         let data_index = y * width as usize;
@@ -95,9 +348,88 @@ pub fn dequantize_idct(
             &in_data[data_index..],
             &prediction[data_index..],
             width,
-            height,
+            y,
             &mut out_data[data_index..],
             quantization,
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// `matrices` as text, in raster order unless `zigzag`
+    fn qmatrix_text(matrices: &QuantMatrices, zigzag: bool) -> String {
+        let mut text = String::from(if zigzag { "zigzag\n" } else { "raster\n" });
+        for matrix in [&matrices.luma, &matrices.chroma] {
+            let mut raster = [0u8; 64];
+            for i in 0..64 {
+                let pos = (tables::ZIGZAG_V[i] * 8 + tables::ZIGZAG_U[i]) as usize;
+                raster[pos] = matrix[i];
+            }
+            let values = if zigzag { matrix } else { &raster };
+            for row in values.chunks(8) {
+                let row: Vec<String> = row.iter().map(|v| v.to_string()).collect();
+                text += &(row.join(", ") + "\n");
+            }
+        }
+        return text;
+    }
+
+    #[test]
+    fn qmatrix_in_either_order() {
+        let defaults = QuantMatrices::default();
+        for zigzag in [false, true] {
+            let text = qmatrix_text(&defaults, zigzag);
+            assert_eq!(read_qmatrix(text.as_bytes()).unwrap(), defaults);
+        }
+
+        /* The first row of the raster is the top row of the block, it is not
+        the start of the zig-zag */
+        let raster = read_qmatrix(qmatrix_text(&defaults, false).as_bytes()).unwrap();
+        let text = qmatrix_text(&defaults, false).replacen("raster", "zigzag", 1);
+        assert_ne!(read_qmatrix(text.as_bytes()).unwrap(), raster);
+        let text = qmatrix_text(&defaults, false).replacen("raster", "", 1);
+        assert_eq!(read_qmatrix(text.as_bytes()).unwrap(), raster);
+
+        let flat = "# flat\n".to_string() + &"2 # two\n".repeat(128);
+        let flat = read_qmatrix(flat.as_bytes()).unwrap();
+        assert_eq!((flat.luma, flat.chroma), ([2; 64], [2; 64]));
+    }
+
+    #[test]
+    fn invalid_qmatrix_is_an_error() {
+        for text in [
+            "2 ".repeat(127),
+            "2 ".repeat(129),
+            "0 ".repeat(128),
+            "256 ".repeat(128),
+            "diagonal ".to_string() + &"2 ".repeat(128),
+            "x ".repeat(128),
+        ] {
+            let err = read_qmatrix(text.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn dead_zone_drops_noise() {
+        let size_and_psnr_at = |qp: u8, rounding: Rounding| {
+            let mut ctx = context((64, 48), c63::CodingTools::new());
+            ctx.set_qp(qp);
+            ctx.rounding = rounding;
+            return size_and_psnr(ctx, noisy_image, 4);
+        };
+
+        /* The noise is not worth its bits, at the same qp the stream is
+        smaller and a finer qp has both a smaller stream and a higher PSNR
+        than rounding to nearest */
+        let nearest = size_and_psnr_at(40, Rounding::NEAREST);
+        let dead_zone = size_and_psnr_at(40, Rounding::DEAD_ZONE);
+        assert!(dead_zone.0 < nearest.0);
+        let finer = size_and_psnr_at(50, Rounding::DEAD_ZONE);
+        assert!(finer.0 < nearest.0 && finer.1 > nearest.1);
+    }
+}
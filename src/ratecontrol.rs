@@ -0,0 +1,512 @@
+use crate::c63;
+
+use std::io::{self, BufRead, Write};
+
+/* A higher qp gives finer quantization tables, so the size of a frame grows
+with qp. The model used here is bits = complexity * qp^QP_EXPONENT, with the
+complexity estimated separately for key and inter frames from the frames coded
+so far. The size grows slower than qp, the per block overhead does not depend on
+it at all. */
+
+/// Exponent of qp in the size model
+const QP_EXPONENT: f64 = 0.6;
+
+/* In the constant quality mode the quantization follows the complexity of the
+frames like the CRF mode of x264: the qp of a frame is the quality level scaled
+by (REFERENCE_COMPLEXITY / complexity)^(1 - QCOMPRESS), where the complexity is
+the average absolute motion compensated luma residual. Detail in busy frames is
+masked by the motion, so bits are better spent on static frames and keyframes
+which later frames predict from. */
+
+/// Complexity of a frame that is coded at the quality level
+const REFERENCE_COMPLEXITY: f64 = 4.0;
+
+/// How much of the complexity is compensated, 1.0 gives a constant qp
+const QCOMPRESS: f64 = 0.6;
+
+/// Weight of the newest frame in the average complexity
+const QUALITY_COMPLEXITY_WEIGHT: f64 = 0.3;
+
+/// Ratio of the qp of keyframes and inter frames
+const KEYFRAME_QP_RATIO: f64 = 1.4;
+
+/// Share of the bits of an average frame given to a keyframe
+const KEYFRAME_BOOST: f64 = 3.0;
+
+/// Seconds over which the ABR mode pays back the error of the bits spent so far
+const ABR_WINDOW: f64 = 2.0;
+
+/// Weight of the newest frame in the complexity estimate
+const COMPLEXITY_WEIGHT: f64 = 0.5;
+
+/// qp of a frame relative to the quality level, for a given temporal
+/// complexity
+fn quality_scale(keyframe: bool, complexity: f64) -> f64 {
+    if keyframe {
+        return KEYFRAME_QP_RATIO;
+    }
+
+    /* Static frames would otherwise get an unbounded qp */
+    let complexity = complexity.max(REFERENCE_COMPLEXITY / 8.0);
+    return (REFERENCE_COMPLEXITY / complexity).powf(1.0 - QCOMPRESS);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateControlMode {
+    /// Every frame is coded with the qp of the encoder
+    ConstantQp,
+    /// Average bitrate, the buffer only limits the size of single frames
+    Abr,
+    /// Constant bitrate, qp is also steered to keep the buffer at its initial
+    /// level of half full
+    Cbr,
+    /// Constant perceptual quality, qp follows the complexity of the frames
+    /// around the quality level
+    ConstantQuality,
+    /// Bits are distributed over the sequence from first pass statistics
+    SecondPass,
+}
+
+/// Statistics of a coded frame, written by the first pass
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameStats {
+    pub keyframe: bool,
+    pub qp: u8,
+    pub bits: usize,
+    pub complexity: f64, // mean absolute motion compensated residual
+}
+
+/// qp and expected size of a frame in the second pass
+struct PlannedFrame {
+    qp: f64,
+    bits: f64,
+}
+
+/// Chooses the qp of each frame to hit a target bitrate
+///
+/// The buffer is a leaky bucket drained at the target bitrate, the size of a
+/// frame is limited so that it never overflows.
+pub struct RateControl {
+    pub mode: RateControlMode,
+
+    pub bitrate: f64,   // bits per second
+    pub framerate: f64, // frames per second
+    pub vbv_size: f64,  // bits
+
+    pub qp_min: u8,
+    pub qp_max: u8,
+
+    pub quality: f64, // qp of an average frame in the constant quality mode
+
+    vbv_fullness: f64,
+    complexity: [f64; 2], // per inter/keyframe, 0 until a frame is coded
+    last_qp: [u8; 2],
+    average_complexity: f64, // of inter frames, 0 until one is seen
+    total_bits: f64,
+    frames: u64,
+
+    /* The frame that frame_qp was last called for */
+    pending: Option<FrameStats>,
+    stats: Vec<FrameStats>,
+
+    plan: Vec<PlannedFrame>,
+}
+
+impl RateControl {
+    pub fn new() -> RateControl {
+        return RateControl {
+            mode: RateControlMode::ConstantQp,
+            bitrate: 0.0,
+            framerate: 25.0,
+            vbv_size: 0.0,
+            qp_min: 1,
+            qp_max: c63::MAX_QP,
+            quality: 0.0,
+            vbv_fullness: 0.0,
+            complexity: [0.0; 2],
+            last_qp: [0; 2],
+            average_complexity: 0.0,
+            total_bits: 0.0,
+            frames: 0,
+            pending: None,
+            stats: Vec::new(),
+            plan: Vec::new(),
+        };
+    }
+
+    /// rate control to `kbps` in `mode`, with a buffer of `vbv_kbits` or 1
+    /// second of data if it is `None`
+    pub fn with_bitrate(
+        mode: RateControlMode,
+        kbps: f64,
+        framerate: f64,
+        vbv_kbits: Option<f64>,
+    ) -> RateControl {
+        let mut rc = RateControl::new();
+        rc.mode = mode;
+        rc.bitrate = kbps * 1000.0;
+        rc.framerate = framerate;
+        rc.vbv_size = vbv_kbits.unwrap_or(kbps) * 1000.0;
+        if mode == RateControlMode::Cbr {
+            rc.vbv_fullness = rc.vbv_size / 2.0;
+        }
+        return rc;
+    }
+
+    /// constant quality around `quality`, in the same scale as qp
+    pub fn with_quality(quality: f64) -> RateControl {
+        let mut rc = RateControl::new();
+        rc.mode = RateControlMode::ConstantQuality;
+        rc.quality = quality;
+        return rc;
+    }
+
+    /// second pass to `kbps`, with the statistics of the first pass
+    ///
+    /// The qp of each frame follows its complexity like in the constant
+    /// quality mode, the quality level is the one that makes the frames add up
+    /// to the target size when scaled with the size model.
+    pub fn second_pass(
+        stats: &[FrameStats],
+        kbps: f64,
+        framerate: f64,
+    ) -> Result<RateControl, &'static str> {
+        if stats.is_empty() {
+            return Err("No frames in the first pass statistics");
+        }
+
+        let mut rc = RateControl::with_bitrate(RateControlMode::SecondPass, kbps, framerate, None);
+        let target = rc.bits_per_frame() * stats.len() as f64;
+
+        let plan = |quality: f64| -> Vec<PlannedFrame> {
+            return stats
+                .iter()
+                .map(|s| {
+                    let qp = (quality * quality_scale(s.keyframe, s.complexity))
+                        .clamp(rc.qp_min as f64, rc.qp_max as f64);
+                    let bits = s.bits as f64 * (qp / s.qp as f64).powf(QP_EXPONENT);
+                    PlannedFrame { qp: qp, bits: bits }
+                })
+                .collect();
+        };
+        let size = |plan: &[PlannedFrame]| plan.iter().map(|p| p.bits).sum::<f64>();
+
+        /* The size grows with the quality level, bisect it in log space */
+        let (mut low, mut high) = ((rc.qp_min as f64).ln() - 2.0, (rc.qp_max as f64).ln());
+        for _ in 0..50 {
+            let mid = (low + high) / 2.0;
+            if size(&plan(mid.exp())) > target {
+                high = mid;
+            } else {
+                low = mid;
+            }
+        }
+
+        rc.plan = plan(low.exp());
+        return Ok(rc);
+    }
+
+    /// statistics of the frames coded so far
+    pub fn stats(&self) -> &[FrameStats] {
+        return &self.stats;
+    }
+
+    fn bits_per_frame(&self) -> f64 {
+        return self.bitrate / self.framerate;
+    }
+
+    /// target size in bits of the next frame
+    fn target_bits(&self, keyframe: bool) -> f64 {
+        let per_frame = self.bits_per_frame();
+        let mut target = per_frame;
+
+        match self.mode {
+            RateControlMode::ConstantQp
+            | RateControlMode::ConstantQuality
+            | RateControlMode::SecondPass => {}
+            RateControlMode::Abr => {
+                let error = per_frame * self.frames as f64 - self.total_bits;
+                target += error / (ABR_WINDOW * self.framerate);
+            }
+            RateControlMode::Cbr => {
+                let error = self.vbv_size / 2.0 - self.vbv_fullness;
+                target += per_frame * error / self.vbv_size;
+            }
+        }
+
+        if keyframe {
+            target *= KEYFRAME_BOOST;
+        }
+
+        /* Never overflow the buffer, and never starve a frame completely */
+        let room = self.vbv_size - self.vbv_fullness;
+        return target.min(0.9 * room).max(0.1 * per_frame);
+    }
+
+    /// qp of a frame in the constant quality mode
+    fn quality_qp(&mut self, keyframe: bool, complexity: f64) -> f64 {
+        if keyframe {
+            return self.quality * quality_scale(true, complexity);
+        }
+
+        self.average_complexity = if self.average_complexity > 0.0 {
+            (1.0 - QUALITY_COMPLEXITY_WEIGHT) * self.average_complexity
+                + QUALITY_COMPLEXITY_WEIGHT * complexity
+        } else {
+            complexity
+        };
+
+        return self.quality * quality_scale(false, self.average_complexity);
+    }
+
+    /// qp of a frame in the bitrate modes
+    fn bitrate_qp(&self, keyframe: bool, qp: u8) -> f64 {
+        let t = keyframe as usize;
+        let complexity = if self.complexity[t] > 0.0 {
+            self.complexity[t]
+        } else if self.complexity[1 - t] > 0.0 {
+            /* Keyframes are roughly as large as the boost given to them */
+            let scale = if keyframe {
+                KEYFRAME_BOOST
+            } else {
+                1.0 / KEYFRAME_BOOST
+            };
+            self.complexity[1 - t] * scale
+        } else {
+            0.0
+        };
+
+        let mut new_qp = if complexity > 0.0 {
+            (self.target_bits(keyframe) / complexity).powf(1.0 / QP_EXPONENT)
+        } else {
+            qp as f64
+        };
+
+        /* Limit the change from the last frame of the same type, the model is
+        only accurate close to the qp it was estimated at */
+        if self.last_qp[t] > 0 {
+            let last = self.last_qp[t] as f64;
+            new_qp = new_qp.clamp(last / 2.0, last * 2.0);
+        }
+
+        return new_qp;
+    }
+
+    /// qp of a frame in the second pass, the planned qp corrected for the
+    /// error of the bits spent so far
+    fn second_pass_qp(&self, qp: u8) -> f64 {
+        let n = self.frames as usize;
+        if n >= self.plan.len() {
+            /* More frames than in the first pass */
+            return match self.plan.last() {
+                Some(planned) => planned.qp,
+                None => qp as f64,
+            };
+        }
+
+        let planned_bits: f64 = self.plan[..n].iter().map(|p| p.bits).sum();
+        let mut correction = 1.0;
+        if self.total_bits > 0.0 && planned_bits > 0.0 {
+            correction = (planned_bits / self.total_bits)
+                .powf(1.0 / QP_EXPONENT)
+                .clamp(0.5, 2.0);
+        }
+
+        return self.plan[n].qp * correction;
+    }
+
+    /// qp of the next frame, `qp` is used while there is nothing to base the
+    /// estimate on. `complexity` is the temporal complexity of inter frames.
+    pub fn frame_qp(&mut self, keyframe: bool, qp: u8, complexity: f64) -> u8 {
+        let new_qp = match self.mode {
+            RateControlMode::ConstantQp => qp,
+            mode => {
+                let new_qp = match mode {
+                    RateControlMode::ConstantQuality => self.quality_qp(keyframe, complexity),
+                    RateControlMode::SecondPass => self.second_pass_qp(qp),
+                    _ => self.bitrate_qp(keyframe, qp),
+                };
+                (new_qp.round() as i32).clamp(self.qp_min as i32, self.qp_max as i32) as u8
+            }
+        };
+
+        self.pending = Some(FrameStats {
+            keyframe: keyframe,
+            qp: new_qp,
+            bits: 0,
+            complexity: complexity,
+        });
+
+        return new_qp;
+    }
+
+    /// account for the last frame, which was coded in `bits`
+    pub fn update(&mut self, bits: usize) {
+        let bits = bits as f64;
+
+        if let Some(mut stats) = self.pending.take() {
+            let t = stats.keyframe as usize;
+            let complexity = bits / (stats.qp as f64).powf(QP_EXPONENT);
+            self.complexity[t] = if self.complexity[t] > 0.0 {
+                (1.0 - COMPLEXITY_WEIGHT) * self.complexity[t] + COMPLEXITY_WEIGHT * complexity
+            } else {
+                complexity
+            };
+            self.last_qp[t] = stats.qp;
+
+            stats.bits = bits as usize;
+            self.stats.push(stats);
+        }
+
+        self.total_bits += bits;
+        self.frames += 1;
+        self.vbv_fullness = (self.vbv_fullness + bits - self.bits_per_frame()).max(0.0);
+    }
+
+    /// bits in the buffer after the last frame
+    pub fn vbv_fullness(&self) -> f64 {
+        return self.vbv_fullness;
+    }
+}
+
+/// write first pass statistics, one line per frame
+pub fn write_stats<W: Write>(output: &mut W, stats: &[FrameStats]) -> Result<(), io::Error> {
+    writeln!(output, "# keyframe qp bits complexity")?;
+    for s in stats.iter() {
+        writeln!(
+            output,
+            "{} {} {} {}",
+            s.keyframe as u8, s.qp, s.bits, s.complexity
+        )?;
+    }
+    return Ok(());
+}
+
+pub fn read_stats<R: BufRead>(input: R) -> Result<Vec<FrameStats>, io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid first pass statistics");
+
+    let mut stats = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err(invalid());
+        }
+        stats.push(FrameStats {
+            keyframe: fields[0] == "1",
+            qp: fields[1].parse().map_err(|_| invalid())?,
+            bits: fields[2].parse().map_err(|_| invalid())?,
+            complexity: fields[3].parse().map_err(|_| invalid())?,
+        });
+    }
+
+    return Ok(stats);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// code `frames` frames of a sequence where a frame of `qp` takes
+    /// `complexity * sqrt(qp)` bits, keyframes 4 times as many, returning the
+    /// bits per second
+    fn simulate(rc: &mut RateControl, frames: u64, complexity: impl Fn(u64) -> f64) -> f64 {
+        let mut total = 0.0;
+        for n in 0..frames {
+            let keyframe = n % 50 == 0;
+            let qp = rc.frame_qp(keyframe, 25, 0.0);
+            let scale = if keyframe { 4.0 } else { 1.0 };
+            let bits = complexity(n) * scale * (qp as f64).sqrt();
+            rc.update(bits as usize);
+            total += bits;
+            assert!(rc.vbv_fullness() <= rc.vbv_size);
+        }
+        return total / (frames as f64 / rc.framerate);
+    }
+
+    #[test]
+    fn constant_qp_is_unchanged() {
+        let mut rc = RateControl::new();
+        assert_eq!(rc.frame_qp(true, 25, 0.0), 25);
+        rc.update(100000);
+        assert_eq!(rc.frame_qp(false, 25, 10.0), 25);
+    }
+
+    #[test]
+    fn constant_quality_follows_complexity() {
+        let mut rc = RateControl::with_quality(25.0);
+        assert!(rc.frame_qp(true, 25, 0.0) > 25);
+
+        /* Average frames are coded at the quality level */
+        for _ in 0..20 {
+            assert_eq!(rc.frame_qp(false, 25, REFERENCE_COMPLEXITY), 25);
+        }
+
+        /* Busy frames are quantized coarser, static frames finer */
+        let mut busy_qp = 25;
+        for _ in 0..20 {
+            busy_qp = rc.frame_qp(false, 25, 4.0 * REFERENCE_COMPLEXITY);
+        }
+        let mut static_qp = 25;
+        for _ in 0..20 {
+            static_qp = rc.frame_qp(false, 25, 0.0);
+        }
+        assert!(busy_qp < 25);
+        assert!(static_qp > 25);
+    }
+
+    #[test]
+    fn second_pass_hits_target() {
+        /* First pass at a constant qp, where the busy second half of the
+        sequence takes most of the bits */
+        let model = |n: u64| if n < 250 { 5000.0 } else { 12000.0 };
+        let mut first = RateControl::new();
+        for n in 0..500 {
+            let keyframe = n % 50 == 0;
+            let qp = first.frame_qp(keyframe, 25, (model(n) / 1000.0) as f64);
+            let scale = if keyframe { 4.0 } else { 1.0 };
+            first.update((model(n) * scale * (qp as f64).sqrt()) as usize);
+        }
+
+        let mut output = Vec::new();
+        write_stats(&mut output, first.stats()).unwrap();
+        let stats = read_stats(&output[..]).unwrap();
+        assert_eq!(stats, first.stats());
+
+        let mut rc = RateControl::second_pass(&stats, 1000.0, 25.0).unwrap();
+        let mut total = 0.0;
+        let mut qps = Vec::new();
+        for (n, s) in stats.iter().enumerate() {
+            let qp = rc.frame_qp(s.keyframe, 25, s.complexity);
+            let scale = if s.keyframe { 4.0 } else { 1.0 };
+            let bits = model(n as u64) * scale * (qp as f64).sqrt();
+            rc.update(bits as usize);
+            total += bits;
+            qps.push(qp);
+        }
+
+        let bitrate = total / (stats.len() as f64 / 25.0);
+        assert!((bitrate - 1000e3).abs() < 0.03 * 1000e3, "{}", bitrate);
+
+        /* The busy frames are quantized coarser */
+        assert!(qps[100] > qps[400]);
+    }
+
+    #[test]
+    fn abr_hits_target() {
+        let mut rc = RateControl::with_bitrate(RateControlMode::Abr, 1000.0, 25.0, None);
+        let bitrate = simulate(&mut rc, 500, |n| if n < 250 { 5000.0 } else { 12000.0 });
+        assert!((bitrate - 1000e3).abs() < 0.05 * 1000e3, "{}", bitrate);
+    }
+
+    #[test]
+    fn cbr_keeps_buffer_bounded() {
+        let mut rc = RateControl::with_bitrate(RateControlMode::Cbr, 500.0, 25.0, Some(500.0));
+        let bitrate = simulate(&mut rc, 500, |n| 3000.0 + 3000.0 * ((n / 30) % 2) as f64);
+        assert!((bitrate - 500e3).abs() < 0.1 * 500e3, "{}", bitrate);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::bitstream;
+use crate::dsp;
+use crate::tables;
+
+/// Lagrange multiplier per squared quantizer step, the squared error in pixels
+/// one bit is worth
+const LAMBDA_SCALE: f64 = 0.006;
+
+/// the lambda of a block quantized with `quant_tbl`
+///
+/// The tables are scaled by the qp, so lambda follows the squared mean step of
+/// the AC coefficients.
+pub fn lambda(quant_tbl: &[u8; 64]) -> f64 {
+    let step = quant_tbl[1..].iter().map(|&q| q as f64).sum::<f64>() / 63.0;
+    return LAMBDA_SCALE * step * step;
+}
+
+/// The bits an entropy coder spends on the AC coefficients of a block
+pub trait AcBits {
+    /// bits of the nonzero AC coefficient `level` at zig-zag position `i`,
+    /// after the zeros since the nonzero coefficient at `prev`, 0 for the DC
+    fn coefficient(&self, prev: usize, i: usize, level: i16) -> f64;
+
+    /// bits of ending the block after its last nonzero coefficient at `last`,
+    /// 0 if all AC coefficients are zero
+    fn end(&self, last: usize) -> f64;
+}
+
+/* Levels that need more bits than c63::HUFF_AC_SIZE - 1 have no code in the
+AC tables, they cannot be written at all */
+fn symbol_size(ac_table: &bitstream::HuffmanTable, symbol: u8) -> f64 {
+    match ac_table.code_size(symbol) {
+        0 => f64::INFINITY,
+        size => size as f64,
+    }
+}
+
+/// The code sizes of a Huffman AC table
+impl AcBits for bitstream::HuffmanTable {
+    fn coefficient(&self, prev: usize, i: usize, level: i16) -> f64 {
+        let run = i - prev - 1;
+        let size = bitstream::bit_width(level);
+        let zrl = (run / 16) as f64 * symbol_size(self, 0xF0);
+        return zrl + symbol_size(self, (((run % 16) as u8) << 4) | size) + size as f64;
+    }
+
+    /* The EOB is left out after the last position */
+    fn end(&self, last: usize) -> f64 {
+        return if last == 63 {
+            0.0
+        } else {
+            symbol_size(self, 0x00)
+        };
+    }
+}
+
+/// Quantize the scaled DCT of a block (from `dsp::dct_block_8x8`) into
+/// zig-zagged coefficients, choosing the AC levels that minimize distortion +
+/// `lambda` * bits with the bits of `ac_bits`
+///
+/// Each AC coefficient is rounded with `rounding`, moved one step towards zero
+/// or zeroed. The trellis has one state per position of the last nonzero
+/// coefficient, the cost of a run only depends on where the previous one was.
+/// The DC is rounded, it is coded as a difference to the previous block.
+pub fn quantize_block(
+    dct: &[f32; 64],
+    out_data: &mut [i16],
+    quant_tbl: &[u8; 64],
+    rounding: f64,
+    ac_bits: &dyn AcBits,
+    lambda: f64,
+) {
+    /* Unrounded levels in zig-zag order, the same division as
+    dsp::quantize_block */
+    let mut levels = [0f64; 64];
+    for (zigzag, level) in levels.iter_mut().enumerate() {
+        let u = tables::ZIGZAG_U[zigzag] as usize;
+        let v = tables::ZIGZAG_V[zigzag] as usize;
+        *level = (dct[v * 8 + u] as f64 / 4.0f64) / quant_tbl[zigzag] as f64;
+    }
+
+    /* The transform is orthonormal, an error of one level is an error of the
+    table entry in the pixels */
+    let distortion = |i: usize, level: i16| {
+        let error = (levels[i] - level as f64) * quant_tbl[i] as f64;
+        error * error
+    };
+
+    /* Distortion of zeroing the coefficients before each position */
+    let mut zeroed = [0f64; 65];
+    for i in 1..64 {
+        zeroed[i + 1] = zeroed[i] + distortion(i, 0);
+    }
+
+    /* cost[i]: best cost of the coefficients up to i with the last nonzero one
+    at i, 0 is the DC */
+    let mut cost = [f64::INFINITY; 64];
+    let mut choice = [(0i16, 0usize); 64];
+    cost[0] = 0.0;
+
+    for i in 1..64 {
+        let rounded = dsp::quantize_level(levels[i], rounding) as i16;
+        if rounded == 0 {
+            continue;
+        }
+
+        for level in [rounded, rounded - rounded.signum()] {
+            if level == 0 {
+                continue;
+            }
+            for prev in 0..i {
+                if cost[prev].is_infinite() {
+                    continue;
+                }
+                let bits = ac_bits.coefficient(prev, i, level);
+                let c = cost[prev] + zeroed[i] - zeroed[prev + 1]
+                    + distortion(i, level)
+                    + lambda * bits;
+                if c < cost[i] {
+                    cost[i] = c;
+                    choice[i] = (level, prev);
+                }
+            }
+        }
+    }
+
+    let mut best = (f64::INFINITY, 0);
+    for last in 0..64 {
+        let c = cost[last] + zeroed[64] - zeroed[last + 1] + lambda * ac_bits.end(last);
+        if c < best.0 {
+            best = (c, last);
+        }
+    }
+
+    out_data[..64].fill(0);
+    out_data[0] = dsp::quantize_level(levels[0], rounding) as i16;
+    let mut i = best.1;
+    while i > 0 {
+        out_data[i] = choice[i].0;
+        i = choice[i].1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arith;
+    use crate::c63;
+    use crate::testing::*;
+
+    /// the levels the plain quantizer would give `levels`
+    fn scaled_dct(levels: &[f32; 64], quant_tbl: &[u8; 64]) -> [f32; 64] {
+        let mut dct = [0f32; 64];
+        for zigzag in 0..64 {
+            let u = tables::ZIGZAG_U[zigzag] as usize;
+            let v = tables::ZIGZAG_V[zigzag] as usize;
+            dct[v * 8 + u] = levels[zigzag] * 4.0 * quant_tbl[zigzag] as f32;
+        }
+        return dct;
+    }
+
+    #[test]
+    fn lone_trailing_coefficients_are_dropped() {
+        let quant_tbl = [16u8; 64];
+        let ac_table = bitstream::HuffmanTable::ac_default(0);
+
+        for ac_bits in [&ac_table as &dyn AcBits, &arith::AcBins] {
+            /* Big low frequencies are kept, a barely rounded up coefficient
+            far down the block is not worth its run */
+            let mut levels = [0f32; 64];
+            levels[0] = 12.0;
+            levels[1] = -7.0;
+            levels[2] = 5.0;
+            levels[50] = 0.6;
+            let dct = scaled_dct(&levels, &quant_tbl);
+
+            let mut out = [0i16; 64];
+            quantize_block(&dct, &mut out, &quant_tbl, 0.5, ac_bits, 0.0);
+            assert_eq!((out[0], out[1], out[2], out[50]), (12, -7, 5, 1));
+
+            quantize_block(&dct, &mut out, &quant_tbl, 0.5, ac_bits, lambda(&quant_tbl));
+            assert_eq!((out[0], out[1], out[2], out[50]), (12, -7, 5, 0));
+
+            /* Without the bits the rounded levels are the best */
+            levels[50] = 2.4;
+            levels[7] = -1.5;
+            let dct = scaled_dct(&levels, &quant_tbl);
+            quantize_block(&dct, &mut out, &quant_tbl, 0.5, ac_bits, 0.0);
+            let mut rounded = [0i16; 64];
+            for i in 0..64 {
+                rounded[i] = (levels[i] as f64).round() as i16;
+            }
+            assert_eq!(out, rounded);
+        }
+    }
+
+    #[test]
+    fn streams_are_smaller() {
+        for intra_prediction in [false, true] {
+            let tools = c63::CodingTools {
+                intra_prediction,
+                ..c63::CodingTools::new()
+            };
+            let size_of = |rdoq: bool| {
+                return size_and_psnr_of_each_coder(
+                    SMALL,
+                    tools,
+                    |ctx| ctx.rdoq = rdoq,
+                    panning_color_image,
+                    3,
+                );
+            };
+            for (rdoq, plain) in size_of(true).iter().zip(size_of(false).iter()) {
+                assert!(rdoq.0 < plain.0);
+            }
+        }
+
+        /* Little of the quality is given up for the bits */
+        let psnr_of = |rdoq: bool| {
+            let mut ctx = context(LARGE, c63::CodingTools::new());
+            ctx.rdoq = rdoq;
+            return size_and_psnr(ctx, panning_image, 1).1;
+        };
+        assert!(psnr_of(true) > psnr_of(false) - 1.0);
+    }
+}
@@ -36,6 +36,12 @@ pub const DCVLC_DATA: [[u8; 12]; 2] = [
     [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
 ];
 
+// As in the original C source. Two luma entries differ from the codes that
+// ACVLC_NUM_BY_LENGTH and ACVLC_DATA give them: run 0 size 10 duplicates the
+// code of run 0 size 9 (65410 instead of 65411), and run 3 size 3 is 8181, which
+// is 4085 once masked to its 12 bits. The C encoder writes the duplicate code,
+// so a decoder reads run 0 size 10 as size 9. Streams with a C63X segment use
+// the codes of the DHT segment instead, see HuffmanTables::canonical.
 pub const ACVLC: [[[u16; 11]; 16]; 2] = [
     [
         [10, 0, 1, 4, 11, 26, 120, 248, 1014, 65410, 65410],
@@ -205,9 +211,10 @@ pub const ACVLC_DATA: [[u8; 162]; 2] = [
 ];
 
 // These are just copied from DC. Should be generated from relevant statistics
-pub const MVVLC: [u16; 8] = [0, 2, 3, 4, 5, 6, 14, 30];
+pub const MVVLC: [u16; 9] = [0, 2, 3, 4, 5, 6, 14, 30, 62];
 
-pub const MVVLC_SIZE: [u8; 8] = [2, 3, 3, 3, 3, 3, 4, 5];
+/* Size 8 is only used by differences of predicted motion vectors */
+pub const MVVLC_SIZE: [u8; 9] = [2, 3, 3, 3, 3, 3, 4, 5, 6];
 
 pub const ZIGZAG_U: [u8; 64] = [
     0, 1, 0, 0, 1, 2, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 6, 5, 4,
@@ -0,0 +1,309 @@
+use crate::decode::{decode_frame, next_display_image, DecodeContext};
+use crate::encode_context::{self, EncodeContext};
+use crate::{c63, c63_write};
+
+/// path of a file in testdata, see testdata/README.md
+pub fn testdata(name: &str) -> std::path::PathBuf {
+    return [env!("CARGO_MANIFEST_DIR"), "testdata", name]
+        .iter()
+        .collect();
+}
+
+/// the image of frame `frame` of a test input for an encoder
+pub type Image = fn(&EncodeContext, i32) -> c63::YUV;
+
+/// a size that has blocks on the edges of the padding
+pub const SMALL: (i32, i32) = (40, 24);
+/// a size where the savings of a tool are more than its APP0 segment
+pub const LARGE: (i32, i32) = (128, 64);
+/// QCIF, the smallest of the common video sizes
+pub const QCIF: (i32, i32) = (176, 144);
+
+pub const ENTROPY_CODERS: [c63::EntropyCoder; 2] =
+    [c63::EntropyCoder::Huffman, c63::EntropyCoder::Arithmetic];
+
+/// an encoder of `size` with the coding `tools`
+pub fn context((width, height): (i32, i32), tools: c63::CodingTools) -> EncodeContext {
+    let mut ctx = EncodeContext::new(width, height).unwrap();
+    ctx.coding_tools = tools;
+    return ctx;
+}
+
+/// a moving gradient with some texture, so that frames have motion vectors
+/// and nonzero coefficients
+pub fn test_image(ctx: &EncodeContext, frame: i32) -> c63::YUV {
+    let mut image = c63::YUV::new(
+        (ctx.ypw * ctx.yph) as usize,
+        (ctx.upw * ctx.uph) as usize,
+        (ctx.vpw * ctx.vph) as usize,
+    );
+    for y in 0..ctx.yph {
+        for x in 0..ctx.ypw {
+            let v = (x + 2 * frame) * 3 + y * 2 + ((x * y + frame) % 7) * 5;
+            image.y[(y * ctx.ypw + x) as usize] = (v % 256) as u8;
+        }
+    }
+    for y in 0..ctx.uph {
+        for x in 0..ctx.upw {
+            let i = (y * ctx.upw + x) as usize;
+            image.u[i] = (100 + x + frame) as u8;
+            image.v[i] = (150 - y + frame) as u8;
+        }
+    }
+    return image;
+}
+
+pub fn static_image(ctx: &EncodeContext, _frame: i32) -> c63::YUV {
+    return test_image(ctx, 0);
+}
+
+/// texture moving right by 2 pixels per frame, every block has the same
+/// motion
+pub fn panning_image(ctx: &EncodeContext, frame: i32) -> c63::YUV {
+    let mut image = test_image(ctx, 0);
+    for y in 0..ctx.yph {
+        for x in 0..ctx.ypw {
+            let u = x + 2 * frame;
+            let v = 128.0 + 60.0 * (u as f64 / 5.0).sin() * (y as f64 / 4.0).cos();
+            image.y[(y * ctx.ypw + x) as usize] = v as u8;
+        }
+    }
+    return image;
+}
+
+/// texture where each 16x16 macroblock moves its own way, by -2, 0 or 2
+/// pixels per frame in each direction depending on its column and row
+pub fn mixed_motion_image(ctx: &EncodeContext, frame: i32) -> c63::YUV {
+    let mut image = test_image(ctx, 0);
+    for y in 0..ctx.yph {
+        for x in 0..ctx.ypw {
+            let u = (x + 2 * ((x / 16) % 3 - 1) * frame) as f64;
+            let v = (y + 2 * ((y / 16) % 3 - 1) * frame) as f64;
+            let value = 128.0 + 60.0 * (u / 5.0).sin() * (v / 4.0).cos();
+            image.y[(y * ctx.ypw + x) as usize] = value as u8;
+        }
+    }
+    return image;
+}
+
+/// panning_image with the chroma planes moving along
+pub fn panning_color_image(ctx: &EncodeContext, frame: i32) -> c63::YUV {
+    let mut image = panning_image(ctx, frame);
+    for y in 0..ctx.uph {
+        for x in 0..ctx.upw {
+            let u = (x + frame) as f64;
+            let i = (y * ctx.upw + x) as usize;
+            image.u[i] = (128.0 + 40.0 * (u / 3.0).sin()) as u8;
+            image.v[i] = (128.0 + 40.0 * (y as f64 / 3.0 + u / 4.0).cos()) as u8;
+        }
+    }
+    return image;
+}
+
+/// panning_color_image with a flat left half, a sky next to texture
+pub fn half_flat_image(ctx: &EncodeContext, frame: i32) -> c63::YUV {
+    let mut image = panning_color_image(ctx, frame);
+    for y in 0..ctx.yph {
+        for x in 0..ctx.ypw / 2 {
+            image.y[(y * ctx.ypw + x) as usize] = (110 + y / 8) as u8;
+        }
+    }
+    return image;
+}
+
+/// switching between two unrelated images every frame, like blinking
+/// signage
+pub fn blinking_image(ctx: &EncodeContext, frame: i32) -> c63::YUV {
+    if frame % 2 == 0 {
+        return panning_color_image(ctx, 0);
+    }
+    return test_image(ctx, 0);
+}
+
+/// panning_color_image fading in, along the motion each frame is the
+/// average of the frames on both sides
+pub fn fading_image(ctx: &EncodeContext, frame: i32) -> c63::YUV {
+    let mut image = panning_color_image(ctx, frame);
+    for p in image.y.iter_mut() {
+        *p = (*p as i32 / 2 + 10 * frame) as u8;
+    }
+    return image;
+}
+
+/// panning_color_image with grain that changes every frame, like camera
+/// noise
+pub fn noisy_image(ctx: &EncodeContext, frame: i32) -> c63::YUV {
+    let mut image = panning_color_image(ctx, frame);
+    let mut seed = 12345u32.wrapping_add(frame as u32 * 7919);
+    for p in image.y.iter_mut() {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let noise = (seed >> 16) as i32 % 9 - 4;
+        *p = (*p as i32 + noise).clamp(0, 255) as u8;
+    }
+    return image;
+}
+
+/// peak signal to noise ratio of `b` against `a` in dB
+pub fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    let mse = a
+        .iter()
+        .zip(b)
+        .map(|(&x, &y)| (x as f64 - y as f64).powi(2))
+        .sum::<f64>()
+        / a.len() as f64;
+    return 10.0 * (255.0 * 255.0 / mse).log10();
+}
+
+/// the size of the stream and the mean luma PSNR of `frames` frames of
+/// `image` coded with `ctx`
+pub fn size_and_psnr(mut ctx: EncodeContext, image: Image, frames: i32) -> (usize, f64) {
+    let mut stream = Vec::new();
+    let mut total_psnr = 0.0;
+    for i in 0..frames {
+        let original = image(&ctx, i);
+        encode_context::encode_image(&mut ctx, original.clone());
+        c63_write::write_frame(&mut ctx, &mut stream).unwrap();
+        total_psnr += psnr(&original.y, &ctx.current_frame.as_ref().unwrap().recons.y);
+    }
+    return (stream.len(), total_psnr / frames as f64);
+}
+
+/// the mean luma PSNR of the images the decoder displays for `frames` frames
+/// of `image` coded with `ctx`
+pub fn decoded_psnr(mut ctx: EncodeContext, image: Image, frames: i32) -> f64 {
+    let mut stream = Vec::new();
+    let mut originals = Vec::new();
+    for i in 0..frames {
+        let original = image(&ctx, i);
+        encode_context::encode_image(&mut ctx, original.clone());
+        c63_write::write_frame(&mut ctx, &mut stream).unwrap();
+        originals.push(original);
+    }
+
+    let mut dctx = DecodeContext::new();
+    let mut input = &stream[..];
+    let mut displayed = Vec::new();
+    while decode_frame(&mut dctx, &mut input).unwrap() {
+        while let Some(image) = next_display_image(&mut dctx, false) {
+            displayed.push(image);
+        }
+    }
+    while let Some(image) = next_display_image(&mut dctx, true) {
+        displayed.push(image);
+    }
+    assert_eq!(displayed.len(), originals.len());
+
+    let total_psnr = originals
+        .iter()
+        .zip(displayed.iter())
+        .map(|(original, image)| psnr(&original.y, &image.y))
+        .sum::<f64>();
+    return total_psnr / frames as f64;
+}
+
+/// size_and_psnr of an encoder of `size` with the coding `tools` and the
+/// options set by `configure`, once with each entropy coder
+pub fn size_and_psnr_of_each_coder(
+    size: (i32, i32),
+    tools: c63::CodingTools,
+    configure: impl Fn(&mut EncodeContext),
+    image: Image,
+    frames: i32,
+) -> [(usize, f64); 2] {
+    return ENTROPY_CODERS.map(|entropy_coder| {
+        let mut ctx = context(
+            size,
+            c63::CodingTools {
+                entropy_coder,
+                ..tools
+            },
+        );
+        configure(&mut ctx);
+        return size_and_psnr(ctx, image, frames);
+    });
+}
+
+/// encode `frames` frames of `image` with `ctx` and check that the decoder
+/// reconstructs the same images as the encoder, returns the size of the
+/// stream
+pub fn assert_round_trip(mut ctx: EncodeContext, image: Image, frames: i32) -> usize {
+    let mut stream = Vec::new();
+    let mut coded = Vec::new();
+    let mut write_frame = |ctx: &mut EncodeContext| {
+        let start = stream.len();
+        c63_write::write_frame(ctx, &mut stream).unwrap();
+        ctx.rate_control.update((stream.len() - start) * 8);
+        let frame = ctx.current_frame.as_ref().unwrap();
+        coded.push((
+            frame.display_number,
+            frame.recons.clone(),
+            frame.delta_qps.clone(),
+        ));
+    };
+    for i in 0..frames {
+        let frame = image(&ctx, i);
+        encode_context::push_image(&mut ctx, frame);
+        while encode_context::encode_next_frame(&mut ctx) {
+            write_frame(&mut ctx);
+        }
+    }
+    encode_context::finish_input(&mut ctx);
+    while encode_context::encode_next_frame(&mut ctx) {
+        write_frame(&mut ctx);
+    }
+    assert_eq!(coded.len(), frames as usize);
+
+    /* The decoder gives the same frames in coding order */
+    let mut dctx = DecodeContext::new();
+    let mut input = &stream[..];
+    let mut displayed = Vec::new();
+    for (display_number, recons, delta_qps) in coded.iter() {
+        assert!(decode_frame(&mut dctx, &mut input).unwrap());
+        assert_eq!(dctx.coding_tools, ctx.coding_tools);
+        let frame = dctx.current_frame.as_ref().unwrap();
+        assert_eq!(frame.display_number, *display_number);
+        assert_eq!(&frame.delta_qps, delta_qps);
+        assert_eq!(&frame.recons.y, &recons.y);
+        assert_eq!(&frame.recons.u, &recons.u);
+        assert_eq!(&frame.recons.v, &recons.v);
+        while let Some(image) = next_display_image(&mut dctx, false) {
+            displayed.push(image);
+        }
+    }
+    assert!(!decode_frame(&mut dctx, &mut input).unwrap());
+    while let Some(image) = next_display_image(&mut dctx, true) {
+        displayed.push(image);
+    }
+
+    /* and in display order */
+    coded.sort_by_key(|(display_number, _, _)| *display_number);
+    assert_eq!(displayed.len(), coded.len());
+    for (image, (_, expected, _)) in displayed.iter().zip(coded.iter()) {
+        assert_eq!(&image.y, &expected.y);
+    }
+
+    return stream.len();
+}
+
+/// assert_round_trip of an encoder of `size` with the coding `tools` and the
+/// options set by `configure`, once with each entropy coder; returns the size
+/// of the Huffman and the arithmetic coded stream
+pub fn assert_round_trips(
+    size: (i32, i32),
+    tools: c63::CodingTools,
+    configure: impl Fn(&mut EncodeContext),
+    image: Image,
+    frames: i32,
+) -> [usize; 2] {
+    return ENTROPY_CODERS.map(|entropy_coder| {
+        let mut ctx = context(
+            size,
+            c63::CodingTools {
+                entropy_coder,
+                ..tools
+            },
+        );
+        configure(&mut ctx);
+        return assert_round_trip(ctx, image, frames);
+    });
+}
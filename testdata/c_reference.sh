@@ -0,0 +1,23 @@
+#!/bin/sh
+# Build the C reference c63enc and c63dec from the source tree given as the
+# first argument and write the goldens that only the C reference can make,
+# see README.md.
+set -e
+
+if [ $# -ne 1 ]; then
+    echo "usage: $0 <c63 source directory>" >&2
+    exit 1
+fi
+
+testdata=$(cd "$(dirname "$0")" && pwd)
+make -C "$1" c63enc c63dec
+c63enc="$1/c63enc"
+c63dec="$1/c63dec"
+
+# A stream of the C encoder, which always codes at qp 25, and its frames
+# decoded by the C decoder
+"$c63enc" -w 64 -h 48 -o "$testdata/c_pan_64x48.c63" "$testdata/pan_64x48.yuv"
+"$c63dec" "$testdata/c_pan_64x48.c63" "$testdata/c_pan_64x48_decoded.yuv"
+
+# The stream of this crate decoded by the C decoder
+"$c63dec" "$testdata/pan_64x48_qp10.c63" "$testdata/pan_64x48_qp10_decoded.yuv"